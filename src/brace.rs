@@ -0,0 +1,117 @@
+//! Brace expansion of names, like in shells. `photo_{001..003}.jpg` expands to
+//! `photo_001.jpg`, `photo_002.jpg` and `photo_003.jpg`, and `{a,b}.txt` to
+//! `a.txt` and `b.txt`. Braces that contain neither a range nor a comma are
+//! kept as is
+
+use anyhow::{bail, Result};
+use os_str_bytes::{RawOsStr, RawOsString};
+
+/// Most names a single name may expand to
+pub const MAX_EXPANSION: usize = 10_000;
+
+pub fn expand(name: &RawOsStr) -> Result<Vec<RawOsString>> {
+    let expanded = expand_bytes(name.as_raw_bytes())?;
+    if expanded.len() > MAX_EXPANSION {
+        bail!("Expands to more than {MAX_EXPANSION} names");
+    }
+    // Only ASCII characters have been removed or inserted, so the encoding is
+    // still valid
+    Ok(expanded
+        .into_iter()
+        .map(RawOsString::assert_from_raw_vec)
+        .collect())
+}
+
+fn expand_bytes(name: &[u8]) -> Result<Vec<Vec<u8>>> {
+    // Find the first pair of braces that can be expanded
+    let mut search_from = 0;
+    let (start, end, alternatives) = loop {
+        let Some(start) = find(name, b'{', search_from) else {
+            return Ok(vec![name.to_vec()]);
+        };
+        let Some(end) = find(name, b'}', start) else {
+            return Ok(vec![name.to_vec()]);
+        };
+        match alternatives(&name[start + 1..end])? {
+            Some(alternatives) => break (start, end, alternatives),
+            None => search_from = start + 1,
+        }
+    };
+
+    let suffixes = expand_bytes(&name[end + 1..])?;
+    if alternatives.len().saturating_mul(suffixes.len()) > MAX_EXPANSION {
+        bail!("Expands to more than {MAX_EXPANSION} names");
+    }
+    let mut expanded = Vec::new();
+    for alternative in &alternatives {
+        for suffix in &suffixes {
+            let mut name = name[..start].to_vec();
+            name.extend_from_slice(alternative);
+            name.extend_from_slice(suffix);
+            expanded.push(name);
+        }
+    }
+    Ok(expanded)
+}
+
+fn find(bytes: &[u8], byte: u8, from: usize) -> Option<usize> {
+    bytes[from..]
+        .iter()
+        .position(|&b| b == byte)
+        .map(|i| i + from)
+}
+
+/// Returns what the text between two braces expands to, or `None` if it isn't
+/// a range or a list
+fn alternatives(inner: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+    if let Some(range) = range(inner)? {
+        return Ok(Some(range));
+    }
+    if inner.contains(&b',') {
+        return Ok(Some(
+            inner.split(|&b| b == b',').map(<[u8]>::to_vec).collect(),
+        ));
+    }
+    Ok(None)
+}
+
+/// Expands a numeric range like `1..10` or `010..001`. Numbers are padded with
+/// zeros to the same width if either end starts with a zero
+fn range(inner: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+    let Ok(inner) = std::str::from_utf8(inner) else {
+        return Ok(None);
+    };
+    let Some((first, last)) = inner.split_once("..") else {
+        return Ok(None);
+    };
+    let is_number =
+        |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(first) || !is_number(last) {
+        return Ok(None);
+    }
+    let (Ok(from), Ok(to)) = (first.parse::<u64>(), last.parse::<u64>()) else {
+        bail!("Range {{{inner}}} is too large");
+    };
+    if from.abs_diff(to) >= MAX_EXPANSION as u64 {
+        bail!("Range {{{inner}}} has more than {MAX_EXPANSION} numbers");
+    }
+
+    let padded = (first.len() > 1 && first.starts_with('0'))
+        || (last.len() > 1 && last.starts_with('0'));
+    let width = if padded {
+        first.len().max(last.len())
+    } else {
+        0
+    };
+    let numbers: Vec<u64> = if from <= to {
+        (from..=to).collect()
+    } else {
+        (to..=from).rev().collect()
+    };
+    Ok(Some(
+        numbers
+            .into_iter()
+            .map(|n| format!("{n:0width$}").into_bytes())
+            .collect(),
+    ))
+}
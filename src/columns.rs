@@ -0,0 +1,257 @@
+//! Columns shown between the index and the name of each file. Most are
+//! read-only and editing them has no effect, while editable columns are
+//! compared with their original text to find changes
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use std::fs::Metadata;
+use std::time::SystemTime;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+const EDITABLE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// Inode number, which is the same for hard links to a file
+    Inode,
+    /// File type and permissions, e.g. `drwxr-xr-x`
+    Mode,
+    /// Number of hard links
+    Links,
+    /// Size in a human readable format, e.g. `1.5K`
+    Size,
+    /// Modification time in the local timezone, e.g. `2023-01-31 12:00`
+    Mtime,
+    /// Name of the owner, or the uid if the user has no name
+    Owner,
+    /// Name of the group, or the gid if the group has no name
+    Group,
+    /// Owner and group as `user:group`. Editable
+    Ownership,
+    /// Modification time with seconds, e.g. `2023-01-31 12:00:00`. Editable
+    Timestamp,
+}
+
+impl Column {
+    /// Number of space separated words in the column. Used when parsing to
+    /// find where the name starts
+    pub fn words(self) -> usize {
+        match self {
+            Column::Inode | Column::Mode | Column::Links | Column::Size => 1,
+            Column::Mtime | Column::Timestamp => 2,
+            Column::Owner | Column::Group | Column::Ownership => 1,
+        }
+    }
+
+    /// Whether changing the text of the column changes the file
+    pub fn editable(self) -> bool {
+        match self {
+            Column::Ownership | Column::Timestamp => true,
+            Column::Inode
+            | Column::Mode
+            | Column::Links
+            | Column::Size
+            | Column::Mtime
+            | Column::Owner
+            | Column::Group => false,
+        }
+    }
+
+    pub fn format(self, metadata: &Metadata) -> String {
+        match self {
+            Column::Inode => inode(metadata),
+            Column::Mode => mode(metadata),
+            Column::Links => links(metadata),
+            Column::Size => human_size(metadata.len()),
+            Column::Mtime => format_time(metadata, TIME_FORMAT),
+            Column::Timestamp => format_time(metadata, EDITABLE_TIME_FORMAT),
+            Column::Owner => owner_name(metadata),
+            Column::Group => group_name(metadata),
+            Column::Ownership => {
+                format!("{}:{}", owner_name(metadata), group_name(metadata))
+            }
+        }
+    }
+}
+
+fn format_time(metadata: &Metadata, format: &str) -> String {
+    match metadata.modified() {
+        Ok(time) => DateTime::<Local>::from(time).format(format).to_string(),
+        // Not available on all platforms
+        Err(_) => "-".to_owned(),
+    }
+}
+
+/// Parses an edited timestamp column as a time in the local timezone
+pub fn parse_timestamp(text: &str) -> Result<SystemTime> {
+    let naive = NaiveDateTime::parse_from_str(text, EDITABLE_TIME_FORMAT)
+        .map_err(|_| {
+            anyhow!(
+                "Expected a time like 2023-01-31 12:00:00 but found {text:?}"
+            )
+        })?;
+    let time = Local
+        .from_local_datetime(&naive)
+        .single()
+        .ok_or_else(|| anyhow!("{text:?} is ambiguous or doesn't exist"))?;
+    Ok(time.into())
+}
+
+#[cfg(unix)]
+fn owner_name(metadata: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    match uzers::get_user_by_uid(metadata.uid()) {
+        Some(user) => user.name().to_string_lossy().into_owned(),
+        None => metadata.uid().to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn group_name(metadata: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    match uzers::get_group_by_gid(metadata.gid()) {
+        Some(group) => group.name().to_string_lossy().into_owned(),
+        None => metadata.gid().to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> String {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let file_type = metadata.file_type();
+    let type_char = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else {
+        '-'
+    };
+
+    let mode = metadata.permissions().mode();
+    let mut text = String::from(type_char);
+    // Permissions of the owner, the group and others, with the special bit
+    // that replaces the execute permission of each
+    for (shift, special_bit, special_char) in
+        [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')]
+    {
+        let bits = (mode >> shift) & 0o7;
+        text.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        text.push(match (bits & 0o1 != 0, mode & special_bit != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    text
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.ino().to_string()
+}
+
+#[cfg(unix)]
+fn links(metadata: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.nlink().to_string()
+}
+
+/// Parses an edited `user:group` column into a uid and gid. Names are looked
+/// up, while numbers are used as is
+#[cfg(unix)]
+pub fn parse_ownership(text: &str) -> Result<(u32, u32)> {
+    let (user, group) = text
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected user:group but found {text:?}"))?;
+    let uid = match uzers::get_user_by_name(user) {
+        Some(user) => user.uid(),
+        None => user.parse().map_err(|_| anyhow!("Unknown user {user:?}"))?,
+    };
+    let gid = match uzers::get_group_by_name(group) {
+        Some(group) => group.gid(),
+        None => group
+            .parse()
+            .map_err(|_| anyhow!("Unknown group {group:?}"))?,
+    };
+    Ok((uid, gid))
+}
+
+// Windows has no owner and group in the same sense
+#[cfg(not(unix))]
+fn owner_name(_metadata: &Metadata) -> String {
+    "-".to_owned()
+}
+
+#[cfg(not(unix))]
+fn group_name(_metadata: &Metadata) -> String {
+    "-".to_owned()
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> String {
+    let type_char = if metadata.is_dir() { 'd' } else { '-' };
+    let write_char = if metadata.permissions().readonly() {
+        '-'
+    } else {
+        'w'
+    };
+    format!("{type_char}r{write_char}-------")
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> String {
+    "-".to_owned()
+}
+
+#[cfg(not(unix))]
+fn links(_metadata: &Metadata) -> String {
+    "-".to_owned()
+}
+
+#[cfg(not(unix))]
+pub fn parse_ownership(_text: &str) -> Result<(u32, u32)> {
+    Err(anyhow!(
+        "Changing the owner of files is only supported on Unix"
+    ))
+}
+
+/// Formats a size in bytes using binary units, like `ls -h`
+pub(crate) fn human_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+
+    if size < 1024 {
+        return size.to_string();
+    }
+
+    let mut value = size as f64;
+    let mut unit = UNITS[0];
+    for next_unit in UNITS {
+        value /= 1024.0;
+        unit = next_unit;
+        if value < 1024.0 {
+            break;
+        }
+    }
+
+    if value < 10.0 {
+        format!("{value:.1}{unit}")
+    } else {
+        format!("{value:.0}{unit}")
+    }
+}
@@ -0,0 +1,25 @@
+//! Escaping of names in the temporary file, so that names containing newlines
+//! or other control characters can be edited like any other name
+//! Uses C-style escapes: `\n`, `\t` and `\\`, and `\xNN` for other control
+//! characters
+
+use os_str_bytes::{RawOsStr, RawOsString};
+
+/// Escapes a name for the temporary file
+pub fn escape(name: &RawOsStr) -> RawOsString {
+    let mut escaped = Vec::new();
+    for &byte in name.as_raw_bytes() {
+        match byte {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\t' => escaped.extend_from_slice(b"\\t"),
+            0x00..=0x1f | 0x7f => {
+                escaped.extend_from_slice(format!("\\x{byte:02x}").as_bytes())
+            }
+            _ => escaped.push(byte),
+        }
+    }
+    // Only ASCII characters have been replaced, with other ASCII characters,
+    // so the encoding is still valid
+    RawOsString::assert_from_raw_vec(escaped)
+}
@@ -0,0 +1,57 @@
+//! Computing and applying the changes of an edited listing of files, which
+//! the rsdir command is built on. A listing is a list of [`InputRow`]s, and
+//! the edited text is parsed into [`OutputRow`]s, which are matched to the
+//! listed files by their index
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use rsdir::{InputRow, OutputRow};
+//! use std::fs;
+//! use std::path::PathBuf;
+//!
+//! let input = vec![InputRow {
+//!     index: 1,
+//!     section: 0,
+//!     name: PathBuf::from("./old"),
+//!     is_dir: false,
+//!     metadata: Some(fs::symlink_metadata("./old")?),
+//!     ino: None,
+//! }];
+//! let output = vec![OutputRow {
+//!     index: 1,
+//!     name: PathBuf::from("./new"),
+//!     cells: Vec::new(),
+//! }];
+//! let plan = rsdir::compute_plan(&input, &output)?;
+//! for operation in plan.operations() {
+//!     println!("{operation}");
+//! }
+//! plan.apply(&Default::default())?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod brace;
+mod columns;
+mod escape;
+mod lscolors;
+pub mod plan;
+mod sandbox;
+mod vars;
+
+use anyhow::Result;
+
+pub use columns::Column;
+pub use escape::escape;
+pub use lscolors::LsColors;
+pub use plan::{InputRow, Operation, OutputRow, Plan};
+pub use sandbox::Sandbox;
+
+/// Computes the operations that change the `input` files into the edited
+/// `output`, with the default [`plan::PlanOptions`]. Files whose index is
+/// missing from `output` are removed, and names that conflict are errors
+pub fn compute_plan(input: &[InputRow], output: &[OutputRow]) -> Result<Plan> {
+    plan::compute_plan(input, output, &Default::default(), |_, _| {
+        Ok(plan::Resolution::Fail)
+    })
+}
@@ -0,0 +1,118 @@
+//! Coloring of names by file type and extension, configured with `LS_COLORS`
+//! like `ls`
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const LS_COLORS_ENV: &str = "LS_COLORS";
+/// Used when `LS_COLORS` isn't set. A subset of the defaults of GNU ls
+const DEFAULT_LS_COLORS: &str =
+    "di=01;34:ln=01;36:pi=40;33:so=01;35:bd=40;33;01:cd=40;33;01:ex=01;32";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug)]
+pub struct LsColors {
+    /// Colors of file types, keyed by codes like `di`
+    types: HashMap<String, String>,
+    /// Colors of name endings, from entries like `*.txt`
+    suffixes: Vec<(String, String)>,
+}
+
+impl LsColors {
+    pub fn from_env() -> LsColors {
+        let value = env::var(LS_COLORS_ENV);
+        let value = match &value {
+            Ok(value) => value.as_str(),
+            Err(_) => DEFAULT_LS_COLORS,
+        };
+
+        let mut colors = LsColors {
+            types: HashMap::new(),
+            suffixes: Vec::new(),
+        };
+        for entry in value.split(':') {
+            let Some((key, color)) = entry.split_once('=') else {
+                continue;
+            };
+            match key.strip_prefix('*') {
+                Some(suffix) => {
+                    colors.suffixes.push((suffix.to_owned(), color.to_owned()))
+                }
+                None => {
+                    colors.types.insert(key.to_owned(), color.to_owned());
+                }
+            }
+        }
+        colors
+    }
+
+    /// Wraps the text in the color of the file. The file type is read from
+    /// the file system if the path exists, and otherwise only `is_dir` is
+    /// used
+    pub fn paint(&self, text: &str, path: &Path, is_dir: bool) -> String {
+        match self.color(path, is_dir) {
+            Some(color) if !color.is_empty() => {
+                format!("\x1b[{color}m{text}{RESET}")
+            }
+            _ => text.to_owned(),
+        }
+    }
+
+    fn color(&self, path: &Path, is_dir: bool) -> Option<&str> {
+        let type_code = match fs::symlink_metadata(path) {
+            Ok(metadata) => type_code(&metadata),
+            Err(_) if is_dir => "di",
+            Err(_) => "fi",
+        };
+        if type_code != "fi" {
+            return self.types.get(type_code).map(String::as_str);
+        }
+
+        // The longest matching suffix wins
+        let name = path.file_name()?.to_string_lossy();
+        self.suffixes
+            .iter()
+            .filter(|(suffix, _)| name.ends_with(suffix.as_str()))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, color)| color.as_str())
+            .or_else(|| self.types.get("fi").map(String::as_str))
+    }
+}
+
+#[cfg(unix)]
+fn type_code(metadata: &fs::Metadata) -> &'static str {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        "di"
+    } else if file_type.is_symlink() {
+        "ln"
+    } else if file_type.is_fifo() {
+        "pi"
+    } else if file_type.is_socket() {
+        "so"
+    } else if file_type.is_block_device() {
+        "bd"
+    } else if file_type.is_char_device() {
+        "cd"
+    } else if metadata.permissions().mode() & 0o111 != 0 {
+        "ex"
+    } else {
+        "fi"
+    }
+}
+
+#[cfg(not(unix))]
+fn type_code(metadata: &fs::Metadata) -> &'static str {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        "di"
+    } else if file_type.is_symlink() {
+        "ln"
+    } else {
+        "fi"
+    }
+}
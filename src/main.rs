@@ -1,49 +1,650 @@
+#[cfg(all(target_os = "linux", feature = "getdents"))]
+mod getdents;
+mod map;
+mod number;
+mod report;
+mod script;
+mod serve;
+mod transform;
+
 use anyhow::{anyhow, bail, Context, Result};
-use clap::Parser;
-use os_str_bytes::RawOsString;
-use std::collections::{HashMap, HashSet};
-use std::ffi::OsString;
-use std::io::Write;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use icu_collator::{Collator, CollatorOptions, Strength};
+use icu_locid::Locale;
+use number::Numbering;
+use os_str_bytes::{RawOsStr, RawOsString};
+use regex::Regex;
+use report::ReportFormat;
+use rsdir::plan::{self, InputRow, OutputRow};
+use rsdir::{Column, LsColors, Sandbox};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::{env, fs, io, result};
+use std::process::{self, Command};
+use std::sync::atomic::{self, AtomicBool};
+use std::time::{Duration, Instant};
+use std::{env, fmt, fs, io, result, thread};
 use tempfile::NamedTempFile;
+use transform::{Case, Normalization, Replace, SanitizeRule, Transform};
+use unicode_width::UnicodeWidthStr;
 
 const DEFAULT_DIR: &str = ".";
 const DEFAULT_EDITOR: &str = "vi";
-const EDITOR_ENV: &str = "EDITOR";
+const DEFAULT_PAGER: &str = "less";
+/// Value of --backup that adds numbers instead of a suffix
+const NUMBERED_BACKUP: &str = "numbered";
+/// Starts rows that are ignored, when there are indexes
+const COMMENT: &str = "#";
+/// Whether --apply-stdin read stdin to the end, so that questions must be
+/// answered on the terminal
+static STDIN_CONSUMED: AtomicBool = AtomicBool::new(false);
+/// Starts the row of --list that identifies the listed files
+const LISTING_HEADER: &str = "# rsdir listing";
+const TTY_PATH: &str = "/dev/tty";
+/// Path argument that means the paths are read from stdin
+const STDIN_PATH: &str = "-";
+/// Flags that make GUI editors wait until the file is closed, by the name of
+/// the editor
+const WAIT_FLAGS: &[(&str, &str)] = &[
+    ("code", "--wait"),
+    ("codium", "--wait"),
+    ("subl", "-w"),
+    ("gedit", "--wait"),
+    ("mate", "-w"),
+];
+/// Editors that run in the terminal, and wait forever without one
+const TERMINAL_EDITORS: &[&str] = &[
+    "vi", "vim", "nvim", "nano", "pico", "micro", "hx", "kak", "joe", "mg",
+];
+/// An editor that exits sooner than this without changing the file probably
+/// didn't wait for it to be edited
+const QUICK_EXIT: Duration = Duration::from_secs(1);
+/// Variables that the editor is read from, in order of precedence
+const EDITOR_VARS: &[&str] = &["RSDIR_EDITOR", "VISUAL", "EDITOR"];
+/// Variables that the locale of --sort locale is read from, in order of
+/// precedence
+const COLLATION_VARS: &[&str] = &["LC_ALL", "LC_COLLATE", "LANG"];
+const DEFAULT_LISTING_THRESHOLD: usize = 10_000;
+const DEFAULT_CHANGE_THRESHOLD: usize = 50;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    /// Verbosely display the actions taken by the program
+    /// Verbosely display the actions taken by the program. Repeat to also
+    /// show unchanged files and checks (-vv) and timing (-vvv)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Directory to create the temporary file in. Defaults to TMPDIR
+    #[arg(long, value_name = "PATH")]
+    tmpdir: Option<PathBuf>,
+
+    /// Keep the edited temporary file instead of removing it. It is always
+    /// kept if the changes couldn't be applied
+    #[arg(long)]
+    keep_buffer: bool,
+
+    /// Continue editing a buffer saved by a previous session
+    #[arg(long, value_name = "PATH")]
+    resume: Option<PathBuf>,
+
+    /// After applying the changes, list the files again and reopen the
+    /// editor, until the buffer is saved without changes
+    #[arg(
+        long = "loop",
+        conflicts_with_all = ["emit_script", "dump_listing", "from_listing"]
+    )]
+    repeat: bool,
+
+    /// Write the listing to FILE instead of opening the editor. Edit a copy
+    /// of it and apply it later with --from-listing
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "resume",
+            "sanitize",
+            "transform",
+            "normalize",
+            "prefix",
+            "suffix",
+            "rename_preview"
+        ]
+    )]
+    dump_listing: Option<PathBuf>,
+
+    /// Apply an edited copy of a listing written by --dump-listing, without
+    /// listing the files or opening the editor. The original listing must be
+    /// passed with --baseline, and the same buffer options must be used
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "baseline",
+        conflicts_with_all = ["resume", "dump_listing", "files_from", "path"]
+    )]
+    from_listing: Option<PathBuf>,
+
+    /// Rename files by a map of old and new names, without listing the files
+    /// or opening the editor. The map has a line with the old and new name
+    /// separated by a tab for each file, or is JSON like --write-map writes it
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["resume", "dump_listing", "from_listing", "files_from", "path"]
+    )]
+    map: Option<PathBuf>,
+
+    /// Write the listing to stdout instead of opening the editor, so that it
+    /// can be edited by other programs and applied with --apply-stdin
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "resume",
+            "dump_listing",
+            "from_listing",
+            "map",
+            "no_index",
+            "print0",
+        ]
+    )]
+    list: bool,
+
+    /// Apply a listing written by --list and edited by other programs, read
+    /// from stdin, instead of opening the editor. The files are listed again
+    /// with the same arguments, and must not have changed
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "resume",
+            "dump_listing",
+            "from_listing",
+            "map",
+            "no_index",
+            "list",
+            "repeat",
+            "files_from",
+            "null_stdin",
+        ]
+    )]
+    apply_stdin: bool,
+
+    /// The listing written by --dump-listing that --from-listing was edited
+    /// from
+    #[arg(long, value_name = "FILE", requires = "from_listing")]
+    baseline: Option<PathBuf>,
+
+    /// List the contents of subdirectories recursively
     #[arg(short, long)]
-    verbose: bool,
+    recursive: bool,
+
+    /// Descend into symlinked directories when listing recursively
+    #[arg(long, requires = "recursive")]
+    follow: bool,
+
+    /// Don't descend into directories on other file systems when listing
+    /// recursively, like mount points. They are still listed themselves
+    #[arg(short = 'x', long, requires = "recursive")]
+    one_file_system: bool,
+
+    /// How the files in each directory are sorted. Paths from stdin or
+    /// --files-from are kept in the order they were read
+    #[arg(long, value_enum, value_name = "ORDER", default_value_t = SortOrder::Name)]
+    sort: SortOrder,
+
+    /// Reverse the order that the files in each directory are sorted in
+    #[arg(short = 'R', long)]
+    reverse: bool,
+
+    /// Pipe the listing through CMD before editing it, e.g. "sort -k2", for
+    /// orders that --sort doesn't have. The command may only reorder the
+    /// rows, which must keep their indexes
+    #[arg(
+        long,
+        value_name = "CMD",
+        conflicts_with_all = ["resume", "no_index", "strict"]
+    )]
+    sort_cmd: Option<String>,
+
+    /// Ask for confirmation before editing more files than this
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LISTING_THRESHOLD)]
+    listing_threshold: usize,
+
+    /// Fail without opening the editor when there are more files than this
+    #[arg(long, value_name = "N")]
+    max_files: Option<usize>,
+
+    /// Don't ask any questions, for use in scripts. Changes are applied
+    /// without confirmation, conflicts fail unless --on-conflict is ask, and
+    /// --sanitize, --transform, --normalize, --prefix and --suffix are applied
+    /// without opening the editor
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Copy files instead of moving them when their index is on several rows.
+    /// Braces in names are expanded, e.g. photo_{001..010}.jpg or {a,b}.txt,
+    /// to make several copies from one row
+    #[arg(long)]
+    copy: bool,
+
+    /// Start with cleaned up names in the temporary file, to review before
+    /// saving. Takes a comma separated list of rules, and defaults to all
+    #[arg(
+        long,
+        value_enum,
+        value_name = "RULES",
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ',',
+        default_missing_value = "all"
+    )]
+    sanitize: Vec<SanitizeRule>,
+
+    /// Start with the case of the names changed in the temporary file, to
+    /// review before saving
+    #[arg(long, value_enum, value_name = "CASE")]
+    transform: Option<Case>,
+
+    /// Show how replacing the matches of REGEX in the names with REPLACEMENT
+    /// changes them, with the matches highlighted, and ask whether to apply
+    /// the new names, edit them first or abort. Use $1 or ${name} in
+    /// REPLACEMENT for capture groups
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["REGEX", "REPLACEMENT"],
+        conflicts_with_all = ["resume", "repeat"]
+    )]
+    rename_preview: Option<Vec<String>>,
+
+    /// Start with TEXT added to the start of the names in the temporary file,
+    /// to review before saving
+    #[arg(long, value_name = "TEXT")]
+    prefix: Option<String>,
+
+    /// Start with TEXT added to the end of the names in the temporary file,
+    /// before the extension, to review before saving
+    #[arg(long, value_name = "TEXT")]
+    suffix: Option<String>,
+
+    /// Start with the names in the given Unicode normalization. Otherwise
+    /// names that only differ in normalization are treated as unchanged, since
+    /// some file systems normalize names
+    #[arg(long, value_enum, value_name = "FORM")]
+    normalize: Option<Normalization>,
+
+    /// Number the names by the order of the rows after editing, so that files
+    /// can be ordered by moving their rows. Each # in FORMAT is a digit, e.g.
+    /// ##- for 01-, 02- and so on. Earlier numbers in the same format are
+    /// replaced
+    #[arg(long, value_name = "FORMAT")]
+    number_by_order: Option<Numbering>,
 
-    /// Directories to edit. Defaults to current directory
+    /// Expand environment variables in changed names, written as $NAME or
+    /// ${NAME}
+    #[arg(long)]
+    expand_env: bool,
+
+    /// When a new name already exists, add a suffix like " (1)" to it instead
+    /// of failing
+    #[arg(long)]
+    auto_suffix: bool,
+
+    /// When a new name already exists, rename the existing file by adding
+    /// SUFFIX to it instead of failing. Defaults to ~. Use numbered to add
+    /// the first free number, like name.~1~
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "~",
+        conflicts_with = "auto_suffix"
+    )]
+    backup: Option<String>,
+
+    /// What to do when a new name already exists, or several files get the
+    /// same name
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = OnConflict::Auto)]
+    on_conflict: OnConflict,
+
+    /// Fail if a new name is outside of the listed directories, or the
+    /// current directory when the paths are read with --files-from
+    #[arg(long)]
+    restrict: bool,
+
+    /// Allow new names that go above the listed directories with .., like
+    /// ../other/name, which otherwise fail
+    #[arg(long, conflicts_with = "restrict")]
+    allow_parent: bool,
+
+    /// Allow removing block and character devices, and directories that
+    /// still contain files, which otherwise fails
+    #[arg(long)]
+    force: bool,
+
+    /// Allow removing directories together with the files in them. Otherwise
+    /// a directory can only be removed if its contents are removed too
+    #[arg(long)]
+    recursive_delete: bool,
+
+    /// Create the missing directories of new names, like mkdir -p
+    #[arg(long)]
+    parents: bool,
+
+    /// Allow removing directories that are or contain mount points, which
+    /// removes the files of the mounted file systems too
+    #[arg(long = "allow-mountpoint")]
+    allow_mount_points: bool,
+
+    /// Reject new names that can't be used on Windows, like CON, NUL and
+    /// names ending with a dot or space. They are always rejected on Windows
+    #[arg(long)]
+    portable_names: bool,
+
+    /// Whether files are removed before or after the other changes. By
+    /// default the changes are made in the order of the listing
+    #[arg(long, value_enum, value_name = "ORDER")]
+    order: Option<plan::Order>,
+
+    /// Fail instead of looking for files that were renamed by another program
+    /// while the editor was open
+    #[arg(long)]
+    no_track_renames: bool,
+
+    /// Don't copy POSIX ACLs when a file is moved to another file system by
+    /// copying it. ACLs are only copied on Linux
+    #[arg(long)]
+    no_acl: bool,
+
+    /// Don't copy the SELinux context when a file is moved to another file
+    /// system, so that it gets the default context of its new location
+    #[arg(long)]
+    no_selinux: bool,
+
+    /// Show the changes as a diff and ask for confirmation before applying
+    /// them
+    #[arg(long)]
+    confirm: bool,
+
+    /// Show the changes and ask for confirmation before applying more changes
+    /// than this
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_CHANGE_THRESHOLD)]
+    change_threshold: usize,
+
+    /// Show the changes and ask for confirmation before removing any files
+    #[arg(long)]
+    confirm_removals: bool,
+
+    /// Print previews of the changes directly, instead of through PAGER when
+    /// they are longer than the terminal is high
+    #[arg(long)]
+    no_pager: bool,
+
+    /// Print what happened to each change at the end, for other programs to
+    /// read
+    #[arg(long, value_name = "FORMAT")]
+    report: Option<ReportFormat>,
+
+    /// Print the names of the files after the changes, separated by NUL
+    /// characters, e.g. for xargs -0. Copies are included and removed files
+    /// left out
+    #[arg(long, conflicts_with = "report")]
+    print0: bool,
+
+    /// Write the old and new name of every renamed file to a file, as lines
+    /// with a tab between them, or as JSON if the file name ends with .json
+    #[arg(long, value_name = "FILE")]
+    write_map: Option<PathBuf>,
+
+    /// Print a shell script that makes the changes instead of making them
+    #[arg(long, conflicts_with_all = ["confirm", "confirm_removals", "interactive", "print0"])]
+    emit_script: bool,
+
+    /// Append a timestamped record of every applied change to a file
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// When to ask before applying a change
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = Interactive::Never)]
+    interactive: Interactive,
+
+    /// Show the permissions, number of links, owner, group, size and
+    /// modification time of each file, like ls -l
+    #[arg(short, long)]
+    long: bool,
+
+    /// Show the inode number of each file, to find hard links to the same
+    /// file
+    #[arg(short, long)]
+    inode: bool,
+
+    /// Show the size of each file
+    #[arg(long)]
+    size: bool,
+
+    /// Show the modification time of each file
+    #[arg(long)]
+    mtime: bool,
+
+    /// Show the modification time of each file with seconds, and change it
+    /// when the column is edited
+    #[arg(long, conflicts_with_all = ["mtime", "long"])]
+    touch: bool,
+
+    /// Show the owner and group of each file
+    #[arg(long)]
+    owner: bool,
+
+    /// Show the owner and group of each file as user:group, and change them
+    /// when the column is edited
+    #[arg(long, conflicts_with_all = ["owner", "long"])]
+    chown: bool,
+
+    /// Pad the indexes with zeros instead of spaces
+    #[arg(long)]
+    pad_index: bool,
+
+    /// Leave out the indexes and match the rows to the files by their
+    /// position. Empty a row to remove the file
+    #[arg(long, conflicts_with = "pad_index")]
+    no_index: bool,
+
+    /// Reject edited text with reordered rows, changed indexes, directories
+    /// without a trailing slash or trailing whitespace, instead of applying
+    /// whatever it amounts to
+    #[arg(long)]
+    strict: bool,
+
+    /// Match rows that lost their index to the file at the same position,
+    /// with a warning, instead of failing
+    #[arg(long, conflicts_with_all = ["strict", "no_index"])]
+    lenient: bool,
+
+    /// Show the names relative to PATH, which all files must be inside.
+    /// Edited names are relative to PATH too, unless they are absolute
+    #[arg(long, value_name = "PATH")]
+    relative_to: Option<PathBuf>,
+
+    /// Separator between the index, the columns and the name
+    #[arg(long, value_enum, default_value_t = Separator::Space)]
+    separator: Separator,
+
+    /// End each row with NUL instead of newline and don't escape names, so
+    /// that any name can be edited as is. Requires an editor that can handle
+    /// NUL characters
+    #[arg(long)]
+    null_buffer: bool,
+
+    /// Read the paths to edit from a file instead of listing directories. Use
+    /// - to read them from stdin
+    #[arg(long, value_name = "FILE", conflicts_with = "path")]
+    files_from: Option<PathBuf>,
+
+    /// Paths read with --files-from or from stdin are separated by NUL
+    /// instead of newline
+    #[arg(short = '0', long)]
+    null: bool,
+
+    /// Read NUL separated paths from stdin, like the output of
+    /// find -print0. The same as --files-from - -0
+    #[arg(long, conflicts_with_all = ["files_from", "path"])]
+    null_stdin: bool,
+
+    /// Directories to edit. Defaults to current directory. Use - to also edit
+    /// the paths read from stdin
     path: Vec<String>,
+
+    /// When to color names in the output, using LS_COLORS
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorWhen::Auto)]
+    color: ColorWhen,
+
+    /// How errors are printed, for wrappers that present them
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print a completion script for a shell
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+    /// Print a man page in roff format
+    Manpage,
+    /// Run a JSON-RPC server for editor integrations, which lists files and
+    /// plans and applies changes on request
+    Serve {
+        /// Read requests from stdin and write responses to stdout
+        #[arg(long, required = true)]
+        stdio: bool,
+    },
 }
 
 #[derive(Debug)]
 struct PathInfo {
     name: PathBuf,
     is_dir: bool,
+    is_symlink: bool,
+    metadata: Option<fs::Metadata>,
+    ino: Option<u64>,
 }
 
 #[derive(Debug)]
-struct InputRow {
-    index: usize,
-    name: PathBuf,
-    is_dir: bool,
+struct ListOptions {
+    recursive: bool,
+    follow: bool,
+    one_file_system: bool,
+    /// Separator of the paths read from stdin, when one of the paths is -
+    separator: char,
+    sort: SortOrder,
+    reverse: bool,
+    /// Whether the metadata of each file is needed, which requires an extra
+    /// system call per file on some platforms
+    metadata: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SortOrder {
+    /// By the bytes of the names
+    Name,
+    /// By the collation rules of the locale, so that accented letters sort
+    /// next to their base letters, ignoring case. The locale is read from
+    /// LC_ALL, LC_COLLATE or LANG
+    Locale,
+    /// By extension and then by name, so that files of the same type are
+    /// kept together. Files without an extension come first
+    Ext,
+    /// By modification time, oldest first
+    Mtime,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Separator {
+    /// Spaces, with the columns padded to line up
+    Space,
+    /// A single tab. Names are read as is, including leading and trailing
+    /// spaces
+    Tab,
+}
+
+impl Separator {
+    fn as_char(self) -> char {
+        match self {
+            Separator::Space => ' ',
+            Separator::Tab => '\t',
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Interactive {
+    /// Apply all changes without asking
+    Never,
+    /// Ask before every move and removal. Answer a to apply all remaining
+    /// changes or q to abort
+    Each,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnConflict {
+    /// Ask when stdin is a terminal and --yes isn't passed, and fail
+    /// otherwise
+    Auto,
+    /// Ask whether to skip the file, overwrite the existing file, add a suffix
+    /// like " (1)" or edit the names again
+    Ask,
+    /// Fail without changing anything
+    Fail,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorFormat {
+    Text,
+    /// An object on one line with the message, its causes, the kind of error
+    /// and the row and names it is about
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorWhen {
+    /// When writing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// How the files are written in the temporary file
 #[derive(Debug)]
-struct OutputRow {
-    index: usize,
-    name: PathBuf,
+struct BufferFormat {
+    /// Read-only columns shown between the index and the name
+    columns: Vec<Column>,
+    /// Whether the rows start with an index. Without indexes the rows are
+    /// matched to the files by position
+    index: bool,
+    pad_index: bool,
+    separator: Separator,
+    /// Whether the rows end with NUL instead of being separated by newlines,
+    /// in which case names aren't escaped
+    null: bool,
+    /// Directory that the names are written relative to
+    relative_to: Option<PathBuf>,
+    /// Whether rows that end with whitespace are rejected, rather than
+    /// trimmed
+    strict: bool,
+    /// Whether rows without an index are matched to the file at the same
+    /// position, rather than rejected
+    lenient: bool,
 }
 
-fn get_path_args(paths: Vec<String>) -> Vec<PathBuf> {
+fn get_path_args(paths: &[String]) -> Vec<PathBuf> {
     if paths.is_empty() {
         vec![PathBuf::from(DEFAULT_DIR)]
     } else {
@@ -51,89 +652,860 @@ fn get_path_args(paths: Vec<String>) -> Vec<PathBuf> {
     }
 }
 
-fn read_dir(path: &Path) -> result::Result<Vec<PathInfo>, io::Error> {
+#[cfg(not(all(target_os = "linux", feature = "getdents")))]
+fn read_dir(
+    path: &Path,
+    with_metadata: bool,
+) -> result::Result<Vec<PathInfo>, io::Error> {
     fs::read_dir(path)?
         .map(|res| {
             let entry = res?;
+            // Comes from `d_type` of the directory entry where available, and
+            // only needs a stat when the file system doesn't fill it in
+            let file_type = entry.file_type()?;
             Ok(PathInfo {
                 name: entry.path(),
-                is_dir: entry.file_type()?.is_dir(),
+                is_dir: file_type.is_dir(),
+                is_symlink: file_type.is_symlink(),
+                metadata: if with_metadata {
+                    Some(entry.metadata()?)
+                } else {
+                    None
+                },
+                ino: entry_ino(&entry),
+            })
+        })
+        .collect()
+}
+
+#[cfg(all(target_os = "linux", feature = "getdents"))]
+fn read_dir(
+    path: &Path,
+    with_metadata: bool,
+) -> result::Result<Vec<PathInfo>, io::Error> {
+    getdents::read_dir_entries(path)?
+        .into_iter()
+        .map(|entry| {
+            // Like with std, only files whose type the file system doesn't
+            // know are read when the metadata isn't needed
+            if with_metadata || entry.file_type == libc::DT_UNKNOWN {
+                let metadata = fs::symlink_metadata(&entry.path)?;
+                return Ok(PathInfo {
+                    name: entry.path,
+                    is_dir: metadata.is_dir(),
+                    is_symlink: metadata.is_symlink(),
+                    metadata: Some(metadata),
+                    ino: Some(entry.ino),
+                });
+            }
+            Ok(PathInfo {
+                name: entry.path,
+                is_dir: entry.file_type == libc::DT_DIR,
+                is_symlink: entry.file_type == libc::DT_LNK,
+                metadata: None,
+                ino: Some(entry.ino),
             })
         })
         .collect()
 }
 
-fn list_files(paths: Vec<PathBuf>) -> Result<Vec<InputRow>> {
-    let mut entries = Vec::<PathInfo>::new();
+/// The inode of a directory entry, which identifies the file without reading
+/// its metadata
+#[cfg(all(unix, not(all(target_os = "linux", feature = "getdents"))))]
+fn entry_ino(entry: &fs::DirEntry) -> Option<u64> {
+    use std::os::unix::fs::DirEntryExt;
 
-    for path in paths {
-        entries.extend(
-            read_dir(&path)
-                .with_context(|| format!("Couldn't list files in {path:?}"))?,
-        )
+    Some(entry.ino())
+}
+
+#[cfg(not(unix))]
+fn entry_ino(_entry: &fs::DirEntry) -> Option<u64> {
+    None
+}
+
+/// Lists the files in a directory, including the contents of subdirectories
+/// when listing recursively. Symlinked directories are only descended into
+/// when following symlinks, and never if they point to a directory that is
+/// already being listed, which would otherwise cause an endless loop
+/// `ancestors` contains the canonical paths of the directories being listed
+/// `device` is the file system that the listing is kept on, if any
+fn walk_dir(
+    path: &Path,
+    options: &ListOptions,
+    device: Option<u64>,
+    ancestors: &mut Vec<PathBuf>,
+) -> Result<Vec<PathInfo>> {
+    let entries = read_dir(path, options.metadata)
+        .with_context(|| format!("Couldn't list files in {path:?}"))?;
+    if !options.recursive {
+        return Ok(entries);
     }
 
-    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut result = Vec::new();
+    for entry in entries {
+        let descend = entry.is_dir
+            || (options.follow && entry.is_symlink && entry.name.is_dir());
+        let name = entry.name.clone();
+        result.push(entry);
+        if !descend {
+            continue;
+        }
+        if device.is_some()
+            && fs::metadata(&name).ok().and_then(|m| device_of(&m)) != device
+        {
+            continue;
+        }
+
+        if options.follow {
+            let real_path = fs::canonicalize(&name)
+                .with_context(|| format!("Couldn't resolve {name:?}"))?;
+            if ancestors.contains(&real_path) {
+                eprintln!(
+                    "Not following {name:?}, which loops back to {real_path:?}"
+                );
+                continue;
+            }
+            ancestors.push(real_path);
+            result.extend(walk_dir(&name, options, device, ancestors)?);
+            ancestors.pop();
+        } else {
+            result.extend(walk_dir(&name, options, device, ancestors)?);
+        }
+    }
+    Ok(result)
+}
+
+/// The device of the file system that a file is on
+#[cfg(unix)]
+fn device_of(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_of(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Lists the files in each of the directories. Entries are sorted within each
+/// directory, while the directories are kept in the order they were passed
+fn list_files(
+    paths: Vec<PathBuf>,
+    options: &ListOptions,
+) -> Result<Vec<InputRow>> {
+    let mut entries = Vec::<(usize, PathInfo)>::new();
+
+    for (section, path) in paths.iter().enumerate() {
+        // Paths from stdin are kept in the order they were read
+        if path == Path::new(STDIN_PATH) {
+            let section_entries = read_paths(path, options.separator)?;
+            entries.extend(
+                section_entries.into_iter().map(|entry| (section, entry)),
+            );
+            continue;
+        }
+        let mut ancestors = Vec::new();
+        if options.follow {
+            ancestors.push(
+                fs::canonicalize(path).with_context(|| {
+                    format!("Couldn't list files in {path:?}")
+                })?,
+            );
+        }
+        let device = if options.one_file_system {
+            let metadata = fs::metadata(path)
+                .with_context(|| format!("Couldn't list files in {path:?}"))?;
+            device_of(&metadata)
+        } else {
+            None
+        };
+        let mut section_entries =
+            walk_dir(path, options, device, &mut ancestors)?;
+        sort_entries(&mut section_entries, options.sort)?;
+        if options.reverse {
+            section_entries.reverse();
+        }
+        entries
+            .extend(section_entries.into_iter().map(|entry| (section, entry)));
+    }
 
     Ok(entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, (section, file))| InputRow {
+            index: index + 1,
+            section,
+            name: file.name,
+            is_dir: file.is_dir,
+            metadata: file.metadata,
+            ino: file.ino,
+        })
+        .collect())
+}
+
+fn sort_entries(entries: &mut [PathInfo], order: SortOrder) -> Result<()> {
+    match order {
+        SortOrder::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortOrder::Locale => {
+            let collator = locale_collator()?;
+            // Names that collate the same, like names that only differ in
+            // case, are ordered by their bytes so that the order is stable
+            entries.sort_by(|a, b| {
+                collate(&collator, &a.name, &b.name)
+                    .then_with(|| a.name.cmp(&b.name))
+            });
+        }
+        SortOrder::Ext => entries.sort_by(|a, b| {
+            a.name
+                .extension()
+                .cmp(&b.name.extension())
+                .then_with(|| a.name.cmp(&b.name))
+        }),
+        SortOrder::Mtime => {
+            let mtime =
+                |entry: &PathInfo| entry.metadata.as_ref()?.modified().ok();
+            entries.sort_by(|a, b| {
+                mtime(a).cmp(&mtime(b)).then_with(|| a.name.cmp(&b.name))
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A collator for the locale of the environment, or the root locale if it
+/// isn't set or is C or POSIX
+fn locale_collator() -> Result<Collator> {
+    let locale = COLLATION_VARS
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|value| !value.is_empty()))
+        .and_then(|value| {
+            // E.g. sv_SE.UTF-8@euro
+            let name = value.split(['.', '@']).next()?.replace('_', "-");
+            name.parse::<Locale>().ok()
+        })
+        .unwrap_or(Locale::UND);
+    let mut options = CollatorOptions::new();
+    options.strength = Some(Strength::Secondary);
+    Collator::try_new(&(&locale).into(), options).map_err(|err| {
+        anyhow!("Couldn't sort by the collation rules of {locale}: {err}")
+    })
+}
+
+/// Compares the paths component by component, so that the files in a
+/// directory are kept together when listing recursively
+fn collate(collator: &Collator, a: &Path, b: &Path) -> Ordering {
+    let (mut a, mut b) = (a.iter(), b.iter());
+    loop {
+        match (a.next(), b.next()) {
+            (Some(a), Some(b)) => {
+                match collator
+                    .compare(&a.to_string_lossy(), &b.to_string_lossy())
+                {
+                    Ordering::Equal => {}
+                    ordering => return ordering,
+                }
+            }
+            (a, b) => return a.is_some().cmp(&b.is_some()),
+        }
+    }
+}
+
+/// Reads a list of paths from a file. The paths are kept in the same order
+/// as in the file
+fn read_files_from(path: &Path, separator: char) -> Result<Vec<InputRow>> {
+    Ok(read_paths(path, separator)?
         .into_iter()
         .enumerate()
         .map(|(index, file)| InputRow {
             index: index + 1,
+            section: 0,
             name: file.name,
             is_dir: file.is_dir,
+            metadata: file.metadata,
+            ino: file.ino,
         })
         .collect())
 }
 
-/// Generates the text content for the temporary file
-/// Since the text will contain file paths(which may not be valid UTF-8)
-/// [`RawOsString`] is used instead of a normal UTF-8 [`String`]
-fn get_input(files: &[InputRow]) -> RawOsString {
-    let list = files
+/// Reads the paths in a file, or stdin if the path is -
+fn read_paths(path: &Path, separator: char) -> Result<Vec<PathInfo>> {
+    let content = if path == Path::new(STDIN_PATH) {
+        let mut content = Vec::new();
+        io::stdin()
+            .read_to_end(&mut content)
+            .context("Couldn't read paths from stdin")?;
+        content
+    } else {
+        fs::read(path)
+            .with_context(|| format!("Couldn't read paths from {path:?}"))?
+    };
+    let content = RawOsString::assert_from_raw_vec(content);
+
+    content
+        .split(separator)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let name = PathBuf::from(name.to_owned().into_os_string());
+            let metadata = fs::symlink_metadata(&name)
+                .with_context(|| format!("Couldn't read {name:?}"))?;
+            Ok(PathInfo {
+                name,
+                is_dir: metadata.is_dir(),
+                is_symlink: metadata.is_symlink(),
+                metadata: Some(metadata),
+                ino: None,
+            })
+        })
+        .collect()
+}
+
+/// Finds how wide each column must be for the cells to line up when separated
+/// by spaces. Other separators don't pad the cells
+fn column_widths<'a>(
+    columns: &[Column],
+    files: impl Iterator<Item = &'a fs::Metadata>,
+    separator: char,
+) -> Vec<usize> {
+    let mut widths = vec![0; columns.len()];
+    if separator != ' ' {
+        return widths;
+    }
+    for metadata in files {
+        for (width, column) in widths.iter_mut().zip(columns) {
+            *width = (*width).max(column.format(metadata).width());
+        }
+    }
+    widths
+}
+
+/// Formats the columns of a file, padded to the widths from
+/// [`column_widths`]. Every column, including the last, is followed by the
+/// separator
+fn format_row(
+    columns: &[Column],
+    metadata: &fs::Metadata,
+    widths: &[usize],
+    separator: char,
+) -> String {
+    columns
         .iter()
-        .map(|res| {
-            let mut row = OsString::from(format!("{: >5} ", res.index));
-            row.push(res.name.clone().into_os_string());
-            if res.is_dir {
-                row.push("/")
+        .zip(widths)
+        .map(|(column, width)| {
+            let cell = column.format(metadata);
+            // Padded by the columns the text takes up in a terminal, since
+            // wide characters like CJK take up two
+            let padding = " ".repeat(width.saturating_sub(cell.width()));
+            if align_right(*column) {
+                format!("{padding}{cell}{separator}")
+            } else {
+                format!("{cell}{padding}{separator}")
             }
-            row
         })
-        .collect::<Vec<OsString>>();
+        .collect()
+}
+
+/// Numbers are aligned to the right, so that their digits line up
+fn align_right(column: Column) -> bool {
+    match column {
+        Column::Inode | Column::Links | Column::Size => true,
+        Column::Mode
+        | Column::Mtime
+        | Column::Owner
+        | Column::Group
+        | Column::Ownership
+        | Column::Timestamp => false,
+    }
+}
+
+/// Writes the text content of the temporary file, row by row
+/// Since the text will contain file paths(which may not be valid UTF-8) the
+/// names are written as raw bytes
+/// The files from each directory are separated by a blank line
+/// Names are escaped, see [`escape`]
+/// The index column is as wide as the largest index, and the other columns
+/// as wide as their widest cell, which takes a pass over the files before
+/// the rows are written
+/// The metadata of the files must have been read if there are any columns
+fn write_input(
+    files: &[InputRow],
+    format: &BufferFormat,
+    transform: &Transform,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    fn metadata(file: &InputRow) -> &fs::Metadata {
+        file.metadata.as_ref().expect("Missing metadata")
+    }
+
+    let columns = &format.columns;
+    let separator = format.separator.as_char();
+    let index_width = if format.separator == Separator::Space {
+        files
+            .iter()
+            .map(|file| file.index.to_string().len())
+            .max()
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    let widths = if columns.is_empty() {
+        Vec::new()
+    } else {
+        column_widths(columns, files.iter().map(metadata), separator)
+    };
+
+    for (i, res) in files.iter().enumerate() {
+        // Like `find -print0`, every row is terminated by NUL rather than
+        // separated
+        if !format.null && i > 0 {
+            out.write_all(b"\n")?;
+        }
+        // Blank rows mean removed files when there are no indexes
+        if format.index && i > 0 && files[i - 1].section != res.section {
+            out.write_all(if format.null { b"\0" } else { b"\n" })?;
+        }
+        if format.index && format.pad_index {
+            write!(out, "{:0>index_width$}{separator}", res.index)?;
+        } else if format.index {
+            write!(out, "{: >index_width$}{separator}", res.index)?;
+        }
+        if !columns.is_empty() {
+            let row = format_row(columns, metadata(res), &widths, separator);
+            out.write_all(row.as_bytes())?;
+        }
+        let mut name = transform.apply(&res.name, res.is_dir);
+        if let Some(base) = &format.relative_to {
+            name = name.strip_prefix(base).map(Path::to_owned).unwrap_or(name);
+        }
+        let name = RawOsString::new(name.into_os_string());
+        if format.null {
+            out.write_all(name.as_raw_bytes())?;
+        } else {
+            out.write_all(rsdir::escape(&name).as_raw_bytes())?;
+        }
+        if res.is_dir {
+            out.write_all(b"/")?;
+        }
+        if format.null {
+            out.write_all(b"\0")?;
+        }
+    }
+    Ok(())
+}
+
+/// Identifies the listed files, so that --apply-stdin can tell whether they
+/// are still the ones that --list listed. Uses FNV-1a, which unlike the hasher
+/// of the standard library is the same in every build
+fn listing_fingerprint(files: &[InputRow]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for file in files {
+        let name = RawOsStr::new(file.name.as_os_str());
+        for &byte in name.as_raw_bytes().iter().chain(b"\0") {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Checks that the listing read by --apply-stdin was written by --list for
+/// the same files
+fn check_fingerprint(
+    edited: &[u8],
+    files: &[InputRow],
+    format: &BufferFormat,
+) -> Result<()> {
+    let terminator = if format.null { b'\0' } else { b'\n' };
+    let header = edited
+        .split(|&byte| byte == terminator)
+        .map(RawOsStr::assert_from_raw_bytes)
+        .find_map(|row| {
+            row.trim_start_matches(' ').strip_prefix(LISTING_HEADER)
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "The listing on stdin has no {LISTING_HEADER:?} row. Pipe a \
+                 listing written by --list"
+            )
+        })?;
+    let fingerprint = format!("{:016x}", listing_fingerprint(files));
+    if header.trim_matches(' ') != fingerprint.as_str() {
+        bail!(
+            "The files changed since they were listed with --list. List them \
+             again"
+        );
+    }
+    Ok(())
+}
+
+/// Writes the listing to edit, piped through --sort-cmd if given
+fn generate_listing(
+    files: &[InputRow],
+    format: &BufferFormat,
+    transform: &Transform,
+    sort_cmd: Option<&str>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let Some(command) = sort_cmd else {
+        return Ok(write_input(files, format, transform, out)?);
+    };
+    // Kept in memory, since the sorted rows are checked against it
+    let mut listing = Vec::new();
+    write_input(files, format, transform, &mut listing)?;
+    out.write_all(&sort_listing(command, listing, format.null)?)?;
+    Ok(())
+}
+
+/// Pipes the listing through a command. Its output must have the same rows,
+/// since a missing row would remove the file
+fn sort_listing(
+    command: &str,
+    listing: Vec<u8>,
+    null: bool,
+) -> Result<Vec<u8>> {
+    let mut words = command.split_whitespace();
+    let Some(program) = words.next() else {
+        bail!("The sort command is empty");
+    };
+    let mut child = Command::new(program)
+        .args(words)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run sort command {command:?}"))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // Written from another thread, so that a command that writes while it
+    // reads can't block on a full pipe. Stdin is closed when it's written
+    let output = thread::scope(|scope| {
+        let listing = &listing;
+        scope.spawn(move || stdin.write_all(listing));
+        child.wait_with_output()
+    })
+    .with_context(|| format!("Failed to run sort command {command:?}"))?;
+    if !output.status.success() {
+        bail!("Sort command {command:?} failed with {}", output.status);
+    }
 
-    RawOsString::new(list.join(&OsString::from("\n")))
+    let terminator = if null { b'\0' } else { b'\n' };
+    let rows = |text: &[u8]| {
+        let mut rows = text
+            .split(|&byte| byte == terminator)
+            .filter(|row| !row.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>();
+        rows.sort_unstable();
+        rows
+    };
+    if rows(&output.stdout) != rows(&listing) {
+        bail!(
+            "Sort command {command:?} changed the rows of the listing instead \
+             of only reordering them"
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Adds context to an error from writing the listing. Other errors, like a
+/// failed sort command, are about the listing itself and are kept as they are
+fn write_context(
+    err: anyhow::Error,
+    context: impl fmt::Display + Send + Sync + 'static,
+) -> anyhow::Error {
+    if err.is::<io::Error>() {
+        err.context(context)
+    } else {
+        err
+    }
 }
 
-/// Writes the content to a new temporary file and returns a handle
+/// Creates a new temporary file, with the content written by `write`, and
+/// returns a handle
 /// Uses [`NamedTempFile`] since we need the to pass the path to the editor
 /// This should be fine as the file should have a short lifespan
 /// The file will be automatically removed when dropped
-fn write_file(file_input: &RawOsString) -> Result<NamedTempFile> {
-    let mut file =
-        NamedTempFile::new().context("Failed to create temporary file")?;
-    file.write_all(file_input.as_raw_bytes())
-        .context("Failed to write to temporary file")?;
+fn write_file(
+    tmpdir: Option<&Path>,
+    write: impl FnOnce(&mut io::BufWriter<&mut NamedTempFile>) -> Result<()>,
+) -> Result<NamedTempFile> {
+    let mut file = match tmpdir {
+        Some(dir) => NamedTempFile::new_in(dir),
+        None => NamedTempFile::new(),
+    }
+    .context("Failed to create temporary file")?;
+    let mut writer = io::BufWriter::new(&mut file);
+    write(&mut writer)
+        .and_then(|_| Ok(writer.flush()?))
+        .map_err(|err| {
+            write_context(err, "Failed to write to temporary file")
+        })?;
+    drop(writer);
     Ok(file)
 }
 
-fn read_file(path: &Path) -> Result<RawOsString> {
-    Ok(RawOsString::assert_from_raw_vec(
-        fs::read(path).context("Failed to read temporary file")?,
-    ))
+/// Adds a comment about a conflict at the start of the edited text, replacing
+/// earlier comments. Without indexes there can't be comments, so the conflict
+/// is printed instead
+fn annotate_conflict(
+    path: &Path,
+    conflict: &str,
+    format: &BufferFormat,
+) -> Result<()> {
+    if !format.index {
+        eprintln!("{conflict}");
+        return Ok(());
+    }
+    let text = fs::read(path).context("Failed to read temporary file")?;
+    let terminator = if format.null { b'\0' } else { b'\n' };
+    let mut annotated = format!("{COMMENT} {conflict}").into_bytes();
+    annotated.push(terminator);
+    for row in text
+        .split_inclusive(|byte| *byte == terminator)
+        .filter(|row| !row.starts_with(COMMENT.as_bytes()))
+    {
+        annotated.extend_from_slice(row);
+    }
+    fs::write(path, annotated).context("Failed to write to temporary file")
+}
+
+/// Keeps the temporary file on disk so that the edits can be recovered, and
+/// prints its path
+fn keep_file(file: NamedTempFile) -> Result<()> {
+    let (_, path) = file.keep().context("Failed to keep temporary file")?;
+    eprintln!("Saved edited buffer to {path:?}");
+    Ok(())
+}
+
+/// Reads a buffer saved by a previous session and checks that its indexes
+/// still refer to the current files
+fn resume_input(
+    path: &Path,
+    files: &[InputRow],
+    format: &BufferFormat,
+) -> Result<RawOsString> {
+    let buffer = RawOsString::assert_from_raw_vec(
+        fs::read(path)
+            .with_context(|| format!("Failed to read buffer {path:?}"))?,
+    );
+    parse_files(buffer.as_raw_bytes(), format, files.len())
+        .and_then(|output| plan::check_indexes(files, &output))
+        .with_context(|| format!("Couldn't resume from {path:?}"))?;
+    Ok(buffer)
+}
+
+/// Reads the files from a listing written by --dump-listing. The files must
+/// still exist under the same names
+fn read_listing(path: &Path, format: &BufferFormat) -> Result<Vec<InputRow>> {
+    let open = || {
+        fs::File::open(path)
+            .map(io::BufReader::new)
+            .with_context(|| format!("Failed to read listing {path:?}"))
+    };
+    let file_count = read_rows(open()?, format).count();
+    parse_files(open()?, format, file_count)
+        .with_context(|| format!("Invalid listing {path:?}"))?
+        .into_iter()
+        .map(|row| {
+            // Directories are written with a trailing slash
+            let name = row.name.components().as_path().to_owned();
+            let metadata = fs::symlink_metadata(&name).with_context(|| {
+                format!("Couldn't find {name:?} from listing {path:?}")
+            })?;
+            Ok(InputRow {
+                index: row.index,
+                section: 0,
+                name,
+                is_dir: metadata.is_dir(),
+                metadata: Some(metadata),
+                ino: None,
+            })
+        })
+        .collect()
+}
+
+/// Prints how --rename-preview changes the names, and asks whether to apply
+/// them, edit them first or abort. Returns whether to open the editor
+fn preview_replace(
+    replace: &Replace,
+    files: &[InputRow],
+    args: &Args,
+    color: bool,
+) -> Result<bool> {
+    let mut preview = String::new();
+    for file in files {
+        let Some(name) = file.name.file_name().and_then(OsStr::to_str) else {
+            continue;
+        };
+        let Some((old, new)) = replace.preview(name, color) else {
+            continue;
+        };
+        let dir = match file.name.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                format!("{}/", parent.to_string_lossy())
+            }
+            _ => String::new(),
+        };
+        preview.push_str(&format!("{dir}{old} -> {dir}{new}\n"));
+    }
+    if preview.is_empty() {
+        bail!("No names match {:?}", replace.regex.as_str());
+    }
+    show_preview(preview.as_bytes(), args.no_pager)
+        .context("Failed to show the new names")?;
+    if args.yes {
+        return Ok(false);
+    }
+    match ask("Apply these names?", "y/e/N")?.as_str() {
+        "y" | "yes" => Ok(false),
+        "e" | "edit" => Ok(true),
+        _ => bail!("Aborted"),
+    }
+}
+
+/// Shows a preview of changes through the pager when it doesn't fit in the
+/// terminal, like git does, and prints it otherwise
+fn show_preview(preview: &[u8], no_pager: bool) -> io::Result<()> {
+    let lines = preview.iter().filter(|&&byte| byte == b'\n').count();
+    // The question after the preview takes a line too
+    let fits = terminal_height().is_none_or(|height| lines < height);
+    if no_pager || fits || !io::stdout().is_terminal() {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(preview)?;
+        return stdout.flush();
+    }
+
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.into());
+    let mut words = pager.split_whitespace();
+    let mut command = Command::new(words.next().unwrap_or(DEFAULT_PAGER));
+    command.args(words).stdin(process::Stdio::piped());
+    // Let less show the colors, and quit on its own when it isn't needed
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    let Ok(mut child) = command.spawn() else {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(preview)?;
+        return stdout.flush();
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may be quit before reading everything
+        match stdin.write_all(preview) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(err)
+            }
+            _ => {}
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+/// Returns the number of rows of the terminal of stdout
+#[cfg(unix)]
+fn terminal_height() -> Option<usize> {
+    // SAFETY: winsize is plain data, which the ioctl fills in
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    // SAFETY: TIOCGWINSZ only writes to the winsize that is passed
+    let result = unsafe {
+        libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size)
+    };
+    if result == 0 && size.ws_row > 0 {
+        Some(size.ws_row.into())
+    } else {
+        env::var("LINES").ok()?.parse().ok()
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_height() -> Option<usize> {
+    env::var("LINES").ok()?.parse().ok()
+}
+
+/// Asks a question on stderr and returns the trimmed, lowercased answer. It's
+/// read from stdin, or from the terminal if --apply-stdin read stdin
+fn ask(question: &str, choices: &str) -> Result<String> {
+    let tty = if STDIN_CONSUMED.load(atomic::Ordering::Relaxed) {
+        let tty = fs::File::open(TTY_PATH).map_err(|_| {
+            anyhow!(
+                "Can't ask {question:?}, since stdin was the listing and there \
+                 is no terminal to answer on. Pass -y to apply the changes \
+                 without asking"
+            )
+        })?;
+        Some(tty)
+    } else {
+        None
+    };
+    eprint!("{question} [{choices}] ");
+    let mut answer = String::new();
+    match tty {
+        Some(tty) => io::BufReader::new(tty).read_line(&mut answer),
+        None => io::stdin().read_line(&mut answer),
+    }
+    .context("Failed to read answer")?;
+    Ok(answer.trim().to_lowercase())
+}
+
+/// Asks a yes/no question on stderr and reads the answer like `ask`
+/// Anything but an explicit yes counts as no
+fn confirm(question: &str) -> Result<bool> {
+    Ok(matches!(ask(question, "y/N")?.as_str(), "y" | "yes"))
 }
 
 fn get_editor() -> String {
-    env::var(EDITOR_ENV).unwrap_or_else(|_| DEFAULT_EDITOR.into())
+    EDITOR_VARS
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|editor| !editor.is_empty()))
+        .unwrap_or_else(|| DEFAULT_EDITOR.into())
 }
 
-fn open_editor(editor: &String, file_path: &Path) -> Result<()> {
-    Command::new(editor)
-        .arg(file_path)
+/// `use_tty` connects the editor to the terminal instead of stdin, which is
+/// needed when the paths were read from stdin
+fn open_editor(editor: &String, file_path: &Path, use_tty: bool) -> Result<()> {
+    let mut command = Command::new(editor);
+    let name = Path::new(editor).file_stem().and_then(OsStr::to_str);
+    if let Some((_, flag)) = WAIT_FLAGS
+        .iter()
+        .find(|(wait_editor, _)| name == Some(wait_editor))
+    {
+        command.arg(flag);
+    }
+    command.arg(file_path);
+    if name.is_some_and(|name| TERMINAL_EDITORS.contains(&name)) {
+        // Connected to the terminal itself, so that it works when stdin or
+        // stdout is a pipe, like with --emit-script or --print0
+        let tty = fs::OpenOptions::new().read(true).write(true).open(TTY_PATH);
+        let Ok(tty) = tty else {
+            bail!(
+                "Editor {editor:?} needs a terminal, but there is none. Set \
+                 EDITOR to an editor that doesn't, or edit the listing in a \
+                 pipeline with --list and --apply-stdin"
+            );
+        };
+        command.stdin(tty.try_clone()?).stdout(tty);
+    } else if use_tty {
+        if let Ok(tty) = fs::File::open(TTY_PATH) {
+            command.stdin(tty);
+        }
+    }
+    let original = fs::read(file_path).ok();
+    let start = Instant::now();
+    command
         .status()
         .with_context(|| format!("Failed to open editor {editor:?}"))
         .and_then(|status| {
             if status.success() {
+                if start.elapsed() < QUICK_EXIT
+                    && io::stderr().is_terminal()
+                    && fs::read(file_path).ok() == original
+                {
+                    eprintln!(
+                        "Editor {editor:?} exited without changing the file. \
+                         If it opens a window, it may need a flag to wait \
+                         until the file is closed"
+                    );
+                }
                 return Ok(());
             }
 
@@ -145,121 +1517,1053 @@ fn open_editor(editor: &String, file_path: &Path) -> Result<()> {
         })
 }
 
-fn parse_files(input: RawOsString) -> Result<Vec<OutputRow>> {
-    input
-        .trim_matches(' ')
-        .split('\n')
-        .filter(|row| row.ne(&""))
+/// Reverses [`rsdir::escape`]
+fn unescape(name: &RawOsStr) -> Result<RawOsString> {
+    let mut bytes = name.as_raw_bytes().iter().copied();
+    let mut unescaped = Vec::new();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            unescaped.push(byte);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'\\') => unescaped.push(b'\\'),
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(b't') => unescaped.push(b'\t'),
+            Some(b'x') => {
+                let digits = [bytes.next(), bytes.next()];
+                let code = match digits {
+                    [Some(high), Some(low)] => {
+                        std::str::from_utf8(&[high, low])
+                            .ok()
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    }
+                    _ => None,
+                }
+                .filter(u8::is_ascii)
+                .ok_or_else(|| anyhow!("Invalid escape sequence \\x"))?;
+                unescaped.push(code);
+            }
+            Some(other) => {
+                bail!("Invalid escape sequence \\{}", char::from(other))
+            }
+            None => bail!("Unfinished escape sequence"),
+        }
+    }
+    // Escape sequences only produce ASCII characters
+    Ok(RawOsString::assert_from_raw_vec(unescaped))
+}
+
+/// Parses the edited text, row by row. Blank lines, such as the ones
+/// separating the directories, and rows starting with # are ignored, as are
+/// the columns
+/// Only the text is read row by row. The parsed rows are all kept, since the
+/// plan is computed from every row at once
+/// `file_count` is the number of listed files, which is needed to match the
+/// rows by position when there are no indexes
+fn parse_files(
+    input: impl BufRead,
+    format: &BufferFormat,
+    file_count: usize,
+) -> Result<Vec<OutputRow>> {
+    if !format.index {
+        return parse_positional(input, format, file_count);
+    }
+
+    let mut output = Vec::new();
+    for (i, row) in read_rows(input, format)
+        .filter(|row| {
+            !row.as_ref().is_ok_and(|row| {
+                let row = row.trim_start_matches(' ');
+                row.is_empty() || row.starts_with(COMMENT)
+            })
+        })
         .enumerate()
-        .map(|(i, row)| {
-            let (index_str, name_str) =
-                row.trim_matches(' ')
-                    .split_once(' ')
-                    .ok_or_else(|| anyhow!("Couldn't find index at row {i}"))?;
+    {
+        let row = row?;
+        check_trailing(&row, format, i)?;
+        let parsed = match format.separator {
+            Separator::Space => row.trim_matches(' ').split_once(' '),
+            Separator::Tab => row.split_once('\t'),
+        }
+        .ok_or_else(|| row_error(i, format!("Couldn't find index at row {i}")))
+        .and_then(|(index_str, name_str)| {
             let index_str = index_str.to_str_lossy();
-            let index = index_str.parse::<usize>().map_err(|_| {
-                anyhow!("Invalid index {index_str:?} at row {i}",)
+            let index = index_str.trim().parse::<usize>().map_err(|_| {
+                row_error(i, format!("Invalid index {index_str:?} at row {i}"))
             })?;
-            let name = PathBuf::from(
-                name_str.trim_matches(" ").to_owned().into_os_string(),
-            );
-            Ok(OutputRow { index, name })
-        })
-        .collect()
+            Ok((index, name_str))
+        });
+        let (index, name_str) = match parsed {
+            Ok(parsed) => parsed,
+            // The files are listed in the order of their indexes
+            Err(_) if format.lenient => {
+                eprintln!(
+                    "Row {i} has no index, so it was matched to index {} by \
+                     its position",
+                    i + 1
+                );
+                (i + 1, row.as_ref())
+            }
+            Err(err) => return Err(err),
+        };
+        let (cells, name) = parse_cells(name_str, format, i)?;
+        output.push(OutputRow { index, name, cells });
+    }
+    Ok(output)
 }
 
-fn rm_file(file: &InputRow, verbose: bool) -> Result<()> {
-    if file.is_dir {
-        fs::remove_dir_all(&file.name)
-    } else {
-        fs::remove_file(&file.name)
+/// Parses the edited text when it has no indexes. Every row is matched to the
+/// file at the same position, and an empty row means the file is removed
+/// Since the position is all that identifies a file, the number of rows must
+/// stay the same
+fn parse_positional(
+    input: impl BufRead,
+    format: &BufferFormat,
+    file_count: usize,
+) -> Result<Vec<OutputRow>> {
+    let mut output = Vec::new();
+    let mut row_count = 0;
+    for (i, row) in read_rows(input, format).enumerate() {
+        let row = row?;
+        check_trailing(&row, format, i)?;
+        row_count += 1;
+        // Keep counting to report the number of rows
+        if row.trim_matches(' ').is_empty() || row_count > file_count {
+            continue;
+        }
+        let (cells, name) = parse_cells(&row, format, i)?;
+        output.push(OutputRow {
+            index: i + 1,
+            name,
+            cells,
+        });
     }
-    .with_context(|| {
-        format!(
-            "Error deleting {} {:?}",
-            if file.is_dir { "directory" } else { "file" },
-            file.name
+    if row_count != file_count {
+        bail!(
+            "Expected {file_count} rows but found {row_count}. Empty a row \
+             instead of deleting it to remove a file"
+        );
+    }
+    Ok(output)
+}
+
+/// Reads the edited text row by row. A separator at the end of the text ends
+/// the last row rather than starting a new one
+fn read_rows<'a>(
+    input: impl BufRead + 'a,
+    format: &BufferFormat,
+) -> impl Iterator<Item = Result<RawOsString>> + 'a {
+    let null = format.null;
+    let mut rows = input.split(if null { b'\0' } else { b'\n' }).peekable();
+    std::iter::from_fn(move || {
+        let row = rows.next()?;
+        // Editors may add a newline at the end of the file
+        if null
+            && rows.peek().is_none()
+            && row.as_ref().is_ok_and(|row| row == b"\n")
+        {
+            return None;
+        }
+        Some(
+            row.map(RawOsString::assert_from_raw_vec)
+                .context("Failed to read the edited text"),
         )
     })
-    .map(|_| {
-        if verbose {
-            println!(
-                "Removed {} {:?}",
-                if file.is_dir { "directory" } else { "file" },
-                file.name
-            )
+}
+
+/// An error in a row of the buffer
+fn row_error(i: usize, message: String) -> anyhow::Error {
+    plan::PlanError::row(i, None, message).into()
+}
+
+/// Rejects rows that end with whitespace with --strict. Spaces are otherwise
+/// trimmed, except when separated by tabs, and carriage returns are escaped
+/// in names, so one at the end was added by an editor
+fn check_trailing(
+    row: &RawOsStr,
+    format: &BufferFormat,
+    i: usize,
+) -> Result<()> {
+    if format.strict
+        && (format.separator == Separator::Space && row.ends_with(' ')
+            || !format.null && row.ends_with('\r'))
+    {
+        return Err(row_error(i, format!("Trailing whitespace at row {i}")));
+    }
+    Ok(())
+}
+
+/// Splits a row into the text of each column and the unescaped name
+fn parse_cells(
+    row: &RawOsStr,
+    format: &BufferFormat,
+    i: usize,
+) -> Result<(Vec<String>, PathBuf)> {
+    let mut name_str = row;
+    let mut cells = Vec::new();
+    let missing_name =
+        || row_error(i, format!("Couldn't find name at row {i}"));
+    match format.separator {
+        Separator::Space => {
+            for column in &format.columns {
+                let mut words = Vec::new();
+                for _ in 0..column.words() {
+                    let (word, rest) = name_str
+                        .trim_start_matches(' ')
+                        .split_once(' ')
+                        .ok_or_else(missing_name)?;
+                    words.push(word.to_str_lossy().into_owned());
+                    name_str = rest;
+                }
+                cells.push(words.join(" "));
+            }
+            name_str = name_str.trim_matches(' ');
         }
-    })
+        Separator::Tab => {
+            for _ in &format.columns {
+                let (cell, rest) =
+                    name_str.split_once('\t').ok_or_else(missing_name)?;
+                cells.push(cell.to_str_lossy().into_owned());
+                name_str = rest;
+            }
+        }
+    }
+    let name = if format.null {
+        name_str.to_owned()
+    } else {
+        unescape(name_str).with_context(|| {
+            plan::PlanError::row(i, None, format!("Invalid name at row {i}"))
+        })?
+    };
+    let name = PathBuf::from(name.into_os_string());
+    match &format.relative_to {
+        // Absolute names are kept as is
+        Some(base) => Ok((cells, base.join(name))),
+        None => Ok((cells, name)),
+    }
 }
-fn mv_file(from: &InputRow, to: &OutputRow, verbose: bool) -> Result<()> {
-    fs::rename(&from.name, &to.name)
-        .with_context(|| {
-            format!(
-                "Error moving {} {:?} to {:?}",
-                if from.is_dir { "directory" } else { "file" },
-                from.name,
-                to.name
-            )
-        })
-        .map(|_| {
-            if verbose {
-                println!(
-                    "Moved {} {:?} to {:?}",
-                    if from.is_dir { "directory" } else { "file" },
-                    from.name,
-                    to.name
-                )
+
+/// Returns the colors to use for names, or `None` if the output shouldn't be
+/// colored. `auto` follows https://no-color.org
+fn get_colors(when: ColorWhen) -> Option<LsColors> {
+    let color = match when {
+        ColorWhen::Always => true,
+        ColorWhen::Never => false,
+        ColorWhen::Auto => {
+            io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()
+        }
+    };
+    color.then(LsColors::from_env)
+}
+
+fn print_completions(shell: Shell) {
+    let mut command = Args::command();
+    let name = command.get_name().to_owned();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+fn print_manpage() -> Result<()> {
+    clap_mangen::Man::new(Args::command())
+        .render(&mut io::stdout())
+        .context("Failed to write man page")
+}
+
+/// The directories that new names must be inside with --restrict, and that
+/// they can't go above with .. without --allow-parent
+fn listed_roots(args: &Args) -> Vec<PathBuf> {
+    if args.files_from.is_some() || args.null_stdin || args.map.is_some() {
+        return vec![PathBuf::from(DEFAULT_DIR)];
+    }
+    // Paths read from stdin are relative to the current directory
+    get_path_args(&args.path)
+        .into_iter()
+        .map(|path| {
+            if path == Path::new(STDIN_PATH) {
+                PathBuf::from(DEFAULT_DIR)
+            } else {
+                path
             }
         })
+        .collect()
+}
+
+/// Resolves the listed directories, so that the new names can be checked
+/// against them
+fn canonical_roots(roots: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    roots
+        .iter()
+        .map(|root| {
+            fs::canonicalize(root)
+                .with_context(|| format!("Couldn't resolve {root:?}"))
+        })
+        .collect()
 }
 
-fn update_files(
+/// Checks that the rows are still in the order of the listing, with an index
+/// on at most one row unless copying, and that directories still end with a
+/// slash
+fn check_strict(
     input: &[InputRow],
     output: &[OutputRow],
-    verbose: bool,
+    copy: bool,
 ) -> Result<()> {
-    let input_idxs: HashSet<_> = input.iter().map(|row| row.index).collect();
-    output.iter().enumerate().try_for_each(|(i, output_row)| {
-        if !input_idxs.contains(&output_row.index) {
-            bail!("Unknown index {} at row {i}", output_row.index)
-        } else {
-            Ok(())
+    let dirs = input
+        .iter()
+        .filter(|file| file.is_dir)
+        .map(|file| file.index)
+        .collect::<HashSet<_>>();
+    let mut previous = None;
+    for row in output {
+        match previous {
+            Some(previous) if row.index < previous => bail!(
+                "Index {} comes after index {previous}, but rows can't be \
+                 reordered with --strict",
+                row.index
+            ),
+            Some(previous) if row.index == previous && !copy => bail!(
+                "Index {} is on several rows, which isn't allowed with \
+                 --strict",
+                row.index
+            ),
+            _ => {}
         }
-    })?;
+        previous = Some(row.index);
 
-    let output_hash = output
-        .iter()
-        .map(|row| (row.index, row))
-        .collect::<HashMap<_, _>>();
+        let name = RawOsStr::new(row.name.as_os_str());
+        if dirs.contains(&row.index) && !name.ends_with('/') {
+            bail!(
+                "{:?} is a directory, but its row doesn't end with /, which \
+                 is required with --strict",
+                row.name
+            );
+        }
+    }
+    Ok(())
+}
 
-    input.iter().try_for_each(|input_row| -> Result<()> {
-        match output_hash.get(&input_row.index) {
-            None => rm_file(input_row, verbose),
-            Some(output_row) if output_row.name != input_row.name => {
-                mv_file(input_row, output_row, verbose)
+/// Applies the edited buffer and returns whether there were any changes
+fn apply_changes(
+    args: &Args,
+    format: &BufferFormat,
+    input_files: &[InputRow],
+    file_output: impl BufRead,
+    session: &mut Session,
+) -> Result<bool> {
+    let mut output_files = parse_files(file_output, format, input_files.len())?;
+    if args.strict {
+        check_strict(input_files, &output_files, args.copy)?;
+    }
+    if let Some(numbering) = &args.number_by_order {
+        numbering.number_rows(&mut output_files);
+    }
+    apply_rows(args, &format.columns, input_files, &output_files, session)
+}
+
+/// Applies the new names and columns of the rows and returns whether there
+/// were any changes
+fn apply_rows(
+    args: &Args,
+    columns: &[Column],
+    input_files: &[InputRow],
+    output_files: &[OutputRow],
+    session: &mut Session,
+) -> Result<bool> {
+    let colors = get_colors(args.color);
+    let roots = listed_roots(args);
+    let canonical_roots = if args.restrict || !args.allow_parent {
+        canonical_roots(&roots)?
+    } else {
+        Vec::new()
+    };
+    let plan = plan::compute_plan(
+        input_files,
+        output_files,
+        &plan::PlanOptions {
+            columns,
+            copy: args.copy,
+            expand_env: args.expand_env,
+            normalize: args.normalize.is_some(),
+            auto_suffix: args.auto_suffix,
+            backup: args.backup.as_deref().map(|suffix| match suffix {
+                NUMBERED_BACKUP => plan::Backup::Numbered,
+                _ => plan::Backup::Suffix(suffix),
+            }),
+            roots: &canonical_roots,
+            restrict: args.restrict,
+            allow_parent: args.allow_parent,
+            force: args.force,
+            recursive_delete: args.recursive_delete,
+            parents: args.parents,
+            allow_mount_points: args.allow_mount_points,
+            portable_names: args.portable_names,
+            order: args.order,
+            notes: args.verbose >= 2,
+        },
+        |conflict, can_overwrite| {
+            let ask_conflicts = match args.on_conflict {
+                OnConflict::Auto => !args.yes && io::stdin().is_terminal(),
+                OnConflict::Ask => true,
+                OnConflict::Fail => false,
+            };
+            if !ask_conflicts {
+                return Ok(plan::Resolution::Fail);
             }
-            _ => Ok(()), // No change
+            let answer = if can_overwrite {
+                ask(
+                    &format!("{conflict}. Skip, overwrite, rename or edit?"),
+                    "s/o/r/e/Q",
+                )?
+            } else {
+                ask(&format!("{conflict}. Skip, rename or edit?"), "s/r/e/Q")?
+            };
+            Ok(match answer.as_str() {
+                "s" | "skip" => plan::Resolution::Skip,
+                "o" | "overwrite" if can_overwrite => {
+                    plan::Resolution::Overwrite
+                }
+                "r" | "rename" => plan::Resolution::AutoSuffix,
+                "e" | "edit" => plan::Resolution::Edit,
+                _ => plan::Resolution::Fail,
+            })
+        },
+    )?;
+    for note in plan.notes() {
+        println!("{note}");
+    }
+
+    if args.emit_script {
+        script::write_script(plan.operations(), &mut io::stdout())
+            .context("Failed to write script")?;
+        return Ok(!plan.is_empty());
+    }
+    plan.check_permissions()?;
+    plan.check_space()?;
+    let removes = plan
+        .operations()
+        .iter()
+        .any(|operation| matches!(operation, plan::Operation::Remove { .. }));
+    if (args.confirm
+        || plan.operations().len() > args.change_threshold
+        || (args.confirm_removals && removes))
+        && !plan.is_empty()
+    {
+        let mut diff = Vec::new();
+        plan::write_diff(plan.operations(), colors.as_ref(), &mut diff)
+            .and_then(|()| show_preview(&diff, args.no_pager))
+            .context("Failed to show the changes")?;
+        if !args.yes && !confirm("Apply these changes?")? {
+            bail!("Aborted");
         }
-    })
+    }
+    let mut apply_all = args.yes || args.interactive == Interactive::Never;
+    let mut log = args
+        .log_file
+        .as_ref()
+        .map(|path| {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Couldn't open log file {path:?}"))
+        })
+        .transpose()?;
+    // On Linux, the changes are made relative to the listed directories, so
+    // that they stay inside even if the files change. Names outside of them
+    // are only allowed without --restrict, and are changed by their path
+    let sandbox = Sandbox::open(&roots, args.restrict)
+        .context("Couldn't open the listed directories")?;
+    let start = Instant::now();
+    let mut outcomes = Vec::new();
+    let result = plan.apply_with_report(
+        &plan::ApplyOptions {
+            sandbox: sandbox.as_ref(),
+            track_renames: !args.no_track_renames,
+            keep_acls: !args.no_acl,
+            keep_selinux: !args.no_selinux,
+        },
+        log.as_mut(),
+        |operation| {
+            if apply_all {
+                return Ok(true);
+            }
+            match ask(&format!("{operation}?"), "y/n/a/q")?.as_str() {
+                "y" | "yes" => Ok(true),
+                "a" | "all" => {
+                    apply_all = true;
+                    Ok(true)
+                }
+                "q" | "quit" => bail!("Aborted"),
+                _ => Ok(false),
+            }
+        },
+        |event| match event {
+            plan::Event::Applied(operation) if args.verbose >= 1 => {
+                println!(
+                    "{}",
+                    plan::describe_applied(operation, colors.as_ref())
+                );
+            }
+            plan::Event::Declined(operation) if args.verbose >= 2 => {
+                println!("Skipped: {operation}");
+            }
+            plan::Event::Skipped(operation, reason) => {
+                eprintln!("Skipped: {operation}. {reason}");
+            }
+            plan::Event::Relocated { from, to } => {
+                eprintln!("{from:?} was renamed to {to:?} after it was listed");
+            }
+            plan::Event::Warning(warning) => eprintln!("{warning}"),
+            _ => {}
+        },
+        &mut outcomes,
+    );
+    let applied = plan
+        .operations()
+        .iter()
+        .zip(&outcomes)
+        .filter(|(_, outcome)| **outcome == plan::Outcome::Applied)
+        .map(|(operation, _)| operation);
+    session.names = surviving_names(input_files, applied);
+    session.add_renames();
+    session
+        .report
+        .extend(plan.operations().iter().cloned().zip(outcomes));
+    result?;
+    if args.verbose >= 3 {
+        println!("Applied changes in {:?}", start.elapsed());
+    }
+    Ok(!plan.is_empty())
 }
 
-fn main() -> Result<()> {
+fn main() {
     let args = Args::parse();
+    let error_format = args.error_format;
+    if let Err(err) = run(args) {
+        print_error(error_format, &err);
+        process::exit(1);
+    }
+}
+
+/// Prints an error on stderr, with its causes, in the format of
+/// `--error-format`
+fn print_error(format: ErrorFormat, err: &anyhow::Error) {
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {err:?}"),
+        ErrorFormat::Json => {
+            let details = err.downcast_ref::<plan::PlanError>();
+            let kind = details.map_or("error", |details| match details.kind {
+                plan::ErrorKind::InvalidRow => "invalid_row",
+                plan::ErrorKind::Conflict => "conflict",
+                plan::ErrorKind::Apply => "apply",
+            });
+            let name = |path: Option<&PathBuf>| {
+                path.map(|path| path.to_string_lossy().into_owned())
+            };
+            let io_error = err
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<io::Error>())
+                .map(|io_error| io_error.kind().to_string());
+            let causes = err.chain().skip(1).map(ToString::to_string);
+            let error = serde_json::json!({
+                "message": err.to_string(),
+                "causes": causes.collect::<Vec<_>>(),
+                "kind": kind,
+                "row": details.and_then(|details| details.row),
+                "path": name(details.and_then(|details| details.path.as_ref())),
+                "target": name(details.and_then(|details| details.target.as_ref())),
+                "io_error": io_error,
+            });
+            eprintln!("{error}");
+        }
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    match args.command {
+        Some(Commands::Completions { shell }) => {
+            print_completions(shell);
+            return Ok(());
+        }
+        Some(Commands::Manpage) => return print_manpage(),
+        Some(Commands::Serve { .. }) => {
+            return serve::serve(io::stdin().lock(), io::stdout().lock())
+        }
+        None => {}
+    }
 
-    let path_args = get_path_args(args.path);
     let editor = get_editor();
+    let mut columns = Vec::new();
+    if args.inode {
+        columns.push(Column::Inode);
+    }
+    if args.long {
+        columns.extend([Column::Mode, Column::Links]);
+    }
+    if args.owner || args.long {
+        columns.extend([Column::Owner, Column::Group]);
+    }
+    if args.chown {
+        columns.push(Column::Ownership);
+    }
+    if args.size || args.long {
+        columns.push(Column::Size);
+    }
+    if args.mtime || args.long {
+        columns.push(Column::Mtime);
+    }
+    if args.touch {
+        columns.push(Column::Timestamp);
+    }
+    let mut session = Session::default();
+    let format = BufferFormat {
+        columns,
+        index: !args.no_index,
+        pad_index: args.pad_index,
+        separator: args.separator,
+        null: args.null_buffer,
+        relative_to: args.relative_to.clone(),
+        strict: args.strict,
+        lenient: args.lenient,
+    };
+
+    if let (Some(path), Some(baseline)) = (&args.from_listing, &args.baseline) {
+        let result = read_listing(baseline, &format).and_then(|input_files| {
+            let file_output = fs::File::open(path)
+                .map(io::BufReader::new)
+                .with_context(|| format!("Failed to read listing {path:?}"))?;
+            apply_changes(
+                &args,
+                &format,
+                &input_files,
+                file_output,
+                &mut session,
+            )
+        });
+        report_session(&args, &session)?;
+        if let Err(err) = result {
+            print_error(args.error_format, &err);
+            process::exit(1);
+        }
+        return print_names(&args, &session.names);
+    }
+    if let Some(path) = &args.map {
+        let result = map::read_map(path).and_then(|(input_files, output)| {
+            apply_rows(&args, &[], &input_files, &output, &mut session)
+        });
+        report_session(&args, &session)?;
+        if let Err(err) = result {
+            print_error(args.error_format, &err);
+            process::exit(1);
+        }
+        return print_names(&args, &session.names);
+    }
+
+    let files_from = if args.null_stdin {
+        Some(PathBuf::from(STDIN_PATH))
+    } else {
+        args.files_from.clone()
+    };
+    let stdin_paths = args.path.iter().filter(|path| *path == STDIN_PATH);
+    if stdin_paths.count() > 1 {
+        bail!("{STDIN_PATH} can only be given once");
+    }
+    let paths_from_stdin = files_from
+        .as_deref()
+        .is_some_and(|path| path == Path::new(STDIN_PATH))
+        || args.path.iter().any(|path| path == STDIN_PATH);
+    if args.apply_stdin && paths_from_stdin {
+        bail!("Can't read paths from stdin with --apply-stdin");
+    }
+    // Read before listing, since answers to questions are also read from
+    // stdin
+    let edited_stdin = if args.apply_stdin {
+        let mut edited = Vec::new();
+        io::stdin()
+            .read_to_end(&mut edited)
+            .context("Couldn't read the listing from stdin")?;
+        STDIN_CONSUMED.store(true, atomic::Ordering::Relaxed);
+        Some(edited)
+    } else {
+        None
+    };
+    if args.null && files_from.is_none() && !paths_from_stdin {
+        bail!("--null requires --files-from or {STDIN_PATH}");
+    }
+    let separator = if args.null || args.null_stdin {
+        '\0'
+    } else {
+        '\n'
+    };
+
+    // The first listing may be replaced by a saved buffer
+    let mut resume = args.resume.clone();
+    loop {
+        let start = Instant::now();
+        let input_files = match &files_from {
+            Some(path) => read_files_from(path, separator)?,
+            None => list_files(
+                get_path_args(&args.path),
+                &ListOptions {
+                    recursive: args.recursive,
+                    follow: args.follow,
+                    one_file_system: args.one_file_system,
+                    separator,
+                    sort: args.sort,
+                    reverse: args.reverse,
+                    metadata: !format.columns.is_empty()
+                        || args.sort == SortOrder::Mtime,
+                },
+            )?,
+        };
+        if args.verbose >= 3 {
+            println!(
+                "Listed {} files in {:?}",
+                input_files.len(),
+                start.elapsed()
+            );
+        }
+        if let Some(base) = &format.relative_to {
+            if let Some(file) =
+                input_files.iter().find(|file| !file.name.starts_with(base))
+            {
+                bail!(
+                    "{:?} isn't inside {base:?}, see --relative-to",
+                    file.name
+                );
+            }
+        }
+        if let Some(max_files) = args.max_files {
+            if input_files.len() > max_files {
+                bail!(
+                    "Found {} files, which is more than the limit of {max_files}",
+                    input_files.len()
+                );
+            }
+        }
+        if input_files.len() > args.listing_threshold
+            && !args.yes
+            && !confirm(&format!("Edit {} files?", input_files.len()))?
+        {
+            bail!("Aborted");
+        }
+
+        let replace = match args.rename_preview.as_deref() {
+            Some([regex, replacement]) => Some(Replace {
+                regex: Regex::new(regex)
+                    .with_context(|| format!("Invalid regex {regex:?}"))?,
+                replacement: replacement.clone(),
+            }),
+            _ => None,
+        };
+        let transform = Transform {
+            replace,
+            sanitize: args.sanitize.clone(),
+            case: args.transform,
+            normalize: args.normalize,
+            prefix: args.prefix.clone(),
+            suffix: args.suffix.clone(),
+        };
+        if let Some(path) = &args.dump_listing {
+            let mut out = fs::File::create(path)
+                .map(io::BufWriter::new)
+                .with_context(|| format!("Failed to write listing {path:?}"))?;
+            return generate_listing(
+                &input_files,
+                &format,
+                &transform,
+                args.sort_cmd.as_deref(),
+                &mut out,
+            )
+            .and_then(|_| Ok(out.flush()?))
+            .map_err(|err| {
+                write_context(err, format!("Failed to write listing {path:?}"))
+            });
+        }
+        if args.list {
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            write!(
+                out,
+                "{LISTING_HEADER} {:016x}",
+                listing_fingerprint(&input_files)
+            )?;
+            out.write_all(if format.null { b"\0" } else { b"\n" })?;
+            generate_listing(
+                &input_files,
+                &format,
+                &transform,
+                args.sort_cmd.as_deref(),
+                &mut out,
+            )?;
+            if !format.null {
+                out.write_all(b"\n")?;
+            }
+            out.flush()?;
+            return Ok(());
+        }
+        if let Some(edited) = &edited_stdin {
+            let result = check_fingerprint(edited, &input_files, &format)
+                .and_then(|_| {
+                    apply_changes(
+                        &args,
+                        &format,
+                        &input_files,
+                        edited.as_slice(),
+                        &mut session,
+                    )
+                });
+            report_session(&args, &session)?;
+            if let Err(err) = result {
+                print_error(args.error_format, &err);
+                process::exit(1);
+            }
+            return print_names(&args, &session.names);
+        }
+        let resumed = resume.is_some();
+        let file = match resume.take() {
+            Some(path) => {
+                let buffer = resume_input(&path, &input_files, &format)?;
+                write_file(args.tmpdir.as_deref(), |out| {
+                    Ok(out.write_all(buffer.as_raw_bytes())?)
+                })?
+            }
+            None => write_file(args.tmpdir.as_deref(), |out| {
+                generate_listing(
+                    &input_files,
+                    &format,
+                    &transform,
+                    args.sort_cmd.as_deref(),
+                    out,
+                )
+            })?,
+        };
+        let file_path = file.path();
+        // Transformed names can be applied without reviewing them
+        let mut review = !(args.yes && !transform.is_empty() && !resumed);
+        if let Some(replace) = &transform.replace {
+            let color = get_colors(args.color).is_some();
+            review = preview_replace(replace, &input_files, &args, color)?;
+        }
+        let changed = loop {
+            if review {
+                open_editor(&editor, file_path, paths_from_stdin)?;
+            }
+
+            let file_output = fs::File::open(file_path)
+                .map(io::BufReader::new)
+                .context("Failed to read temporary file")?;
+
+            match apply_changes(
+                &args,
+                &format,
+                &input_files,
+                file_output,
+                &mut session,
+            ) {
+                Ok(changed) => break changed,
+                Err(err) => {
+                    if let Some(conflict) =
+                        err.downcast_ref::<plan::EditConflict>()
+                    {
+                        annotate_conflict(file_path, &conflict.0, &format)?;
+                        review = true;
+                        continue;
+                    }
+                    report_session(&args, &session)?;
+                    // Print the error before the path of the kept file, which
+                    // is what the user will need to act on
+                    print_error(args.error_format, &err);
+                    keep_file(file)?;
+                    process::exit(1);
+                }
+            }
+        };
+
+        if args.keep_buffer {
+            keep_file(file)?;
+        }
+        if !args.repeat || !changed {
+            break;
+        }
+    }
+
+    report_session(&args, &session)?;
+    print_names(&args, &session.names)
+}
 
-    let input_files = list_files(path_args)?;
-    let file_input = get_input(&input_files);
+/// What the changes of the session did
+#[derive(Default)]
+struct Session {
+    /// What happened to each change, for `--report`
+    report: Vec<(plan::Operation, plan::Outcome)>,
+    /// Names of the files after the last changes, and the names they were
+    /// listed with unless they are copies, for `--print0`
+    names: Vec<(Option<PathBuf>, PathBuf)>,
+    /// The first and last names of renamed files, for `--write-map`
+    renames: Vec<(PathBuf, PathBuf)>,
+}
 
-    let file = write_file(&file_input)?;
-    let file_path = file.path();
-    open_editor(&editor, file_path)?;
+impl Session {
+    /// Adds the renames of the last changes. Files that were renamed by
+    /// earlier changes keep their first name
+    fn add_renames(&mut self) {
+        let renamed = self
+            .names
+            .iter()
+            .filter_map(|(listed, name)| {
+                let listed =
+                    listed.as_ref().filter(|listed| *listed != name)?;
+                let earlier =
+                    self.renames.iter().position(|(_, new)| new == listed);
+                Some((earlier, listed.clone(), name.clone()))
+            })
+            .collect::<Vec<_>>();
+        for (earlier, listed, name) in renamed {
+            match earlier {
+                Some(i) => self.renames[i].1 = name,
+                None => self.renames.push((listed, name)),
+            }
+        }
+        self.renames.retain(|(old, new)| old != new);
+    }
+}
 
-    let file_output = read_file(file_path)?;
+/// Returns the names of the listed files after the applied operations, in the
+/// order of the listing with copies after the files they were copied from
+fn surviving_names<'a>(
+    input_files: &[InputRow],
+    applied: impl Iterator<Item = &'a plan::Operation>,
+) -> Vec<(Option<PathBuf>, PathBuf)> {
+    // The files in a directory are moved, copied or removed with it
+    let renamed = |name: &Path, from: &Path, to: &Path| {
+        let rest = name.strip_prefix(from).ok()?;
+        Some(match rest.as_os_str().is_empty() {
+            true => to.to_owned(),
+            false => to.join(rest),
+        })
+    };
+    // Removed files are `None`. The names are looked up in `index` instead of
+    // going through all of them for each operation, since paths are sorted by
+    // component and a directory is followed by the files in it
+    let mut names = input_files
+        .iter()
+        .map(|file| Some((Some(file.name.clone()), file.name.clone())))
+        .collect::<Vec<_>>();
+    let mut order = (0..names.len()).collect::<Vec<_>>();
+    let mut index = BTreeMap::<PathBuf, Vec<usize>>::new();
+    for (i, file) in input_files.iter().enumerate() {
+        index.entry(file.name.clone()).or_default().push(i);
+    }
+    let take = |index: &mut BTreeMap<PathBuf, Vec<usize>>, dir: &Path| {
+        let inside = index
+            .range::<Path, _>((Bound::Included(dir), Bound::Unbounded))
+            .take_while(|(name, _)| name.starts_with(dir))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        inside
+            .into_iter()
+            .map(|name| {
+                let ids = index.remove(&name).unwrap_or_default();
+                (name, ids)
+            })
+            .collect::<Vec<_>>()
+    };
+    let rename = |names: &mut [Option<(Option<PathBuf>, PathBuf)>],
+                  index: &mut BTreeMap<PathBuf, Vec<usize>>,
+                  moved: Vec<(PathBuf, Vec<usize>)>,
+                  from: &Path,
+                  to: &Path| {
+        for (name, ids) in moved {
+            let new_name = renamed(&name, from, to).unwrap_or(name);
+            for &id in &ids {
+                if let Some((_, name)) = &mut names[id] {
+                    name.clone_from(&new_name);
+                }
+            }
+            index.entry(new_name).or_default().extend(ids);
+        }
+    };
+    for operation in applied {
+        match operation {
+            plan::Operation::Move { from, to, .. } => {
+                let moved = take(&mut index, from);
+                rename(&mut names, &mut index, moved, from, to);
+            }
+            plan::Operation::Swap { a, b, .. } => {
+                let (moved_a, moved_b) =
+                    (take(&mut index, a), take(&mut index, b));
+                rename(&mut names, &mut index, moved_a, a, b);
+                rename(&mut names, &mut index, moved_b, b, a);
+            }
+            plan::Operation::Remove { path, .. } => {
+                for (_, ids) in take(&mut index, path) {
+                    for id in ids {
+                        names[id] = None;
+                    }
+                }
+            }
+            plan::Operation::Copy { from, to, .. } => {
+                let is_copied = |id: &usize| {
+                    names[*id]
+                        .as_ref()
+                        .is_some_and(|(_, name)| name.starts_with(from))
+                };
+                // Copies follow the listing order, which isn't indexed
+                let copies =
+                    order.iter().copied().filter(is_copied).collect::<Vec<_>>();
+                let Some(last) = order.iter().rposition(is_copied) else {
+                    continue;
+                };
+                let ids = names.len()..names.len() + copies.len();
+                for (id, copied) in ids.clone().zip(copies) {
+                    let (_, name) = names[copied].as_ref().unwrap();
+                    let copy = renamed(name, from, to).unwrap();
+                    index.entry(copy.clone()).or_default().push(id);
+                    names.push(Some((None, copy)));
+                }
+                order.splice(last + 1..last + 1, ids);
+            }
+            plan::Operation::Chown { .. }
+            | plan::Operation::Touch { .. }
+            | plan::Operation::CreateDir { .. } => {}
+        }
+    }
+    order
+        .into_iter()
+        .filter_map(|id| names[id].take())
+        .collect()
+}
 
-    let output_files = parse_files(file_output)?;
-    update_files(&input_files, &output_files, args.verbose)?;
+/// Prints the names of the files after the changes, with `--print0`
+fn print_names(
+    args: &Args,
+    names: &[(Option<PathBuf>, PathBuf)],
+) -> Result<()> {
+    if !args.print0 {
+        return Ok(());
+    }
+    let mut out = io::stdout().lock();
+    names
+        .iter()
+        .try_for_each(|(_, name)| {
+            out.write_all(RawOsStr::new(name.as_os_str()).as_raw_bytes())?;
+            out.write_all(b"\0")
+        })
+        .and_then(|()| out.flush())
+        .context("Failed to write names")
+}
 
+/// Prints the report of the changes of the session with `--report`, and
+/// writes the renames with `--write-map`
+fn report_session(args: &Args, session: &Session) -> Result<()> {
+    if let Some(format) = args.report {
+        report::write_report(&session.report, format, &mut io::stdout().lock())
+            .context("Failed to write report")?;
+    }
+    if let Some(path) = &args.write_map {
+        map::write_map(path, &session.renames)
+            .with_context(|| format!("Failed to write map {path:?}"))?;
+    }
     Ok(())
 }
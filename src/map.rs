@@ -0,0 +1,124 @@
+//! Files with the old and new names of renamed files, which are written so
+//! that other programs can follow the renames, and read to rename files by
+//! names made elsewhere. A map is either lines with the old and the new name
+//! separated by a tab, escaped like in the temporary file, or a JSON array of
+//! objects with `old` and `new` if the file name ends with `.json`
+
+use anyhow::{anyhow, Context, Result};
+use os_str_bytes::{RawOsStr, RawOsString};
+use rsdir::{InputRow, OutputRow};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub fn write_map(path: &Path, renames: &[(PathBuf, PathBuf)]) -> Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    if is_json(path) {
+        let renames = renames
+            .iter()
+            .map(|(old, new)| {
+                Ok(json!({ "old": unicode_name(old)?, "new": unicode_name(new)? }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        writeln!(out, "{}", Value::Array(renames))?;
+    } else {
+        for (old, new) in renames {
+            out.write_all(escaped(old).as_raw_bytes())?;
+            out.write_all(b"\t")?;
+            out.write_all(escaped(new).as_raw_bytes())?;
+            out.write_all(b"\n")?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    old: PathBuf,
+    new: PathBuf,
+}
+
+/// Reads a map and returns the files with their old names, as if they were
+/// listed, and rows with their new names. Files that are in the map more than
+/// once share an index
+pub fn read_map(path: &Path) -> Result<(Vec<InputRow>, Vec<OutputRow>)> {
+    let content = fs::read(path)
+        .with_context(|| format!("Couldn't read map {path:?}"))?;
+    let renames = if is_json(path) {
+        serde_json::from_slice::<Vec<Rename>>(&content)
+            .with_context(|| format!("Invalid map {path:?}"))?
+    } else {
+        RawOsString::assert_from_raw_vec(content)
+            .split('\n')
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                let (old, new) = line.split_once('\t').ok_or_else(|| {
+                    anyhow!("Missing tab between the names on line {}", i + 1)
+                })?;
+                Ok(Rename {
+                    old: unescaped(old).with_context(|| {
+                        format!("Invalid name on line {}", i + 1)
+                    })?,
+                    new: unescaped(new).with_context(|| {
+                        format!("Invalid name on line {}", i + 1)
+                    })?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let mut input = Vec::<InputRow>::new();
+    let mut indexes = HashMap::new();
+    let mut output = Vec::with_capacity(renames.len());
+    for Rename { old, new } in renames {
+        let index = match indexes.get(&old) {
+            Some(&index) => index,
+            None => {
+                let metadata = fs::symlink_metadata(&old)
+                    .with_context(|| format!("Couldn't read {old:?}"))?;
+                let index = input.len() + 1;
+                indexes.insert(old.clone(), index);
+                input.push(InputRow {
+                    index,
+                    section: 0,
+                    name: old,
+                    is_dir: metadata.is_dir(),
+                    metadata: Some(metadata),
+                    ino: None,
+                });
+                index
+            }
+        };
+        output.push(OutputRow {
+            index,
+            name: new,
+            cells: Vec::new(),
+        });
+    }
+    Ok((input, output))
+}
+
+fn unescaped(name: &RawOsStr) -> Result<PathBuf> {
+    Ok(PathBuf::from(crate::unescape(name)?.into_os_string()))
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+fn escaped(name: &Path) -> RawOsString {
+    rsdir::escape(RawOsStr::new(name.as_os_str()).as_ref())
+}
+
+/// JSON can only contain valid Unicode
+fn unicode_name(name: &Path) -> Result<&str> {
+    name.to_str().ok_or_else(|| {
+        anyhow!("{name:?} isn't valid Unicode. Write the map as lines instead")
+    })
+}
@@ -0,0 +1,89 @@
+//! Numbering of names by the order of their rows, so that files like chapters
+//! or tracks can be ordered by moving their rows around. The format is a
+//! prefix in which `#` stands for the digits of the number, e.g. `##-` gives
+//! `01-`, `02-` and so on. A prefix in the same format is replaced
+
+use os_str_bytes::{RawOsStr, RawOsString};
+use rsdir::OutputRow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct Numbering {
+    /// Text before the number
+    before: String,
+    /// Number of digits, padded with zeros
+    width: usize,
+    /// Text after the number
+    after: String,
+}
+
+impl FromStr for Numbering {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Numbering, String> {
+        let Some(start) = format.find('#') else {
+            return Err("Use # for the digits of the number".into());
+        };
+        let end = format[start..]
+            .find(|c| c != '#')
+            .map_or(format.len(), |len| start + len);
+        if format[end..].contains('#') {
+            return Err("The digits of the number must be together".into());
+        }
+        Ok(Numbering {
+            before: format[..start].to_owned(),
+            width: end - start,
+            after: format[end..].to_owned(),
+        })
+    }
+}
+
+impl Numbering {
+    /// Numbers the names in the order of the rows, counting separately in each
+    /// directory
+    pub fn number_rows(&self, rows: &mut [OutputRow]) {
+        let mut counts = HashMap::<PathBuf, usize>::new();
+        for row in rows {
+            let parent = row.name.parent().unwrap_or(Path::new("")).to_owned();
+            let count = counts.entry(parent).or_default();
+            *count += 1;
+            row.name = self.number(&row.name, *count);
+        }
+    }
+
+    fn number(&self, path: &Path, n: usize) -> PathBuf {
+        let Some(name) = path.file_name() else {
+            return path.to_owned();
+        };
+        let name = RawOsStr::new(name);
+        let name = name.as_raw_bytes();
+        let rest = &name[self.prefix_len(name).unwrap_or(0)..];
+
+        let mut numbered = format!(
+            "{}{n:0width$}{}",
+            self.before,
+            self.after,
+            width = self.width
+        )
+        .into_bytes();
+        numbered.extend_from_slice(rest);
+        // The number and the format are valid strings, and the rest of the
+        // name is split at the end of them
+        path.with_file_name(
+            RawOsString::assert_from_raw_vec(numbered).into_os_string(),
+        )
+    }
+
+    /// Returns the length of the prefix of a name that was numbered before
+    fn prefix_len(&self, name: &[u8]) -> Option<usize> {
+        let rest = name.strip_prefix(self.before.as_bytes())?;
+        let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        rest[digits..].strip_prefix(self.after.as_bytes())?;
+        Some(self.before.len() + digits + self.after.len())
+    }
+}
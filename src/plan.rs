@@ -0,0 +1,2745 @@
+//! Turning the edited text into a list of operations, which can be previewed
+//! before any of them are applied
+
+use crate::columns::{self, Column};
+use crate::lscolors::LsColors;
+use crate::sandbox::{self, Sandbox};
+use crate::{brace, escape, vars};
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use clap::ValueEnum;
+use filetime::FileTime;
+use os_str_bytes::RawOsStr;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, fmt, fs};
+use unicode_normalization::UnicodeNormalization;
+
+const LOG_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%:z";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+const REVERSE: &str = "\x1b[7m";
+const NO_REVERSE: &str = "\x1b[27m";
+
+/// A listed file
+#[derive(Debug)]
+pub struct InputRow {
+    /// Number that identifies the file in the edited text
+    pub index: usize,
+    /// Index of the directory argument the file was listed from
+    pub section: usize,
+    pub name: PathBuf,
+    pub is_dir: bool,
+    /// Read when the file is listed, if a column or sort needs it. Compared
+    /// with the file again before it is changed, to notice that it changed
+    /// after it was listed
+    pub metadata: Option<fs::Metadata>,
+    /// Inode from the directory entry, which identifies the file when its
+    /// metadata wasn't read. Files with neither are read when the plan is
+    /// computed
+    pub ino: Option<u64>,
+}
+
+/// A row of the edited text
+#[derive(Debug)]
+pub struct OutputRow {
+    /// Index of the listed file that the row is for
+    pub index: usize,
+    /// The new name of the file
+    pub name: PathBuf,
+    /// Text of each column, in the order of the columns that were listed
+    pub cells: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        is_dir: bool,
+    },
+    /// Exchange the names of two files that were renamed to each other
+    Swap {
+        a: PathBuf,
+        b: PathBuf,
+        a_is_dir: bool,
+        b_is_dir: bool,
+    },
+    Remove {
+        path: PathBuf,
+        is_dir: bool,
+    },
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+        is_dir: bool,
+    },
+    /// Change the owner and group, from the `old` to the `new` text of the
+    /// ownership column
+    Chown {
+        path: PathBuf,
+        is_dir: bool,
+        old: String,
+        new: String,
+        uid: u32,
+        gid: u32,
+    },
+    /// Change the modification time, from the `old` to the `new` text of the
+    /// timestamp column
+    Touch {
+        path: PathBuf,
+        is_dir: bool,
+        old: String,
+        new: String,
+        mtime: SystemTime,
+    },
+    /// Create a directory that doesn't exist yet, for a new name inside it
+    CreateDir {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Move { from, to, is_dir } => {
+                write!(f, "Move {} {:?} to {:?}", kind(*is_dir), from, to)
+            }
+            Operation::Swap {
+                a,
+                b,
+                a_is_dir,
+                b_is_dir,
+            } => write!(
+                f,
+                "Swap {} {:?} with {} {:?}",
+                kind(*a_is_dir),
+                a,
+                kind(*b_is_dir),
+                b
+            ),
+            Operation::Remove { path, is_dir } => {
+                write!(f, "Remove {} {:?}", kind(*is_dir), path)
+            }
+            Operation::Copy { from, to, is_dir } => {
+                write!(f, "Copy {} {:?} to {:?}", kind(*is_dir), from, to)
+            }
+            Operation::Chown {
+                path, is_dir, new, ..
+            } => {
+                write!(
+                    f,
+                    "Change owner of {} {:?} to {new}",
+                    kind(*is_dir),
+                    path
+                )
+            }
+            Operation::Touch {
+                path, is_dir, new, ..
+            } => write!(
+                f,
+                "Change modification time of {} {:?} to {new}",
+                kind(*is_dir),
+                path
+            ),
+            Operation::CreateDir { path } => {
+                write!(f, "Create directory {path:?}")
+            }
+        }
+    }
+}
+
+fn kind(is_dir: bool) -> &'static str {
+    if is_dir {
+        "directory"
+    } else {
+        "file"
+    }
+}
+
+/// Describes sockets, FIFOs and device nodes, which are listed like files but
+/// can't be copied like them
+#[cfg(unix)]
+fn special_kind(file_type: &fs::FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_fifo() {
+        Some("FIFO")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind(_file_type: &fs::FileType) -> Option<&'static str> {
+    None
+}
+
+/// Reads the type of a file that may be special. Symlinks aren't followed
+fn special_kind_of(path: &Path) -> Option<&'static str> {
+    fs::symlink_metadata(path)
+        .ok()
+        .and_then(|metadata| special_kind(&metadata.file_type()))
+}
+
+/// Fails if a directory that is to be removed is a mount point or contains
+/// one, since removing it would also remove the files of the mounted file
+/// system
+fn check_mount_points(dir: &Path) -> Result<()> {
+    let Some(mount_point) = find_mount_point(dir) else {
+        return Ok(());
+    };
+    if mount_point == dir {
+        bail!(
+            "Directory {dir:?} is a mount point. Use --allow-mountpoint to \
+             remove it with the files of the file system mounted there"
+        );
+    }
+    bail!(
+        "Directory {dir:?} contains mount point {mount_point:?}. Use \
+         --allow-mountpoint to remove it with the files of the file system \
+         mounted there"
+    );
+}
+
+/// Returns the directory itself, or the first directory inside it, that is on
+/// another file system than the directory it is in
+#[cfg(unix)]
+fn find_mount_point(dir: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let parent = match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let device = fs::symlink_metadata(parent).ok()?.dev();
+    find_other_device(dir, device)
+}
+
+#[cfg(unix)]
+fn find_other_device(path: &Path, device: u64) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return None;
+    }
+    if metadata.dev() != device {
+        return Some(path.to_owned());
+    }
+    fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| find_other_device(&entry.path(), device))
+}
+
+#[cfg(not(unix))]
+fn find_mount_point(_dir: &Path) -> Option<PathBuf> {
+    None
+}
+
+pub fn check_indexes(input: &[InputRow], output: &[OutputRow]) -> Result<()> {
+    let input_idxs: HashSet<_> = input.iter().map(|row| row.index).collect();
+    output.iter().enumerate().try_for_each(|(i, output_row)| {
+        if !input_idxs.contains(&output_row.index) {
+            Err(PlanError::row(
+                i,
+                Some(&output_row.name),
+                format!("Unknown index {} at row {i}", output_row.index),
+            )
+            .into())
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Checks that an index isn't on several rows with different names, since
+/// only one of them could be used without copying
+fn check_duplicates(output: &[OutputRow]) -> Result<()> {
+    let mut first_rows = HashMap::new();
+    for (i, row) in output.iter().enumerate() {
+        let first = *first_rows.entry(row.index).or_insert(i);
+        if output[first].name != row.name {
+            return Err(PlanError::row(
+                i,
+                Some(&row.name),
+                format!(
+                    "Index {} is on rows {first} and {i} with different \
+                     names. Use --copy to copy the file",
+                    row.index
+                ),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// The longest file name, in bytes on Unix and UTF-16 units on Windows
+const NAME_MAX: usize = 255;
+/// The longest path, without the terminating NUL
+#[cfg(unix)]
+const PATH_MAX: usize = libc::PATH_MAX as usize - 1;
+/// The longest absolute path, unless it starts with `\\?\`
+#[cfg(windows)]
+const PATH_MAX: usize = 259;
+#[cfg(not(any(unix, windows)))]
+const PATH_MAX: usize = usize::MAX;
+
+/// Checks that no new name is longer than file systems allow, so that it
+/// isn't found out halfway through the changes
+fn check_lengths(output: &[OutputRow]) -> Result<()> {
+    let units = if cfg!(windows) { "characters" } else { "bytes" };
+    for (i, row) in output.iter().enumerate() {
+        if let Some(name) = row
+            .name
+            .components()
+            .map(|component| component.as_os_str())
+            .find(|name| name_len(name) > NAME_MAX)
+        {
+            return Err(PlanError::row(
+                i,
+                Some(&row.name),
+                format!(
+                    "The name {name:?} at row {i} is longer than the \
+                     {NAME_MAX} {units} that names can have"
+                ),
+            )
+            .into());
+        }
+        if path_len(&row.name) > PATH_MAX {
+            return Err(PlanError::row(
+                i,
+                Some(&row.name),
+                format!(
+                    "{:?} at row {i} is longer than the {PATH_MAX} {units} \
+                     that paths can have",
+                    row.name
+                ),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn name_len(name: &OsStr) -> usize {
+    use std::os::windows::ffi::OsStrExt;
+
+    name.encode_wide().count()
+}
+
+#[cfg(not(windows))]
+fn name_len(name: &OsStr) -> usize {
+    name.len()
+}
+
+/// Windows limits the absolute path, except for extended paths
+#[cfg(windows)]
+fn path_len(path: &Path) -> usize {
+    match std::path::absolute(path) {
+        Ok(path)
+            if !path.as_os_str().to_string_lossy().starts_with(r"\\?\") =>
+        {
+            name_len(path.as_os_str())
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(not(windows))]
+fn path_len(path: &Path) -> usize {
+    path.as_os_str().len()
+}
+
+/// Names of devices on Windows, which can't be used as file names with any
+/// extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
+    "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that the new names can be used on Windows. Files with such names
+/// can be made with some APIs, but are then hard to open or remove
+fn check_portable(output: &[OutputRow]) -> Result<()> {
+    for (i, row) in output.iter().enumerate() {
+        for component in row.name.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            let name = name.to_string_lossy();
+            let stem = name.split('.').next().unwrap_or_default();
+            if RESERVED_NAMES
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end()))
+            {
+                return Err(PlanError::row(
+                    i,
+                    Some(&row.name),
+                    format!(
+                        "The name {name:?} at row {i} is reserved for a \
+                         device on Windows"
+                    ),
+                )
+                .into());
+            }
+            if name.ends_with(['.', ' ']) {
+                return Err(PlanError::row(
+                    i,
+                    Some(&row.name),
+                    format!(
+                        "The name {name:?} at row {i} ends with a dot or \
+                         space, which Windows removes"
+                    ),
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Backup<'a> {
+    /// Add a suffix, like `name~`
+    Suffix(&'a str),
+    /// Add the first free number, like `name.~1~`
+    Numbered,
+}
+
+/// Whether files are removed before or after the other files are moved
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    /// Remove files first
+    DeletesFirst,
+    /// Move and copy files first. Moving a file to the name of a removed file
+    /// is then a conflict
+    RenamesFirst,
+}
+
+/// What to do about a new name that is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Fail,
+    /// Leave the file as it is
+    Skip,
+    /// Remove the existing file first. Not possible when several files get
+    /// the same name
+    Overwrite,
+    /// Add a suffix like ` (1)` to the new name
+    AutoSuffix,
+    /// Edit the names again
+    Edit,
+}
+
+/// A conflict that is to be fixed by editing the names again
+#[derive(Debug)]
+pub struct EditConflict(pub String);
+
+impl fmt::Display for EditConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for EditConflict {}
+
+/// An error with the details that tools need to present it, such as the row
+/// and the names it is about. It shows only its message, so that the error
+/// reads the same without the details
+#[derive(Debug)]
+pub struct PlanError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// The row of the edited buffer, counted from 0 like in the message
+    pub row: Option<usize>,
+    /// The listed name of the file
+    pub path: Option<PathBuf>,
+    /// The new name of the file
+    pub target: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A row of the edited buffer is invalid
+    InvalidRow,
+    /// A new name is already taken, or given to several files
+    Conflict,
+    /// Making a change failed
+    Apply,
+}
+
+impl PlanError {
+    pub fn row(row: usize, target: Option<&Path>, message: String) -> Self {
+        PlanError {
+            kind: ErrorKind::InvalidRow,
+            message,
+            row: Some(row),
+            path: None,
+            target: target.map(Path::to_path_buf),
+        }
+    }
+
+    pub fn conflict(path: &Path, target: &Path, message: String) -> Self {
+        PlanError {
+            kind: ErrorKind::Conflict,
+            message,
+            row: None,
+            path: Some(path.to_path_buf()),
+            target: Some(target.to_path_buf()),
+        }
+    }
+
+    pub fn apply(path: &Path, target: Option<&Path>, message: String) -> Self {
+        PlanError {
+            kind: ErrorKind::Apply,
+            message,
+            row: None,
+            path: Some(path.to_path_buf()),
+            target: target.map(Path::to_path_buf),
+        }
+    }
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+/// Settings that affect which operations are planned
+#[derive(Debug, Default)]
+pub struct PlanOptions<'a> {
+    /// Columns of the buffer, of which the editable ones are compared
+    pub columns: &'a [Column],
+    /// Whether an index on several rows, or a name with braces, creates
+    /// copies of the file
+    pub copy: bool,
+    /// Whether environment variables in changed names are expanded
+    pub expand_env: bool,
+    /// Whether names that only differ in Unicode normalization are changed.
+    /// Otherwise such differences are ignored
+    pub normalize: bool,
+    /// Whether names that already exist get a suffix like ` (1)` instead of
+    /// failing
+    pub auto_suffix: bool,
+    /// How files are renamed when a new name replaces them. Without it,
+    /// replacing a file fails
+    pub backup: Option<Backup<'a>>,
+    /// Canonical paths of the listed directories. Only needed with
+    /// `restrict` or without `allow_parent`
+    pub roots: &'a [PathBuf],
+    /// Whether new names must be inside the listed directories
+    pub restrict: bool,
+    /// Whether new names may go above the listed directories with `..`
+    pub allow_parent: bool,
+    /// Whether removing block and character devices, and directories that
+    /// still contain files, is allowed
+    pub force: bool,
+    /// Whether directories can be removed together with the files in them
+    pub recursive_delete: bool,
+    /// Whether the missing directories of new names are created
+    pub parents: bool,
+    /// Whether directories that are or contain mount points can be removed
+    pub allow_mount_points: bool,
+    /// Whether new names that Windows can't use are rejected, which they
+    /// always are on Windows
+    pub portable_names: bool,
+    /// Whether files are removed first or last. Otherwise the order of the
+    /// listing is kept
+    pub order: Option<Order>,
+    /// Whether notes about how the rows were compared are collected in
+    /// [`Plan::notes`]
+    pub notes: bool,
+}
+
+/// Compares the listed files with the edited rows and returns the operations
+/// needed to make the file system match them, in the order of the listing
+/// unless `order` puts the removals first or last
+/// `resolve` is called with a description of each new name that is already
+/// taken, and whether the existing file can be overwritten
+pub fn compute_plan(
+    input: &[InputRow],
+    output: &[OutputRow],
+    options: &PlanOptions,
+    resolve: impl FnMut(&str, bool) -> Result<Resolution>,
+) -> Result<Plan> {
+    check_indexes(input, output)?;
+    let mut notes = Vec::new();
+    if !options.copy {
+        check_duplicates(output)?;
+    }
+    check_lengths(output)?;
+    if options.portable_names || cfg!(windows) {
+        check_portable(output)?;
+    }
+    if options.notes {
+        notes.push(format!(
+            "Checked {} rows against {} files",
+            output.len(),
+            input.len()
+        ));
+    }
+
+    let mut output_hash = HashMap::<_, Vec<_>>::new();
+    for row in output {
+        output_hash.entry(row.index).or_default().push(row);
+    }
+    let new_names = input
+        .iter()
+        .map(|row| {
+            let rows =
+                output_hash.get(&row.index).map_or(&[][..], Vec::as_slice);
+            (
+                row.name.as_path(),
+                rows.iter().map(|row| row.name.as_path()).collect(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    // When listing recursively, files inside a removed directory are removed
+    // together with it
+    let mut removed_dirs = Vec::<&Path>::new();
+    let mut plan = Vec::new();
+
+    for input_row in input {
+        let Some(output_rows) = output_hash.get(&input_row.index) else {
+            if removed_dirs
+                .iter()
+                .any(|dir| input_row.name.starts_with(dir))
+            {
+                if options.notes {
+                    notes.push(format!(
+                        "Removing {} {:?} together with its directory",
+                        kind(input_row.is_dir),
+                        input_row.name
+                    ));
+                }
+                continue;
+            }
+            if input_row.is_dir {
+                removed_dirs.push(&input_row.name);
+                if !options.allow_mount_points {
+                    check_mount_points(&input_row.name)?;
+                }
+                let remaining = count_remaining(&input_row.name, &new_names);
+                if remaining > 0 && !options.recursive_delete && !options.force
+                {
+                    bail!(
+                        "Directory {:?} still contains {remaining} files. Use \
+                         --recursive-delete to remove it with everything in it",
+                        input_row.name
+                    );
+                }
+            }
+            if let Some(kind) = special_kind_of(&input_row.name)
+                .filter(|kind| kind.ends_with("device") && !options.force)
+            {
+                bail!(
+                    "Can't remove {kind} {:?} without --force",
+                    input_row.name
+                );
+            }
+            plan.push(Operation::Remove {
+                path: input_row.name.clone(),
+                is_dir: input_row.is_dir,
+            });
+            continue;
+        };
+
+        let (output_row, targets) = if options.copy {
+            (output_rows[0], copy_targets(output_rows)?)
+        } else {
+            // Without copying, all rows with the index have the same name
+            let output_row = output_rows[0];
+            (output_row, vec![output_row.name.clone()])
+        };
+        let mut targets = if options.expand_env {
+            expand_vars(targets, &input_row.name)?
+        } else {
+            targets
+        };
+        // File systems like HFS+ change the normalization of names, so such
+        // differences are usually not meant as changes
+        if !options.normalize {
+            for target in &mut targets {
+                if *target != input_row.name
+                    && same_normalized(target, &input_row.name)
+                {
+                    if options.notes {
+                        notes.push(format!(
+                            "Ignoring change in Unicode normalization of {:?}",
+                            input_row.name
+                        ));
+                    }
+                    *target = input_row.name.clone();
+                }
+            }
+        }
+        // The file is moved to the first target, unless it is also kept under
+        // its current name, and copied to the others
+        let new_name = if targets.contains(&input_row.name) {
+            &input_row.name
+        } else {
+            &targets[0]
+        };
+
+        // Columns are changed and copies made before moving, while the file
+        // has its old name
+        let plan_len = plan.len();
+        plan.extend(column_changes(input_row, output_row, options.columns)?);
+        if targets.len() > 1 {
+            // Reading a FIFO or device could block or never end
+            if let Some(kind) = special_kind_of(&input_row.name) {
+                bail!("Can't copy {kind} {:?}", input_row.name);
+            }
+        }
+        for target in targets.iter().filter(|target| *target != new_name) {
+            plan.push(Operation::Copy {
+                from: input_row.name.clone(),
+                to: target.clone(),
+                is_dir: input_row.is_dir,
+            });
+        }
+        if *new_name != input_row.name {
+            plan.push(Operation::Move {
+                from: input_row.name.clone(),
+                to: new_name.clone(),
+                is_dir: input_row.is_dir,
+            });
+        }
+        if plan.len() == plan_len && options.notes {
+            notes.push(format!(
+                "Unchanged {} {:?}",
+                kind(input_row.is_dir),
+                input_row.name
+            ));
+        }
+    }
+
+    let mut plan =
+        check_conflicts(order_removals(plan, options.order), options, resolve)?;
+    if options.restrict || !options.allow_parent {
+        check_roots(&plan, options)?;
+    }
+    if options.parents {
+        plan = create_parents(plan);
+    }
+    let operations = match options.order {
+        None | Some(Order::DeletesFirst) => order_moves(plan),
+        Some(Order::RenamesFirst) => {
+            // Removals that resolve conflicts come before their moves, so the
+            // last removals are the files that were removed from the listing
+            let start = plan
+                .iter()
+                .rposition(|operation| {
+                    !matches!(operation, Operation::Remove { .. })
+                })
+                .map_or(0, |i| i + 1);
+            let mut removals = plan.split_off(start);
+            let mut ordered = order_moves(plan);
+            // A file that was overwritten is already removed
+            removals.retain(|removal| {
+                !ordered.iter().any(|operation| {
+                    matches!(
+                        (operation, removal),
+                        (
+                            Operation::Remove { path, .. },
+                            Operation::Remove { path: removed, .. },
+                        ) if path == removed
+                    )
+                })
+            });
+            ordered.append(&mut removals);
+            ordered
+        }
+    };
+    let changed = operations.iter().flat_map(sources).collect::<HashSet<_>>();
+    let stamps = input
+        .iter()
+        .filter_map(|row| {
+            let stamp = match (&row.metadata, row.ino) {
+                (Some(metadata), _) => Stamp::of(metadata),
+                (None, Some(ino))
+                    if row.is_dir && changed.contains(row.name.as_path()) =>
+                {
+                    dir_stamp(&row.name, ino)
+                }
+                (None, Some(ino)) => Stamp {
+                    dev: None,
+                    ino,
+                    mtime: None,
+                },
+                // Only the files that are changed are read, and changes to
+                // them are noticed from now on
+                (None, None) if changed.contains(row.name.as_path()) => {
+                    Stamp::of(&fs::symlink_metadata(&row.name).ok()?)
+                }
+                (None, None) => return None,
+            };
+            Some((row.name.clone(), stamp))
+        })
+        .collect();
+    Ok(Plan {
+        operations,
+        stamps,
+        notes,
+    })
+}
+
+/// Counts the files in a directory, at any depth, that stay in it. Listed
+/// files leave it if they are removed or all their new names are outside of it
+fn count_remaining(
+    dir: &Path,
+    new_names: &HashMap<&Path, Vec<&Path>>,
+) -> usize {
+    count_staying(dir, dir, new_names)
+}
+
+fn count_staying(
+    removed_dir: &Path,
+    dir: &Path,
+    new_names: &HashMap<&Path, Vec<&Path>>,
+) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let path = dir.join(entry.file_name());
+            let names = new_names.get(path.as_path());
+            let stays = names.is_none_or(|names| {
+                names.iter().any(|name| name.starts_with(removed_dir))
+            });
+            // The files in a directory that is moved out go with it
+            let moved_out =
+                !stays && names.is_some_and(|names| !names.is_empty());
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            usize::from(stays)
+                + if is_dir && !moved_out {
+                    count_staying(removed_dir, &path, new_names)
+                } else {
+                    0
+                }
+        })
+        .sum()
+}
+
+/// Returns the names of all rows of a file, with braces expanded
+fn copy_targets(output_rows: &[&OutputRow]) -> Result<Vec<PathBuf>> {
+    let mut targets = Vec::new();
+    for row in output_rows {
+        let expanded =
+            brace::expand(RawOsStr::new(row.name.as_os_str()).as_ref())
+                .with_context(|| format!("Couldn't expand {:?}", row.name))?;
+        targets.extend(
+            expanded
+                .into_iter()
+                .map(|name| PathBuf::from(name.into_os_string())),
+        );
+    }
+    Ok(targets)
+}
+
+/// Expands environment variables in the names that differ from the current
+/// name, which may contain a `$` that isn't meant as a variable
+fn expand_vars(targets: Vec<PathBuf>, name: &Path) -> Result<Vec<PathBuf>> {
+    targets
+        .into_iter()
+        .map(|target| {
+            if target == name {
+                return Ok(target);
+            }
+            let expanded =
+                vars::expand(RawOsStr::new(target.as_os_str()).as_ref())
+                    .with_context(|| {
+                        format!("Couldn't expand variables in {target:?}")
+                    })?;
+            Ok(PathBuf::from(expanded.into_os_string()))
+        })
+        .collect()
+}
+
+/// Adds the directories that new names are in and that don't exist yet,
+/// before the other operations and with parents before their children.
+/// Directories that a file is moved or copied to are left to that operation
+fn create_parents(plan: Vec<Operation>) -> Vec<Operation> {
+    let arriving = plan
+        .iter()
+        .filter_map(|operation| match operation {
+            Operation::Move { to, .. } | Operation::Copy { to, .. } => {
+                Some(to.as_path())
+            }
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let mut dirs = BTreeSet::new();
+    for to in &arriving {
+        for dir in to.ancestors().skip(1) {
+            if dir.as_os_str().is_empty()
+                || arriving.contains(dir)
+                || fs::symlink_metadata(dir).is_ok()
+            {
+                break;
+            }
+            dirs.insert(dir.to_owned());
+        }
+    }
+    dirs.into_iter()
+        .map(|path| Operation::CreateDir { path })
+        .chain(plan)
+        .collect()
+}
+
+/// Moves the removals before or after the other operations, keeping the order
+/// of the listing otherwise
+fn order_removals(
+    plan: Vec<Operation>,
+    order: Option<Order>,
+) -> Vec<Operation> {
+    let Some(order) = order else {
+        return plan;
+    };
+    let (removals, others): (Vec<_>, Vec<_>) = plan
+        .into_iter()
+        .partition(|operation| matches!(operation, Operation::Remove { .. }));
+    match order {
+        Order::DeletesFirst => removals.into_iter().chain(others).collect(),
+        Order::RenamesFirst => others.into_iter().chain(removals).collect(),
+    }
+}
+
+/// Checks that no two operations create the same name, and that no
+/// operation replaces an existing file that isn't moved or removed itself.
+/// With `auto_suffix`, conflicting names get a suffix like ` (1)` instead, and
+/// with `backup` the existing file is first renamed with the backup suffix.
+/// Otherwise `resolve` decides. In directories where names that only differ in
+/// case are the same, they conflict too
+fn check_conflicts(
+    plan: Vec<Operation>,
+    options: &PlanOptions,
+    mut resolve: impl FnMut(&str, bool) -> Result<Resolution>,
+) -> Result<Vec<Operation>> {
+    // The listed names in each directory, which are known to exist there
+    let mut listed = HashMap::<&Path, Vec<&OsStr>>::new();
+    for path in plan.iter().flat_map(sources) {
+        if let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+            listed.entry(dir).or_default().push(name);
+        }
+    }
+    let case_insensitive = plan
+        .iter()
+        .filter_map(|operation| match operation {
+            Operation::Move { to, .. } | Operation::Copy { to, .. } => {
+                to.parent()
+            }
+            _ => None,
+        })
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter(|dir| {
+            let names = listed.get(dir).map(Vec::as_slice).unwrap_or(&[]);
+            is_case_insensitive(dir, names)
+        })
+        .map(Path::to_path_buf)
+        .collect::<HashSet<_>>();
+    // The name that a path is compared by
+    let key = |path: &Path| match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) if case_insensitive.contains(dir) => {
+            match name.to_str() {
+                Some(name) => dir.join(name.to_lowercase()),
+                None => path.to_path_buf(),
+            }
+        }
+        _ => path.to_path_buf(),
+    };
+
+    let mut vacated = plan
+        .iter()
+        .filter_map(|operation| match operation {
+            Operation::Move { from, .. } => Some(key(from)),
+            Operation::Remove { path, .. }
+                if options.order != Some(Order::RenamesFirst) =>
+            {
+                Some(key(path))
+            }
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    // The names that files are moved or copied to, by their keys
+    let mut taken = HashMap::<PathBuf, PathBuf>::new();
+    let mut checked = Vec::with_capacity(plan.len());
+
+    for mut operation in plan {
+        let (from, to, is_dir, verb) = match operation {
+            Operation::Move {
+                ref from,
+                ref mut to,
+                is_dir,
+            } => (from, to, is_dir, "move"),
+            Operation::Copy {
+                ref from,
+                ref mut to,
+                is_dir,
+            } => (from, to, is_dir, "copy"),
+            _ => {
+                checked.push(operation);
+                continue;
+            }
+        };
+        if verb == "copy" && is_dir && to.starts_with(from) {
+            bail!("Can't copy directory {from:?} into itself");
+        }
+
+        let is_free = |name: &Path| {
+            let name_key = key(name);
+            !taken.contains_key(&name_key)
+                && (vacated.contains(&name_key)
+                    || fs::symlink_metadata(name).is_err()
+                    || (key(from) == name_key && same_file(from, name)))
+        };
+        if !is_free(to) {
+            let duplicate = taken.get(&key(to)).cloned();
+            let backup = options
+                .backup
+                .filter(|_| duplicate.is_none() && !options.auto_suffix);
+            if let Some(kind) = backup {
+                let backup = match kind {
+                    Backup::Suffix(suffix) => with_backup_suffix(to, suffix),
+                    Backup::Numbered => (1..)
+                        .map(|n| with_backup_suffix(to, &format!(".~{n}~")))
+                        .find(|name| is_free(name))
+                        .unwrap(),
+                };
+                if !is_free(&backup) {
+                    bail!(
+                        "Can't back up {to:?} to {backup:?}, which already \
+                         exists"
+                    );
+                }
+                checked.push(Operation::Move {
+                    from: to.clone(),
+                    to: backup.clone(),
+                    is_dir: fs::symlink_metadata(&*to)
+                        .is_ok_and(|metadata| metadata.is_dir()),
+                });
+                taken.insert(key(&backup), backup);
+            } else {
+                let message = match &duplicate {
+                    Some(other) if other == to => {
+                        format!("Several files would be named {to:?}")
+                    }
+                    Some(other) => format!(
+                        "Several files would be named {other:?} and {to:?}, \
+                         which only differ in case and are the same name there"
+                    ),
+                    None => format!(
+                        "Can't {verb} {from:?} to {to:?}, which already \
+                         exists"
+                    ),
+                };
+                let resolution = if options.auto_suffix {
+                    Resolution::AutoSuffix
+                } else {
+                    resolve(&message, duplicate.is_none())?
+                };
+                match resolution {
+                    Resolution::Skip => {
+                        if verb == "move" {
+                            if taken.contains_key(&key(from)) {
+                                bail!(
+                                    "Can't skip moving {from:?}, since another \
+                                     file is moved to its name"
+                                );
+                            }
+                            vacated.remove(&key(from));
+                        }
+                        continue;
+                    }
+                    Resolution::Overwrite if duplicate.is_none() => {
+                        if from.starts_with(&*to) {
+                            bail!("Can't overwrite {to:?}, which contains {from:?}");
+                        }
+                        checked.push(Operation::Remove {
+                            path: to.clone(),
+                            is_dir: fs::symlink_metadata(&*to)
+                                .is_ok_and(|metadata| metadata.is_dir()),
+                        });
+                    }
+                    Resolution::AutoSuffix => {
+                        *to = (1..)
+                            .map(|n| with_suffix(to, n, is_dir))
+                            .find(|name| is_free(name))
+                            .unwrap();
+                    }
+                    Resolution::Edit => {
+                        return Err(EditConflict(message).into())
+                    }
+                    Resolution::Fail | Resolution::Overwrite => {
+                        return Err(
+                            PlanError::conflict(from, to, message).into()
+                        )
+                    }
+                }
+            }
+        }
+        taken.insert(key(to), to.clone());
+        checked.push(operation);
+    }
+    Ok(checked)
+}
+
+/// Orders the operations so that nothing is moved or copied to a name before
+/// the file with that name has been moved away or removed, and no directory
+/// is removed before the files in it have been moved or copied out of it, or
+/// filled before it has been moved or copied to its name. Two
+/// files that are renamed to each other are swapped, and longer cycles of
+/// moves go through a temporary name
+fn order_moves(plan: Vec<Operation>) -> Vec<Operation> {
+    let names = plan
+        .iter()
+        .flat_map(|operation| match operation {
+            Operation::Move { from, to, .. }
+            | Operation::Copy { from, to, .. } => vec![from, to],
+            _ => Vec::new(),
+        })
+        .cloned()
+        .collect::<HashSet<_>>();
+    let mut ordering = Ordering {
+        pending: plan
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Move { from, .. } => Some(from.clone()),
+                Operation::Remove { path, .. } => Some(path.clone()),
+                _ => None,
+            })
+            .collect(),
+        arriving: plan
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Move { to, .. } | Operation::Copy { to, .. } => {
+                    Some(to.clone())
+                }
+                _ => None,
+            })
+            .collect(),
+        sources: HashMap::new(),
+        waiting: Vec::new(),
+        ordered: Vec::with_capacity(plan.len()),
+    };
+    for source in plan.iter().flat_map(sources) {
+        *ordering.sources.entry(source.to_owned()).or_default() += 1;
+    }
+
+    for operation in plan {
+        if ordering.must_wait(&operation) {
+            ordering.waiting.push(operation);
+        } else {
+            ordering.push(operation);
+        }
+    }
+
+    // Every move still waiting is part of a cycle, and removals wait for the
+    // moves out of their directories
+    while let Some(i) = ordering
+        .waiting
+        .iter()
+        .position(|operation| matches!(operation, Operation::Move { .. }))
+    {
+        let Operation::Move { from, to, is_dir } = ordering.waiting.remove(i)
+        else {
+            unreachable!();
+        };
+        let swapped = ordering.waiting.iter().position(|operation| {
+            matches!(operation, Operation::Move { from: other, to: back, .. }
+                if *other == to && *back == from)
+        });
+        if let Some(i) = swapped {
+            let Operation::Move {
+                is_dir: other_is_dir,
+                ..
+            } = ordering.waiting.remove(i)
+            else {
+                unreachable!();
+            };
+            ordering.pending.remove(&from);
+            ordering.pending.remove(&to);
+            ordering.push(Operation::Swap {
+                a: from,
+                b: to,
+                a_is_dir: is_dir,
+                b_is_dir: other_is_dir,
+            });
+            continue;
+        }
+
+        let temp = temp_name(&from, |name| {
+            !names.contains(name) && fs::symlink_metadata(name).is_err()
+        });
+        ordering.waiting.push(Operation::Move {
+            from: temp.clone(),
+            to,
+            is_dir,
+        });
+        ordering.sources.insert(temp.clone(), 1);
+        ordering.push(Operation::Move {
+            from,
+            to: temp,
+            is_dir,
+        });
+    }
+    ordering.ordered
+}
+
+struct Ordering {
+    /// Names that are still to be moved away or removed
+    pending: HashSet<PathBuf>,
+    /// Names that files are still to be moved or copied to
+    arriving: HashSet<PathBuf>,
+    /// Names that operations that aren't ordered yet act on, and how many
+    sources: HashMap<PathBuf, usize>,
+    waiting: Vec<Operation>,
+    ordered: Vec<Operation>,
+}
+
+impl Ordering {
+    fn must_wait(&self, operation: &Operation) -> bool {
+        match operation {
+            Operation::Move { to, .. } | Operation::Copy { to, .. } => {
+                self.pending.contains(to)
+                    || to
+                        .ancestors()
+                        .skip(1)
+                        .any(|dir| self.arriving.contains(dir))
+            }
+            Operation::Remove { path, is_dir: true } => self
+                .sources
+                .keys()
+                .any(|source| source != path && source.starts_with(path)),
+            _ => false,
+        }
+    }
+
+    /// Adds an operation to the ordered ones, followed by any operation that
+    /// was waiting for it
+    fn push(&mut self, operation: Operation) {
+        let mut next = VecDeque::from([operation]);
+        while let Some(operation) = next.pop_front() {
+            for source in sources(&operation) {
+                if let Some(count) = self.sources.get_mut(source) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.sources.remove(source);
+                    }
+                }
+            }
+            if let Operation::Move { from: vacated, .. }
+            | Operation::Remove { path: vacated, .. } = &operation
+            {
+                self.pending.remove(vacated);
+            }
+            match &operation {
+                Operation::Move { to, .. } | Operation::Copy { to, .. } => {
+                    self.arriving.remove(to);
+                }
+                Operation::Swap { a, b, .. } => {
+                    self.arriving.remove(a);
+                    self.arriving.remove(b);
+                }
+                _ => {}
+            }
+            self.ordered.push(operation);
+            let mut i = 0;
+            while i < self.waiting.len() {
+                if self.must_wait(&self.waiting[i]) {
+                    i += 1;
+                } else {
+                    next.push_back(self.waiting.remove(i));
+                }
+            }
+        }
+    }
+}
+
+/// Returns a name like `name.rsdir-1` next to a file, for moving it out of
+/// the way
+pub fn temp_name(path: &Path, is_free: impl Fn(&Path) -> bool) -> PathBuf {
+    (1..)
+        .map(|n| with_backup_suffix(path, &format!(".rsdir-{n}")))
+        .find(|name| is_free(name))
+        .unwrap()
+}
+
+/// Checks that every new name is inside one of the listed directories with
+/// `restrict`, and otherwise that names with `..` don't go above them unless
+/// that is allowed
+fn check_roots(plan: &[Operation], options: &PlanOptions) -> Result<()> {
+    for operation in plan {
+        let to = match operation {
+            Operation::Move { to, .. } | Operation::Copy { to, .. } => to,
+            _ => continue,
+        };
+        let has_parent = to.components().any(|c| c == Component::ParentDir);
+        // Changes are made beneath the directories on Linux, where `..` can't
+        // be followed out of them even if it leads back in
+        if options.restrict && has_parent {
+            bail!("{to:?} contains .., which isn't allowed with --restrict");
+        }
+        if !options.restrict && !has_parent {
+            continue;
+        }
+        let resolved =
+            resolve(to).with_context(|| format!("Couldn't resolve {to:?}"))?;
+        if options.roots.iter().any(|root| resolved.starts_with(root)) {
+            continue;
+        }
+        if options.restrict {
+            bail!("{to:?} is outside of the listed directories");
+        }
+        bail!(
+            "{to:?} goes above the listed directories with .., which \
+             requires --allow-parent"
+        );
+    }
+    Ok(())
+}
+
+/// Returns the canonical path of a name that may not exist yet, by resolving
+/// the closest ancestor that does. The name itself isn't resolved, so that a
+/// symlink is inside the directory it is in
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+        rest.push(name);
+        existing = parent;
+    }
+    loop {
+        if existing.as_os_str().is_empty() {
+            existing = Path::new(".");
+        }
+        match fs::canonicalize(existing) {
+            Ok(resolved) => {
+                return Ok(rest
+                    .iter()
+                    .rev()
+                    .fold(resolved, |path, name| path.join(name)));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => bail!("{existing:?} doesn't exist"),
+        }
+    }
+}
+
+/// Adds the backup suffix to the end of a name, like `name~`
+fn with_backup_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Adds ` (n)` to a name, before the extension of files
+fn with_suffix(path: &Path, n: usize, is_dir: bool) -> PathBuf {
+    let suffix = format!(" ({n})");
+    let (stem, extension) = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) if !is_dir => (stem, Some(extension)),
+        _ => (path.file_name().unwrap_or_default(), None),
+    };
+    let mut name = stem.to_owned();
+    name.push(suffix);
+    if let Some(extension) = extension {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Whether two paths are the same file, such as names that only differ in
+/// case on a case-insensitive file system
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Whether two paths are the same when compared in the same Unicode
+/// normalization
+fn same_normalized(a: &Path, b: &Path) -> bool {
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.nfc().eq(b.nfc()),
+        _ => false,
+    }
+}
+
+/// Whether a move only changes the case of a name, to a name that is the same
+/// file. A hard link in the same directory is also the same file, but is
+/// another name that must not be replaced
+fn is_case_rename(from: &Path, to: &Path) -> bool {
+    let lowercase = |path: &Path| {
+        path.file_name()
+            .and_then(OsStr::to_str)
+            .map(str::to_lowercase)
+    };
+    from.parent() == to.parent()
+        && from != to
+        && lowercase(from).is_some_and(|name| Some(name) == lowercase(to))
+        && same_file(from, to)
+}
+
+/// Whether names that only differ in case are the same in a directory, as on
+/// APFS, NTFS and FAT. A name in it is looked up with its case changed, which
+/// is one of the `names` known to be in it where possible. Otherwise the
+/// directory is read up to its first name with letters
+#[cfg(unix)]
+fn is_case_insensitive(dir: &Path, names: &[&OsStr]) -> bool {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let entries = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.file_name()));
+    names
+        .iter()
+        .map(|name| name.to_os_string())
+        .chain(entries)
+        .find_map(|name| {
+            let name = name.to_str()?;
+            let swapped = name
+                .chars()
+                .map(|c| match c.is_lowercase() {
+                    true => c.to_uppercase().next().unwrap_or(c),
+                    false => c.to_lowercase().next().unwrap_or(c),
+                })
+                .collect::<String>();
+            // A known name may have been removed since it was listed
+            let path = dir.join(name);
+            (swapped != name && fs::symlink_metadata(&path).is_ok())
+                .then(|| same_file(&path, &dir.join(swapped)))
+        })
+        .unwrap_or(false)
+}
+
+/// NTFS is case-insensitive, unless it was turned off for a directory
+#[cfg(windows)]
+fn is_case_insensitive(_dir: &Path, _names: &[&OsStr]) -> bool {
+    true
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_case_insensitive(_dir: &Path, _names: &[&OsStr]) -> bool {
+    false
+}
+
+/// Returns the operations for the editable columns whose text was changed
+fn column_changes(
+    input_row: &InputRow,
+    output_row: &OutputRow,
+    columns: &[Column],
+) -> Result<Vec<Operation>> {
+    // Only read when there are columns
+    let Some(metadata) = &input_row.metadata else {
+        return Ok(Vec::new());
+    };
+    columns
+        .iter()
+        .zip(&output_row.cells)
+        .filter(|(column, _)| column.editable())
+        .filter_map(|(column, cell)| {
+            let old = column.format(metadata);
+            (*cell != old).then_some((column, old, cell))
+        })
+        .map(|(column, old, new)| match column {
+            Column::Ownership => {
+                let (uid, gid) =
+                    columns::parse_ownership(new).with_context(|| {
+                        format!("Invalid owner for {:?}", output_row.name)
+                    })?;
+                Ok(Operation::Chown {
+                    path: input_row.name.clone(),
+                    is_dir: input_row.is_dir,
+                    old,
+                    new: new.clone(),
+                    uid,
+                    gid,
+                })
+            }
+            Column::Timestamp => {
+                let mtime =
+                    columns::parse_timestamp(new).with_context(|| {
+                        format!(
+                            "Invalid modification time for {:?}",
+                            output_row.name
+                        )
+                    })?;
+                Ok(Operation::Touch {
+                    path: input_row.name.clone(),
+                    is_dir: input_row.is_dir,
+                    old,
+                    new: new.clone(),
+                    mtime,
+                })
+            }
+            Column::Inode
+            | Column::Mode
+            | Column::Links
+            | Column::Size
+            | Column::Mtime
+            | Column::Owner
+            | Column::Group => {
+                unreachable!("{column:?} is not editable")
+            }
+        })
+        .collect()
+}
+
+/// The identity and modification time of a listed file, to notice that it was
+/// changed or replaced after it was listed. Only the inode is known of files
+/// whose metadata wasn't read when they were listed
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    dev: Option<u64>,
+    ino: u64,
+    mtime: Option<SystemTime>,
+}
+
+impl Stamp {
+    fn of(metadata: &fs::Metadata) -> Stamp {
+        #[cfg(unix)]
+        let (dev, ino) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.dev(), metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let (dev, ino) = (0, 0);
+        Stamp {
+            dev: Some(dev),
+            ino,
+            // Directories are modified whenever a file in them is renamed,
+            // which doesn't change what the directory is
+            mtime: if metadata.is_dir() {
+                None
+            } else {
+                metadata.modified().ok()
+            },
+        }
+    }
+
+    /// Whether a file is the listed one and unchanged, as far as is known
+    fn matches(&self, metadata: &fs::Metadata) -> bool {
+        let other = Stamp::of(metadata);
+        self.ino == other.ino
+            && self.dev.is_none_or(|dev| Some(dev) == other.dev)
+            && (self.mtime.is_none() || self.mtime == other.mtime)
+    }
+}
+
+/// The stamp of a listed directory whose metadata wasn't read. The inode in
+/// the directory entry of a mount point is the one of the directory below it,
+/// so a directory on another file system than its parent is stamped as it is
+/// now instead
+fn dir_stamp(dir: &Path, ino: u64) -> Stamp {
+    let listed = Stamp {
+        dev: None,
+        ino,
+        mtime: None,
+    };
+    let Ok(metadata) = fs::symlink_metadata(dir) else {
+        return listed;
+    };
+    let stamp = Stamp::of(&metadata);
+    if stamp.ino != ino && stamp.dev != device(&existing_parent(dir)) {
+        return stamp;
+    }
+    listed
+}
+
+/// The directories whose entries an operation changes. A directory that
+/// doesn't exist yet is represented by the closest ancestor that does
+fn changed_dirs(operation: &Operation) -> Vec<PathBuf> {
+    match operation {
+        Operation::Move { from, to, .. } => {
+            vec![existing_parent(from), existing_parent(to)]
+        }
+        Operation::Swap { a, b, .. } => {
+            vec![existing_parent(a), existing_parent(b)]
+        }
+        // The contents of a directory are removed before the directory itself
+        Operation::Remove { path, is_dir } if *is_dir => {
+            vec![existing_parent(path), path.clone()]
+        }
+        Operation::Remove { path, .. } => vec![existing_parent(path)],
+        Operation::Copy { to, .. } | Operation::CreateDir { path: to } => {
+            vec![existing_parent(to)]
+        }
+        // Changing the owner or time depends on the file, not the directory
+        Operation::Chown { .. } | Operation::Touch { .. } => Vec::new(),
+    }
+}
+
+/// Returns the directory of a name, or the closest ancestor of it that exists
+fn existing_parent(path: &Path) -> PathBuf {
+    let mut dir = path.parent().unwrap_or(Path::new(""));
+    while !dir.as_os_str().is_empty() && fs::symlink_metadata(dir).is_err() {
+        dir = dir.parent().unwrap_or(Path::new(""));
+    }
+    if dir.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        dir.to_owned()
+    }
+}
+
+/// The size of a file, or of all files in a directory. Files that can't be
+/// read are left out, and fail when they are copied instead
+fn disk_usage(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| disk_usage(&entry.path()))
+            .sum(),
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => 0,
+    }
+}
+
+/// Returns the device of the file system that a directory is on, and the
+/// number of bytes that are free on it
+#[cfg(unix)]
+fn free_space(dir: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = fs::metadata(dir).ok()?.dev();
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: The path is NUL-terminated and `stat` is large enough
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: `statvfs` succeeded, so `stat` was filled in
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Some((dev, stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// The device of the file system that a file is on
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::symlink_metadata(path)
+        .ok()
+        .map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether entries can be added to and removed from a directory by the
+/// effective user
+#[cfg(unix)]
+fn is_writable(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(dir) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: The path is NUL-terminated
+    unsafe {
+        libc::faccessat(
+            libc::AT_FDCWD,
+            dir.as_ptr(),
+            libc::W_OK | libc::X_OK,
+            libc::AT_EACCESS,
+        ) == 0
+    }
+}
+
+#[cfg(not(unix))]
+fn is_writable(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
+
+/// The listed names that an operation acts on, which are checked against the
+/// listing right before it is applied
+fn sources(operation: &Operation) -> Vec<&Path> {
+    match operation {
+        Operation::Move { from, .. } | Operation::Copy { from, .. } => {
+            vec![from]
+        }
+        Operation::Swap { a, b, .. } => vec![a, b],
+        Operation::Remove { path, .. }
+        | Operation::Chown { path, .. }
+        | Operation::Touch { path, .. } => vec![path],
+        Operation::CreateDir { .. } => Vec::new(),
+    }
+}
+
+/// Finds the files of an operation that were renamed by another program after
+/// they were listed, by looking for the same file in the directory they were
+/// listed in. Returns the operation with the new names, if any were found
+fn relocate(
+    operation: &Operation,
+    stamps: &HashMap<PathBuf, Stamp>,
+    notify: &mut impl FnMut(Event),
+) -> Option<Operation> {
+    let mut relocated = None;
+    for path in sources(operation) {
+        let Some(stamp) = stamps.get(path) else {
+            continue;
+        };
+        if fs::symlink_metadata(path).is_ok() {
+            continue;
+        }
+        let Some(new_path) = find_renamed(path, stamp) else {
+            continue;
+        };
+        notify(Event::Relocated {
+            from: path,
+            to: &new_path,
+        });
+        let mut operation = relocated.unwrap_or_else(|| operation.clone());
+        match &mut operation {
+            Operation::Move { from, .. } | Operation::Copy { from, .. } => {
+                *from = new_path
+            }
+            Operation::Swap { a, b, .. } => {
+                if a == path {
+                    *a = new_path;
+                } else {
+                    *b = new_path;
+                }
+            }
+            Operation::Remove { path, .. }
+            | Operation::Chown { path, .. }
+            | Operation::Touch { path, .. } => *path = new_path,
+            Operation::CreateDir { .. } => {
+                unreachable!("New directories aren't listed")
+            }
+        }
+        relocated = Some(operation);
+    }
+    relocated
+}
+
+/// Looks for a file with the same device, inode and modification time in
+/// the directory of `path`
+fn find_renamed(path: &Path, stamp: &Stamp) -> Option<PathBuf> {
+    // Files can only be identified by their inode on Unix
+    if !cfg!(unix) {
+        return None;
+    }
+    let parent = path.parent()?;
+    let dir = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| parent.join(entry.file_name()))
+        .find(|name| {
+            fs::symlink_metadata(name)
+                .is_ok_and(|metadata| stamp.matches(&metadata))
+        })
+}
+
+/// Settings for applying the operations
+#[derive(Debug)]
+pub struct ApplyOptions<'a> {
+    /// Makes the changes beneath the listed directories
+    pub sandbox: Option<&'a Sandbox>,
+    /// Whether files that were renamed by another program after they were
+    /// listed are looked for, instead of failing
+    pub track_renames: bool,
+    /// Whether the POSIX ACLs of files that are moved to another file system,
+    /// by copying them, are copied too. Only on Linux
+    pub keep_acls: bool,
+    /// Whether the SELinux security contexts of files that are moved to
+    /// another file system are copied too, instead of them getting the default
+    /// context of their new location
+    pub keep_selinux: bool,
+}
+
+impl Default for ApplyOptions<'_> {
+    fn default() -> Self {
+        ApplyOptions {
+            sandbox: None,
+            track_renames: true,
+            keep_acls: true,
+            keep_selinux: true,
+        }
+    }
+}
+
+/// The operations that change the listed files into the edited rows, in the
+/// order they are applied
+#[derive(Debug)]
+pub struct Plan {
+    operations: Vec<Operation>,
+    /// The listed files by name, which are compared with the files again
+    /// right before they are changed
+    stamps: HashMap<PathBuf, Stamp>,
+    notes: Vec<String>,
+}
+
+impl Plan {
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Notes about the rows that were unchanged or ignored, when
+    /// [`PlanOptions::notes`] is set
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    /// Checks that the directories changed by each operation are writable,
+    /// so that operations that would fail are reported together before any
+    /// of them are applied
+    pub fn check_permissions(&self) -> Result<()> {
+        let denied = self
+            .operations
+            .iter()
+            .filter_map(|operation| {
+                let dir = changed_dirs(operation)
+                    .into_iter()
+                    .find(|dir| !is_writable(dir))?;
+                Some(format!("{operation}: {dir:?} isn't writable"))
+            })
+            .collect::<Vec<_>>();
+        if !denied.is_empty() {
+            bail!(
+                "These changes would fail without write permission:\n{}",
+                denied.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that the file systems that files are copied to have room for
+    /// the copies, before any of them are made. Files that are moved to
+    /// another file system are copied too
+    pub fn check_space(&self) -> Result<()> {
+        // The directory, free space and needed space of each file system
+        let mut file_systems = HashMap::<u64, (PathBuf, u64, u64)>::new();
+        for operation in &self.operations {
+            let (Operation::Copy { from, to, .. }
+            | Operation::Move { from, to, .. }) = operation
+            else {
+                continue;
+            };
+            let dir = existing_parent(to);
+            let Some((dev, free)) = free_space(&dir) else {
+                continue;
+            };
+            if matches!(operation, Operation::Move { .. })
+                && device(from) == Some(dev)
+            {
+                continue;
+            }
+            file_systems.entry(dev).or_insert((dir, free, 0)).2 +=
+                disk_usage(from);
+        }
+        for (dir, free, needed) in file_systems.values() {
+            if needed > free {
+                bail!(
+                    "Copying needs {} on the file system of {dir:?}, but only \
+                     {} is free",
+                    columns::human_size(*needed),
+                    columns::human_size(*free)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies every operation. See [`Plan::apply_with`]
+    pub fn apply(&self, options: &ApplyOptions) -> Result<()> {
+        self.apply_with(options, None, |_| Ok(true))
+    }
+
+    /// Applies the operations in order. Operations for which `approve`
+    /// returns false are skipped, as are operations on files that were
+    /// changed or replaced since they were listed. Applied operations are
+    /// appended to `log` with a timestamp
+    pub fn apply_with(
+        &self,
+        options: &ApplyOptions,
+        log: Option<&mut fs::File>,
+        approve: impl FnMut(&Operation) -> Result<bool>,
+    ) -> Result<()> {
+        apply_plan(self, options, log, approve, |_| (), &mut Vec::new())
+    }
+
+    /// Like `apply_with`, and pushes the outcome of each operation to
+    /// `report`, in the order of the operations. The operations after one that
+    /// fails are reported as not applied. `notify` is told what happens as
+    /// the operations are applied
+    pub fn apply_with_report(
+        &self,
+        options: &ApplyOptions,
+        log: Option<&mut fs::File>,
+        approve: impl FnMut(&Operation) -> Result<bool>,
+        notify: impl FnMut(Event),
+        report: &mut Vec<Outcome>,
+    ) -> Result<()> {
+        apply_plan(self, options, log, approve, notify, report)
+    }
+}
+
+/// What happened to an operation when the plan was applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Applied,
+    /// Declined, or its file changed after it was listed, with the reason
+    Skipped(String),
+    Failed(String),
+    /// An earlier operation failed
+    NotApplied,
+}
+
+/// Something that happened while the plan was applied, for the caller to
+/// show
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    Applied(&'a Operation),
+    /// Not approved
+    Declined(&'a Operation),
+    /// Its file changed after it was listed, with the reason
+    Skipped(&'a Operation, &'a str),
+    /// A file was renamed by another program after it was listed
+    Relocated {
+        from: &'a Path,
+        to: &'a Path,
+    },
+    /// Something that didn't fail the operation, such as an attribute that
+    /// couldn't be copied
+    Warning(&'a str),
+}
+
+fn apply_plan(
+    plan: &Plan,
+    options: &ApplyOptions,
+    mut log: Option<&mut fs::File>,
+    mut approve: impl FnMut(&Operation) -> Result<bool>,
+    mut notify: impl FnMut(Event),
+    report: &mut Vec<Outcome>,
+) -> Result<()> {
+    let ApplyOptions {
+        sandbox,
+        track_renames,
+        keep_acls,
+        keep_selinux,
+    } = *options;
+    let warnings = RefCell::new(Vec::new());
+    let keep = Keep {
+        acls: keep_acls,
+        selinux: keep_selinux,
+        warnings: &warnings,
+    };
+    if let Some(log) = log.as_mut().filter(|_| !plan.is_empty()) {
+        let dir =
+            env::current_dir().context("Couldn't get the current directory")?;
+        writeln!(log, "{} Applying changes in {dir:?}", timestamp())
+            .context("Couldn't write to log file")?;
+    }
+
+    let mut stamps = plan.stamps.clone();
+
+    // Returns why the operation was skipped, if it was
+    let mut apply_operation = |operation| -> Result<Option<String>> {
+        if !approve(operation)? {
+            notify(Event::Declined(operation));
+            return Ok(Some("Declined".to_owned()));
+        }
+        let relocated = track_renames
+            .then(|| relocate(operation, &stamps, &mut notify))
+            .flatten();
+        let listed_operation = operation;
+        let operation = relocated.as_ref().unwrap_or(operation);
+        // A file that is gone fails when the operation is applied instead
+        let changed = sources(listed_operation)
+            .into_iter()
+            .zip(sources(operation))
+            .find(|(listed, path)| {
+                stamps.get(*listed).is_some_and(|stamp| {
+                    fs::symlink_metadata(path)
+                        .is_ok_and(|metadata| !stamp.matches(&metadata))
+                })
+            });
+        if let Some((_, path)) = changed {
+            let reason = format!("{path:?} changed after it was listed");
+            notify(Event::Skipped(operation, &reason));
+            return Ok(Some(reason));
+        }
+        let result = match operation {
+            Operation::Move { from, to, is_dir } => {
+                mv_file(from, to, *is_dir, sandbox, keep)
+            }
+            Operation::Swap {
+                a,
+                b,
+                a_is_dir,
+                b_is_dir,
+            } => swap_files((a, *a_is_dir), (b, *b_is_dir), sandbox, keep),
+            Operation::Remove { path, is_dir } => {
+                rm_file(path, *is_dir, sandbox)
+            }
+            Operation::Copy { from, to, is_dir } => cp_file(from, to, *is_dir),
+            Operation::Chown {
+                path,
+                is_dir,
+                new,
+                uid,
+                gid,
+                ..
+            } => chown_file(path, *is_dir, new, (*uid, *gid)),
+            Operation::Touch {
+                path,
+                is_dir,
+                new,
+                mtime,
+                ..
+            } => touch_file(path, *is_dir, new, *mtime),
+            Operation::CreateDir { path } => create_dir(path, sandbox),
+        };
+        for warning in warnings.take() {
+            notify(Event::Warning(&warning));
+        }
+        result?;
+        // The new time is expected when the file is moved afterwards
+        if let (
+            Operation::Touch { path: listed, .. },
+            Operation::Touch { path, .. },
+        ) = (listed_operation, operation)
+        {
+            if let Ok(metadata) = fs::symlink_metadata(path) {
+                stamps.insert(listed.clone(), Stamp::of(&metadata));
+            }
+        }
+        notify(Event::Applied(operation));
+        if let Some(log) = log.as_mut() {
+            writeln!(log, "{} {operation}", timestamp())
+                .context("Couldn't write to log file")?;
+        }
+        Ok(None)
+    };
+
+    for (i, operation) in plan.operations.iter().enumerate() {
+        match apply_operation(operation) {
+            Ok(None) => report.push(Outcome::Applied),
+            Ok(Some(reason)) => report.push(Outcome::Skipped(reason)),
+            Err(err) => {
+                report.push(Outcome::Failed(format!("{err:#}")));
+                report.extend(
+                    plan.operations[i + 1..]
+                        .iter()
+                        .map(|_| Outcome::NotApplied),
+                );
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn timestamp() -> String {
+    Local::now().format(LOG_TIME_FORMAT).to_string()
+}
+
+/// Describes an applied operation for the verbose output
+pub fn describe_applied(
+    operation: &Operation,
+    colors: Option<&LsColors>,
+) -> String {
+    let show = |path: &Path, is_dir: bool| {
+        let text = format!("{path:?}");
+        match colors {
+            Some(colors) => colors.paint(&text, path, is_dir),
+            None => text,
+        }
+    };
+
+    match operation {
+        Operation::Move { from, to, is_dir } => format!(
+            "Moved {} {} to {}",
+            kind(*is_dir),
+            show(from, *is_dir),
+            show(to, *is_dir)
+        ),
+        Operation::Swap {
+            a,
+            b,
+            a_is_dir,
+            b_is_dir,
+        } => format!(
+            "Swapped {} {} with {} {}",
+            kind(*a_is_dir),
+            show(a, *a_is_dir),
+            kind(*b_is_dir),
+            show(b, *b_is_dir)
+        ),
+        Operation::Remove { path, is_dir } => {
+            format!("Removed {} {}", kind(*is_dir), show(path, *is_dir))
+        }
+        Operation::Copy { from, to, is_dir } => format!(
+            "Copied {} {} to {}",
+            kind(*is_dir),
+            show(from, *is_dir),
+            show(to, *is_dir)
+        ),
+        Operation::Chown {
+            path, is_dir, new, ..
+        } => format!(
+            "Changed owner of {} {} to {new}",
+            kind(*is_dir),
+            show(path, *is_dir)
+        ),
+        Operation::Touch {
+            path, is_dir, new, ..
+        } => format!(
+            "Changed modification time of {} {} to {new}",
+            kind(*is_dir),
+            show(path, *is_dir)
+        ),
+        Operation::CreateDir { path } => {
+            format!("Created directory {}", show(path, true))
+        }
+    }
+}
+
+/// Writes the operations as a diff, with the old name of each file prefixed
+/// by `-` and the new name, if any, by `+`. Changed columns are prefixed by
+/// `~`. Names are escaped like in the temporary file, and colored like `ls`
+/// when `colors` is set, with the words that a move changes highlighted
+pub fn write_diff(
+    plan: &[Operation],
+    colors: Option<&LsColors>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let display = |path: &Path, is_dir: bool| {
+        let escaped = escape::escape(RawOsStr::new(path.as_os_str()).as_ref());
+        let mut name = escaped.to_str_lossy().into_owned();
+        // Edited names of directories may already end with a slash
+        if is_dir && !name.ends_with('/') {
+            name.push('/');
+        }
+        name
+    };
+    // `other` is the name that the file has on the other line of a move
+    let mut line = |sign: char,
+                    color_code: &str,
+                    path: &Path,
+                    is_dir: bool,
+                    detail: &str,
+                    other: Option<&Path>| {
+        let name = display(path, is_dir);
+        match colors {
+            Some(colors) => {
+                let name = match other {
+                    Some(other) => {
+                        highlight_change(&name, &display(other, is_dir))
+                    }
+                    None => name,
+                };
+                writeln!(
+                    out,
+                    "{color_code}{sign}{RESET} {}{detail}",
+                    colors.paint(&name, path, is_dir)
+                )
+            }
+            None => writeln!(out, "{sign} {name}{detail}"),
+        }
+    };
+
+    // Special files are marked with their type, read before any changes
+    let special = |path: &Path| {
+        special_kind_of(path)
+            .map(|kind| format!(" ({kind})"))
+            .unwrap_or_default()
+    };
+
+    for operation in plan {
+        match operation {
+            Operation::Move { from, to, is_dir } => {
+                let special = special(from);
+                line('-', RED, from, *is_dir, &special, Some(to))?;
+                line('+', GREEN, to, *is_dir, &special, Some(from))?;
+            }
+            Operation::Swap {
+                a,
+                b,
+                a_is_dir,
+                b_is_dir,
+            } => {
+                let (a_special, b_special) = (special(a), special(b));
+                line('-', RED, a, *a_is_dir, &a_special, Some(b))?;
+                line('+', GREEN, b, *a_is_dir, &a_special, Some(a))?;
+                line('-', RED, b, *b_is_dir, &b_special, Some(a))?;
+                line('+', GREEN, a, *b_is_dir, &b_special, Some(b))?;
+            }
+            Operation::Remove { path, is_dir } => {
+                line('-', RED, path, *is_dir, &special(path), None)?;
+            }
+            Operation::Copy { from, to, is_dir } => {
+                let from =
+                    escape::escape(RawOsStr::new(from.as_os_str()).as_ref());
+                line(
+                    '+',
+                    GREEN,
+                    to,
+                    *is_dir,
+                    &format!(" (copy of {})", from.to_str_lossy()),
+                    None,
+                )?;
+            }
+            Operation::Chown {
+                path,
+                is_dir,
+                old,
+                new,
+                ..
+            }
+            | Operation::Touch {
+                path,
+                is_dir,
+                old,
+                new,
+                ..
+            } => {
+                line(
+                    '~',
+                    YELLOW,
+                    path,
+                    *is_dir,
+                    &format!("{} {old} -> {new}", special(path)),
+                    None,
+                )?;
+            }
+            Operation::CreateDir { path } => {
+                line('+', GREEN, path, true, "", None)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Highlights the part of a name that differs from the other name, between
+/// the start and end they have in common. Words that only partly match are
+/// highlighted as a whole, so that e.g. `001` to `002` shows the whole number
+fn highlight_change(name: &str, other: &str) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    let common_len = |a: &mut dyn Iterator<Item = char>,
+                      b: &mut dyn Iterator<Item = char>| {
+        a.zip(b)
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum::<usize>()
+    };
+
+    let mut start = common_len(&mut name.chars(), &mut other.chars());
+    while is_word(name[..start].chars().next_back())
+        && (is_word(name[start..].chars().next())
+            || is_word(other[start..].chars().next()))
+    {
+        start -= name[..start].chars().next_back().map_or(0, char::len_utf8);
+    }
+    let mut end_len = common_len(
+        &mut name[start..].chars().rev(),
+        &mut other[start..].chars().rev(),
+    );
+    let end = |text: &str, end_len| text.len() - end_len;
+    while is_word(name[end(name, end_len)..].chars().next())
+        && (is_word(name[..end(name, end_len)].chars().next_back())
+            || is_word(other[..end(other, end_len)].chars().next_back()))
+    {
+        end_len -= name[end(name, end_len)..]
+            .chars()
+            .next()
+            .map_or(0, char::len_utf8);
+    }
+
+    let changed = &name[start..end(name, end_len)];
+    if changed.is_empty() {
+        return name.to_owned();
+    }
+    format!(
+        "{}{REVERSE}{changed}{NO_REVERSE}{}",
+        &name[..start],
+        &name[end(name, end_len)..]
+    )
+}
+
+fn rm_file(path: &Path, is_dir: bool, sandbox: Option<&Sandbox>) -> Result<()> {
+    if let Some(sandbox) = sandbox {
+        sandbox.remove(path, is_dir)
+    } else if is_dir {
+        fs::remove_dir_all(path)
+    } else {
+        remove_file(path)
+    }
+    .with_context(|| {
+        let message = format!("Error deleting {} {:?}", kind(is_dir), path);
+        PlanError::apply(path, None, message)
+    })
+}
+
+/// Symlinks to directories and junctions on Windows are removed like empty
+/// directories, which removes the link and leaves its target as it is
+#[cfg(windows)]
+fn remove_file(path: &Path) -> io::Result<()> {
+    use std::os::windows::fs::FileTypeExt;
+
+    if fs::symlink_metadata(path)?.file_type().is_symlink_dir() {
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(not(windows))]
+fn remove_file(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)
+}
+
+fn create_dir(path: &Path, sandbox: Option<&Sandbox>) -> Result<()> {
+    match sandbox {
+        Some(sandbox) => sandbox.create_dir(path),
+        None => fs::create_dir(path),
+    }
+    .with_context(|| {
+        let message = format!("Error creating directory {path:?}");
+        PlanError::apply(path, None, message)
+    })
+}
+
+/// Attributes that are kept when a file is moved to another file system, which
+/// is done by copying it
+#[derive(Debug, Clone, Copy)]
+struct Keep<'a> {
+    acls: bool,
+    selinux: bool,
+    /// Attributes that couldn't be set on the copy, which doesn't fail it
+    warnings: &'a RefCell<Vec<String>>,
+}
+
+fn mv_file(
+    from: &Path,
+    to: &Path,
+    is_dir: bool,
+    sandbox: Option<&Sandbox>,
+    keep: Keep<'_>,
+) -> Result<()> {
+    let replace = is_case_rename(from, to);
+    match sandbox {
+        Some(sandbox) => sandbox.rename(from, to, replace),
+        None => sandbox::rename(from, to, replace),
+    }
+    .or_else(|err| {
+        if !crosses_devices(&err) {
+            return Err(anyhow::Error::from(err));
+        }
+        copy_path(from, to, Some(keep))
+            .context("Couldn't copy it to the other file system")?;
+        rm_file(from, is_dir, sandbox)
+    })
+    .with_context(|| {
+        let message =
+            format!("Error moving {} {:?} to {:?}", kind(is_dir), from, to);
+        PlanError::apply(from, Some(to), message)
+    })
+}
+
+/// Whether a rename failed because the new name is on another file system,
+/// in which case the file is moved by copying it
+#[cfg(unix)]
+fn crosses_devices(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+fn crosses_devices(err: &io::Error) -> bool {
+    // ERROR_NOT_SAME_DEVICE
+    err.raw_os_error() == Some(17)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn crosses_devices(_err: &io::Error) -> bool {
+    false
+}
+
+/// Swaps the names of two files, in one step where supported and otherwise
+/// through a temporary name
+fn swap_files(
+    (a, a_is_dir): (&Path, bool),
+    (b, b_is_dir): (&Path, bool),
+    sandbox: Option<&Sandbox>,
+    keep: Keep<'_>,
+) -> Result<()> {
+    let exchanged = match sandbox {
+        Some(sandbox) => sandbox.exchange(a, b),
+        None => sandbox::exchange(a, b),
+    }
+    .with_context(|| {
+        let message = format!(
+            "Error swapping {} {a:?} with {} {b:?}",
+            kind(a_is_dir),
+            kind(b_is_dir)
+        );
+        PlanError::apply(a, Some(b), message)
+    })?;
+    if exchanged {
+        return Ok(());
+    }
+
+    let temp = temp_name(a, |name| fs::symlink_metadata(name).is_err());
+    mv_file(a, &temp, a_is_dir, sandbox, keep)?;
+    mv_file(b, a, b_is_dir, sandbox, keep)?;
+    mv_file(&temp, b, a_is_dir, sandbox, keep)
+}
+
+fn cp_file(from: &Path, to: &Path, is_dir: bool) -> Result<()> {
+    copy_path(from, to, None).with_context(|| {
+        let message =
+            format!("Error copying {} {:?} to {:?}", kind(is_dir), from, to);
+        PlanError::apply(from, Some(to), message)
+    })
+}
+
+/// Copies a file, or a directory with its contents. Symlinks are copied as
+/// links rather than followed, and special files aren't copied. With `keep`,
+/// the attributes of each file are copied too. Nothing that already exists is
+/// replaced, and on failure only what was created by the copy is removed
+/// again, so that the file is still in one place only
+fn copy_path(from: &Path, to: &Path, keep: Option<Keep<'_>>) -> io::Result<()> {
+    let mut created = Vec::new();
+    copy_new(from, to, keep, &mut created).inspect_err(|_| {
+        // Directories are only removed if nothing else was put in them
+        for (path, is_dir) in created.iter().rev() {
+            let _ = if *is_dir {
+                fs::remove_dir(path)
+            } else {
+                remove_file(path)
+            };
+        }
+    })
+}
+
+/// Copies like [`copy_path`], adding each file that it creates to `created`
+fn copy_new(
+    from: &Path,
+    to: &Path,
+    keep: Option<Keep<'_>>,
+    created: &mut Vec<(PathBuf, bool)>,
+) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    let file_type = metadata.file_type();
+    if let Some(kind) = special_kind(&file_type) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Can't copy {kind} {from:?}"),
+        ));
+    } else if file_type.is_dir() {
+        fs::create_dir(to)?;
+        created.push((to.to_owned(), true));
+        fs::read_dir(from)?.try_for_each(|entry| {
+            let entry = entry?;
+            copy_new(&entry.path(), &to.join(entry.file_name()), keep, created)
+        })?;
+    } else if file_type.is_symlink() {
+        copy_symlink(from, to)?;
+        created.push((to.to_owned(), false));
+    } else {
+        // Created exclusively, so that a file that appeared under the name
+        // since the plan was checked is neither replaced nor removed
+        let mut source = fs::File::open(from)?;
+        let mut target = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(to)?;
+        created.push((to.to_owned(), false));
+        io::copy(&mut source, &mut target)?;
+        target.set_permissions(metadata.permissions())?;
+    }
+    match keep {
+        Some(keep) => keep_attributes(from, to, &metadata, keep),
+        None => Ok(()),
+    }
+}
+
+/// Copies the permissions, times, ACLs and SELinux context of a file, as far
+/// as `keep` says. Directories are done after their contents, which may need
+/// write permission
+fn keep_attributes(
+    from: &Path,
+    to: &Path,
+    metadata: &fs::Metadata,
+    keep: Keep<'_>,
+) -> io::Result<()> {
+    // Symlinks have no permissions of their own
+    if !metadata.is_symlink() {
+        fs::set_permissions(to, metadata.permissions())?;
+        if keep.acls {
+            copy_acls(from, to, metadata.is_dir(), keep.warnings)?;
+        }
+    }
+    // Symlinks do have a context, which decides who may follow them
+    if keep.selinux {
+        copy_selinux_context(from, to, keep.warnings)?;
+    }
+    filetime::set_symlink_file_times(
+        to,
+        FileTime::from_last_access_time(metadata),
+        FileTime::from_last_modification_time(metadata),
+    )
+}
+
+/// Copies the POSIX ACLs of a file, and the default ACLs of a directory that
+/// new files in it get
+#[cfg(target_os = "linux")]
+fn copy_acls(
+    from: &Path,
+    to: &Path,
+    is_dir: bool,
+    warnings: &RefCell<Vec<String>>,
+) -> io::Result<()> {
+    copy_xattr(from, to, c"system.posix_acl_access", warnings)?;
+    if is_dir {
+        copy_xattr(from, to, c"system.posix_acl_default", warnings)?;
+    }
+    Ok(())
+}
+
+/// Other systems aren't supported. On Windows, the copy gets the NTFS ACL that
+/// it inherits from its new directory
+#[cfg(not(target_os = "linux"))]
+fn copy_acls(
+    _from: &Path,
+    _to: &Path,
+    _is_dir: bool,
+    _warnings: &RefCell<Vec<String>>,
+) -> io::Result<()> {
+    Ok(())
+}
+
+/// Copies the SELinux security context of a file. Files without one, on systems
+/// without SELinux, are left as they are
+#[cfg(target_os = "linux")]
+fn copy_selinux_context(
+    from: &Path,
+    to: &Path,
+    warnings: &RefCell<Vec<String>>,
+) -> io::Result<()> {
+    copy_xattr(from, to, c"security.selinux", warnings)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_selinux_context(
+    _from: &Path,
+    _to: &Path,
+    _warnings: &RefCell<Vec<String>>,
+) -> io::Result<()> {
+    Ok(())
+}
+
+/// Copies an extended attribute, if the file has it. Like `cp -a`, a file
+/// system that won't take it only adds to `warnings`, since the file itself
+/// has already been copied
+#[cfg(target_os = "linux")]
+fn copy_xattr(
+    from: &Path,
+    to: &Path,
+    name: &std::ffi::CStr,
+    warnings: &RefCell<Vec<String>>,
+) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::ptr;
+
+    let to_path = to;
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: The names are NUL-terminated, and a length of 0 returns the
+    // size of the attribute without reading it
+    let size = unsafe {
+        libc::lgetxattr(from.as_ptr(), name.as_ptr(), ptr::null_mut(), 0)
+    };
+    if size < 0 {
+        let err = io::Error::last_os_error();
+        // The file has no such attribute, or its file system has none at all
+        return match err.raw_os_error() {
+            Some(libc::ENODATA | libc::ENOTSUP) => Ok(()),
+            _ => Err(err),
+        };
+    }
+    let mut value = vec![0u8; size as usize];
+    // SAFETY: The names are NUL-terminated and the buffer has the length that
+    // is passed
+    let size = unsafe {
+        libc::lgetxattr(
+            from.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    value.truncate(size as usize);
+    // SAFETY: The names are NUL-terminated and the value has the length that
+    // is passed
+    let result = unsafe {
+        libc::lsetxattr(
+            to.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if result < 0 {
+        warnings.borrow_mut().push(format!(
+            "Couldn't set attribute {name:?} on {to_path:?}: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+/// Windows has separate symlinks for files and directories. Junctions are
+/// copied as symlinks to the same directory
+#[cfg(windows)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file, FileTypeExt};
+
+    let target = fs::read_link(from)?;
+    if fs::symlink_metadata(from)?.file_type().is_symlink_dir() {
+        symlink_dir(target, to)
+    } else {
+        symlink_file(target, to)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to).map(|_| ())
+}
+
+fn chown_file(
+    path: &Path,
+    is_dir: bool,
+    ownership: &str,
+    (uid, gid): (u32, u32),
+) -> Result<()> {
+    #[cfg(unix)]
+    let result =
+        std::os::unix::fs::lchown(path, Some(uid), Some(gid)).map_err(|err| {
+            let permission_denied =
+                err.kind() == io::ErrorKind::PermissionDenied;
+            let err = anyhow::Error::new(err);
+            if permission_denied {
+                err.context("Changing the owner usually requires root")
+            } else {
+                err
+            }
+        });
+    #[cfg(not(unix))]
+    let result: Result<()> = {
+        let _ = (uid, gid);
+        Err(anyhow::anyhow!(
+            "Changing the owner of files is only supported on Unix"
+        ))
+    };
+
+    result.with_context(|| {
+        let message = format!(
+            "Error changing owner of {} {:?} to {ownership}",
+            kind(is_dir),
+            path
+        );
+        PlanError::apply(path, None, message)
+    })
+}
+
+fn touch_file(
+    path: &Path,
+    is_dir: bool,
+    timestamp: &str,
+    mtime: SystemTime,
+) -> Result<()> {
+    // The access time is kept, and symlinks are changed rather than followed,
+    // like the other operations
+    fs::symlink_metadata(path)
+        .and_then(|metadata| {
+            filetime::set_symlink_file_times(
+                path,
+                FileTime::from_last_access_time(&metadata),
+                FileTime::from_system_time(mtime),
+            )
+        })
+        .with_context(|| {
+            let message = format!(
+                "Error changing modification time of {} {:?} to {timestamp}",
+                kind(is_dir),
+                path
+            );
+            PlanError::apply(path, None, message)
+        })
+}
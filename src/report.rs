@@ -0,0 +1,112 @@
+//! A report of what happened to each change, printed at the end of the session
+//! so that other programs can read it
+
+use crate::serve;
+use clap::ValueEnum;
+use rsdir::plan::{Operation, Outcome};
+use serde_json::Value;
+use std::io::{self, Write};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ReportFormat {
+    /// Aligned columns
+    Table,
+    /// An array of the changes, with the same fields as in server mode
+    Json,
+    /// A header and a line for each change
+    Csv,
+}
+
+pub fn write_report(
+    report: &[(Operation, Outcome)],
+    format: ReportFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    match format {
+        ReportFormat::Table => {
+            writeln!(out, "{:<12}CHANGE", "RESULT")?;
+            for (operation, outcome) in report {
+                let (result, detail) = result(outcome);
+                write!(out, "{result:<12}{operation}")?;
+                match detail {
+                    Some(detail) => writeln!(out, ": {detail}")?,
+                    None => writeln!(out)?,
+                }
+            }
+        }
+        ReportFormat::Json => {
+            let changes = report
+                .iter()
+                .map(|(operation, outcome)| {
+                    let mut change = serve::describe_operation(operation);
+                    let (result, detail) = result(outcome);
+                    change["result"] = result.into();
+                    if let Some(detail) = detail {
+                        let key = match outcome {
+                            Outcome::Failed(_) => "error",
+                            _ => "reason",
+                        };
+                        change[key] = detail.into();
+                    }
+                    change
+                })
+                .collect();
+            writeln!(out, "{}", Value::Array(changes))?;
+        }
+        ReportFormat::Csv => {
+            writeln!(out, "result,type,path,new,detail")?;
+            for (operation, outcome) in report {
+                let change = serve::describe_operation(operation);
+                let (path, new) = match operation {
+                    Operation::Move { from, to, .. }
+                    | Operation::Copy { from, to, .. } => {
+                        (from.to_string_lossy(), to.to_string_lossy())
+                    }
+                    Operation::Swap { a, b, .. } => {
+                        (a.to_string_lossy(), b.to_string_lossy())
+                    }
+                    Operation::Remove { path, .. }
+                    | Operation::CreateDir { path } => {
+                        (path.to_string_lossy(), "".into())
+                    }
+                    Operation::Chown { path, new, .. }
+                    | Operation::Touch { path, new, .. } => {
+                        (path.to_string_lossy(), new.into())
+                    }
+                };
+                let (result, detail) = result(outcome);
+                let fields = [
+                    result,
+                    change["type"].as_str().unwrap_or_default(),
+                    &path,
+                    &new,
+                    detail.unwrap_or_default(),
+                ];
+                let line = fields.map(csv_field).join(",");
+                writeln!(out, "{line}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The result of a change, and the reason it was skipped or the error it
+/// failed with
+fn result(outcome: &Outcome) -> (&'static str, Option<&str>) {
+    match outcome {
+        Outcome::Applied => ("applied", None),
+        Outcome::Skipped(reason) => ("skipped", Some(reason)),
+        Outcome::Failed(error) => ("failed", Some(error)),
+        Outcome::NotApplied => ("not applied", None),
+    }
+}
+
+/// Quotes a field that contains a separator, quote or line break, doubling
+/// the quotes in it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -0,0 +1,396 @@
+//! Applying moves and removals relative to the listed directories on Linux.
+//! The directory of each name is opened with `openat2` and
+//! `RESOLVE_BENEATH`, so that neither `..` nor symlinks in an edited name can
+//! reach outside of the directory it was listed from. Unless restricted,
+//! names outside of it or through a symlinked directory are changed by their
+//! path instead.
+//!
+//! Renames use `RENAME_NOREPLACE` where supported, so that a file created
+//! under the new name after the plan was checked is never replaced, and
+//! `RENAME_EXCHANGE` to swap the names of two files in one step
+
+#[cfg(target_os = "linux")]
+pub use linux::{exchange, rename, Sandbox};
+#[cfg(not(target_os = "linux"))]
+pub use other::{exchange, rename, Sandbox};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::fs;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Component, Path, PathBuf};
+
+    /// `struct open_how` from `linux/openat2.h`
+    #[repr(C)]
+    struct OpenHow {
+        flags: u64,
+        mode: u64,
+        resolve: u64,
+    }
+
+    /// The listed directories, which names are changed beneath
+    #[derive(Debug)]
+    pub struct Sandbox {
+        /// The listed directories, as passed on the command line, and their file
+        /// descriptors
+        roots: Vec<(PathBuf, OwnedFd)>,
+        /// Whether names outside of the listed directories, or through a
+        /// symlinked directory, fail. Otherwise they are changed by their
+        /// path, like without a sandbox
+        restrict: bool,
+    }
+
+    impl Sandbox {
+        /// Opens the directories. With `restrict`, names outside of them or
+        /// through a symlinked directory fail, instead of being changed by
+        /// their path. Returns `None` if the kernel doesn't support `openat2`,
+        /// which was added in Linux 5.6
+        pub fn open(
+            roots: &[PathBuf],
+            restrict: bool,
+        ) -> io::Result<Option<Sandbox>> {
+            let mut sandbox = Sandbox {
+                roots: Vec::new(),
+                restrict,
+            };
+            for root in roots {
+                let fd = OwnedFd::from(fs::File::open(root)?);
+                let root = strip_cur_dir(root).to_owned();
+                match openat2(&fd, Path::new(".")) {
+                    Ok(fd) => sandbox.roots.push((root, fd)),
+                    Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+                        return Ok(None)
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(Some(sandbox))
+        }
+
+        /// Renames a file, failing if the new name exists unless `replace` is
+        /// set, which is needed when both names are the same file
+        pub fn rename(
+            &self,
+            from: &Path,
+            to: &Path,
+            replace: bool,
+        ) -> io::Result<()> {
+            match (self.open_parent(from)?, self.open_parent(to)?) {
+                (Some((from_dir, from_name)), Some((to_dir, to_name))) => {
+                    rename_at(
+                        (from_dir.as_raw_fd(), &c_name(from_name)?),
+                        (to_dir.as_raw_fd(), &c_name(to_name)?),
+                        replace,
+                    )
+                }
+                _ => rename(from, to, replace),
+            }
+        }
+
+        /// Swaps the names of two files in one step. Returns `false` if the
+        /// file system doesn't support it
+        pub fn exchange(&self, a: &Path, b: &Path) -> io::Result<bool> {
+            match (self.open_parent(a)?, self.open_parent(b)?) {
+                (Some((a_dir, a_name)), Some((b_dir, b_name))) => exchange_at(
+                    (a_dir.as_raw_fd(), &c_name(a_name)?),
+                    (b_dir.as_raw_fd(), &c_name(b_name)?),
+                ),
+                _ => exchange(a, b),
+            }
+        }
+
+        /// Removes a file, or a directory with everything in it
+        pub fn remove(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+            let Some((dir, name)) = self.open_parent(path)? else {
+                return if is_dir {
+                    fs::remove_dir_all(path)
+                } else {
+                    fs::remove_file(path)
+                };
+            };
+            if is_dir {
+                // The directory is only reached through its already opened
+                // parent. `remove_dir_all` doesn't follow symlinks inside it
+                let fds = Path::new("/proc/self/fd");
+                if !fds.is_dir() {
+                    if self.restrict {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "Removing a directory with --restrict needs /proc",
+                        ));
+                    }
+                    return fs::remove_dir_all(path);
+                }
+                return fs::remove_dir_all(
+                    fds.join(dir.as_raw_fd().to_string()).join(name),
+                );
+            }
+            let name = c_name(name)?;
+            // SAFETY: The name is NUL-terminated and the directory is open
+            check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })
+        }
+
+        /// Creates a directory, whose parent must exist
+        pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+            let Some((dir, name)) = self.open_parent(path)? else {
+                return fs::create_dir(path);
+            };
+            let name = c_name(name)?;
+            // SAFETY: The name is NUL-terminated and the directory is open
+            check(unsafe {
+                libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o777)
+            })
+        }
+
+        /// Opens the directory that a name is in, beneath the listed directory
+        /// that the name starts with, and returns it together with the last
+        /// component of the name. Returns `None` for names outside of the
+        /// listed directories or that go through a symlinked directory, which
+        /// fail with `restrict`
+        fn open_parent<'a>(
+            &self,
+            path: &'a Path,
+        ) -> io::Result<Option<(OwnedFd, &'a OsStr)>> {
+            let outside = |message| {
+                if self.restrict {
+                    Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        message,
+                    ))
+                } else {
+                    Ok(None)
+                }
+            };
+            const OUTSIDE: &str = "Name is outside of the listed directories";
+            let Some(name) = path.file_name() else {
+                return outside(OUTSIDE);
+            };
+            let relative = strip_cur_dir(path);
+            let Some((root_fd, parent)) =
+                self.roots.iter().find_map(|(root, fd)| {
+                    let parent = relative.strip_prefix(root).ok()?.parent()?;
+                    Some((fd, parent))
+                })
+            else {
+                return outside(OUTSIDE);
+            };
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            match openat2(root_fd, parent) {
+                Ok(dir) => Ok(Some((dir, name))),
+                Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+                    outside(OUTSIDE)
+                }
+                Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+                    outside("Name goes through a symlink")
+                }
+                Err(err) => Err(err),
+            }
+        }
+    }
+
+    /// Renames a file, failing if the new name exists unless `replace` is
+    /// set, which is needed when both names are the same file
+    pub fn rename(from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+        rename_at(
+            (libc::AT_FDCWD, &c_name(from.as_os_str())?),
+            (libc::AT_FDCWD, &c_name(to.as_os_str())?),
+            replace,
+        )
+    }
+
+    /// Swaps the names of two files in one step. Returns `false` if the
+    /// file system doesn't support it
+    pub fn exchange(a: &Path, b: &Path) -> io::Result<bool> {
+        exchange_at(
+            (libc::AT_FDCWD, &c_name(a.as_os_str())?),
+            (libc::AT_FDCWD, &c_name(b.as_os_str())?),
+        )
+    }
+
+    fn exchange_at(
+        a: (libc::c_int, &CStr),
+        b: (libc::c_int, &CStr),
+    ) -> io::Result<bool> {
+        match renameat2(a, b, libc::RENAME_EXCHANGE) {
+            Ok(()) => Ok(true),
+            Err(err) if is_unsupported(&err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Renames a name in a directory. File systems that don't support
+    /// `RENAME_NOREPLACE` fall back to checking that the new name doesn't
+    /// exist right before renaming
+    fn rename_at(
+        (from_dir, from): (libc::c_int, &CStr),
+        (to_dir, to): (libc::c_int, &CStr),
+        replace: bool,
+    ) -> io::Result<()> {
+        if !replace {
+            match renameat2(
+                (from_dir, from),
+                (to_dir, to),
+                libc::RENAME_NOREPLACE,
+            ) {
+                Err(err) if is_unsupported(&err) => {}
+                result => return result,
+            }
+            let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+            // SAFETY: The name is NUL-terminated and `stat` is large enough
+            let exists = unsafe {
+                libc::fstatat(
+                    to_dir,
+                    to.as_ptr(),
+                    stat.as_mut_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            } == 0;
+            if exists {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+            }
+        }
+        // SAFETY: The names are NUL-terminated
+        check(unsafe {
+            libc::renameat(from_dir, from.as_ptr(), to_dir, to.as_ptr())
+        })
+    }
+
+    fn renameat2(
+        (from_dir, from): (libc::c_int, &CStr),
+        (to_dir, to): (libc::c_int, &CStr),
+        flags: libc::c_uint,
+    ) -> io::Result<()> {
+        // SAFETY: The names are NUL-terminated
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                from_dir,
+                from.as_ptr(),
+                to_dir,
+                to.as_ptr(),
+                flags,
+            )
+        };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether `renameat2` failed because the kernel or file system doesn't
+    /// support the flag
+    fn is_unsupported(err: &io::Error) -> bool {
+        matches!(err.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS))
+    }
+
+    /// Removes leading `./`, which is optional in names
+    fn strip_cur_dir(path: &Path) -> &Path {
+        let mut components = path.components();
+        while components.clone().next() == Some(Component::CurDir) {
+            components.next();
+        }
+        components.as_path()
+    }
+
+    /// Opens a directory beneath `dir`, without following any symlinks
+    fn openat2(dir: &OwnedFd, path: &Path) -> io::Result<OwnedFd> {
+        let path = c_name(path.as_os_str())?;
+        let how = OpenHow {
+            flags: (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64,
+            mode: 0,
+            resolve: libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS,
+        };
+        // SAFETY: The path is NUL-terminated and `how` has the layout of
+        // `struct open_how`, with its size passed along
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dir.as_raw_fd(),
+                path.as_ptr(),
+                &how as *const OpenHow,
+                std::mem::size_of::<OpenHow>(),
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The file descriptor was just opened and isn't owned elsewhere
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+    }
+
+    fn c_name(name: &OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    /// Swapping names in one step isn't supported
+    pub fn exchange(_a: &Path, _b: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Renames a file, failing if the new name exists unless `replace` is
+    /// set, which is needed when both names are the same file
+    pub fn rename(from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+        if !replace && fs::symlink_metadata(to).is_ok() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        fs::rename(from, to)
+    }
+
+    /// `openat2` is only available on Linux, so there is never a sandbox
+    #[derive(Debug)]
+    pub enum Sandbox {}
+
+    impl Sandbox {
+        pub fn open(
+            _roots: &[PathBuf],
+            _restrict: bool,
+        ) -> io::Result<Option<Sandbox>> {
+            Ok(None)
+        }
+
+        pub fn rename(
+            &self,
+            _from: &Path,
+            _to: &Path,
+            _replace: bool,
+        ) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn exchange(&self, _a: &Path, _b: &Path) -> io::Result<bool> {
+            match *self {}
+        }
+
+        pub fn create_dir(&self, _path: &Path) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn remove(&self, _path: &Path, _is_dir: bool) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Writing the operations as a shell script, so that they can be reviewed,
+//! edited or run elsewhere instead of being applied directly
+
+use chrono::{DateTime, Local};
+use os_str_bytes::RawOsStr;
+use rsdir::plan::{self, Operation};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Time format of `touch -t`
+const TOUCH_TIME_FORMAT: &str = "%Y%m%d%H%M.%S";
+/// Shell function that stops the script when a name that a file is moved or
+/// copied to exists. `mv` and `cp` would otherwise put the file inside an
+/// existing directory, or replace a file that was created after the script
+/// was written. `mv -T` does the same, but only in GNU coreutils
+const CHECK_FREE: &str = r#"check_free() {
+    if [ -e "$1" ] || [ -L "$1" ]; then
+        echo "$1 already exists" >&2
+        exit 1
+    fi
+}"#;
+
+pub fn write_script(
+    plan: &[Operation],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "#!/bin/sh")?;
+    writeln!(out, "set -e")?;
+    writeln!(out, "{CHECK_FREE}")?;
+    for operation in plan {
+        match operation {
+            Operation::Move { from, to, .. } => write_move(out, from, to)?,
+            Operation::Swap { a, b, .. } => {
+                // `mv --exchange` is too new to rely on
+                let temp = plan::temp_name(a, |name| {
+                    fs::symlink_metadata(name).is_err()
+                });
+                write_move(out, a, &temp)?;
+                write!(out, " && ")?;
+                write_move(out, b, a)?;
+                write!(out, " && ")?;
+                write_move(out, &temp, b)?;
+            }
+            Operation::Remove { path, is_dir } => {
+                write!(out, "{} -- ", if *is_dir { "rm -r" } else { "rm" })?;
+                write_quoted(out, path)?;
+            }
+            Operation::Copy { from, to, .. } => {
+                write!(out, "check_free ")?;
+                write_quoted(out, to)?;
+                write!(out, " && cp -RP -- ")?;
+                write_quoted(out, from)?;
+                write!(out, " ")?;
+                write_quoted(out, to)?;
+            }
+            Operation::Chown { path, uid, gid, .. } => {
+                write!(out, "chown -h {uid}:{gid} -- ")?;
+                write_quoted(out, path)?;
+            }
+            Operation::Touch { path, mtime, .. } => {
+                let time = DateTime::<Local>::from(*mtime);
+                write!(
+                    out,
+                    "touch -h -m -t {} -- ",
+                    time.format(TOUCH_TIME_FORMAT)
+                )?;
+                write_quoted(out, path)?;
+            }
+            Operation::CreateDir { path } => {
+                write!(out, "mkdir -- ")?;
+                write_quoted(out, path)?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes a move, which checks that the new name is free first. A change of
+/// case only is left to `mv`, since the new name is the same file on
+/// case-insensitive file systems
+fn write_move(out: &mut impl Write, from: &Path, to: &Path) -> io::Result<()> {
+    let lowercase = |path: &Path| {
+        path.file_name()
+            .and_then(OsStr::to_str)
+            .map(str::to_lowercase)
+    };
+    let case_only = from.parent() == to.parent()
+        && lowercase(from).is_some_and(|name| Some(name) == lowercase(to));
+    if !case_only {
+        write!(out, "check_free ")?;
+        write_quoted(out, to)?;
+        write!(out, " && ")?;
+    }
+    write!(out, "mv -- ")?;
+    write_quoted(out, from)?;
+    write!(out, " ")?;
+    write_quoted(out, to)
+}
+
+/// Writes a path in single quotes, which keep every character as is except
+/// single quotes themselves. The bytes of the path are written unchanged, so
+/// names that aren't valid Unicode are kept
+fn write_quoted(out: &mut impl Write, path: &Path) -> io::Result<()> {
+    let name = RawOsStr::new(path.as_os_str());
+    out.write_all(b"'")?;
+    for (i, part) in
+        name.as_raw_bytes().split(|&byte| byte == b'\'').enumerate()
+    {
+        // End the quotes, add an escaped quote and start new quotes
+        if i > 0 {
+            out.write_all(b"'\\''")?;
+        }
+        out.write_all(part)?;
+    }
+    out.write_all(b"'")
+}
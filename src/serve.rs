@@ -0,0 +1,281 @@
+//! A JSON-RPC 2.0 server on stdin and stdout, so that editor plugins can use
+//! rsdir to list files and to plan and apply changes to them, without a
+//! temporary file or scraping the output. Every request and response is a
+//! single line of JSON
+//!
+//! Methods:
+//! - `list` with `paths`, `recursive`, `follow` and `one_file_system` lists
+//!   the files, which `plan` and `apply` are then compared with
+//! - `plan` with `files`, the rows of the edited listing as `index` and
+//!   `name`, returns the operations without applying them. Files that are
+//!   missing from the rows are removed
+//! - `apply` with `files` applies the operations and returns them. The files
+//!   must be listed again before the next change
+
+use crate::{ListOptions, SortOrder, STDIN_PATH};
+use anyhow::{anyhow, Result};
+use rsdir::plan::Plan;
+use rsdir::plan::{self, Operation};
+use rsdir::{InputRow, OutputRow};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Used for errors of the method itself, like conflicting names
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    paths: Vec<String>,
+    #[serde(default)]
+    recursive: bool,
+    #[serde(default)]
+    follow: bool,
+    #[serde(default)]
+    one_file_system: bool,
+}
+
+#[derive(Deserialize)]
+struct ChangeParams {
+    files: Vec<Row>,
+}
+
+#[derive(Deserialize)]
+struct Row {
+    index: usize,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    index: usize,
+    name: &'a str,
+    is_dir: bool,
+}
+
+#[derive(Default)]
+struct Session {
+    /// Files of the last listing, and the directories they were listed from
+    listing: Option<(Vec<InputRow>, Vec<PathBuf>)>,
+}
+
+/// An error and its JSON-RPC error code
+struct Error(i64, anyhow::Error);
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Error {
+        Error(SERVER_ERROR, err)
+    }
+}
+
+pub fn serve(input: impl BufRead, mut output: impl Write) -> Result<()> {
+    let mut session = Session::default();
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(request) => {
+                let id = request.get("id").cloned();
+                let result = handle(&mut session, &request);
+                // Requests without an id are notifications, which aren't
+                // answered
+                let Some(id) = id else {
+                    continue;
+                };
+                response(id, result)
+            }
+            Err(err) => response(
+                Value::Null,
+                Err(Error(PARSE_ERROR, anyhow!("Invalid JSON: {err}"))),
+            ),
+        };
+        writeln!(output, "{response}")?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn response(id: Value, result: Result<Value, Error>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(Error(code, err)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": format!("{err:#}") },
+        }),
+    }
+}
+
+fn handle(session: &mut Session, request: &Value) -> Result<Value, Error> {
+    let method = request.get("method").and_then(Value::as_str);
+    let params = request.get("params").cloned().unwrap_or(json!({}));
+    match method {
+        Some("list") => list(session, parse_params(params)?),
+        Some("plan") => {
+            let (listing, roots) = listed(session)?;
+            let plan = compute_plan(listing, roots, parse_params(params)?)?;
+            Ok(json!({ "operations": describe(plan.operations()) }))
+        }
+        Some("apply") => {
+            let (listing, roots) = listed(session)?;
+            let plan = compute_plan(listing, roots, parse_params(params)?)?;
+            plan.check_permissions()?;
+            plan.check_space()?;
+            plan.apply(&plan::ApplyOptions::default())?;
+            // The names of the listing may no longer exist
+            session.listing = None;
+            Ok(json!({ "operations": describe(plan.operations()) }))
+        }
+        Some(method) => Err(Error(
+            METHOD_NOT_FOUND,
+            anyhow!("Unknown method {method:?}"),
+        )),
+        None => Err(Error(METHOD_NOT_FOUND, anyhow!("Missing method"))),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, Error> {
+    serde_json::from_value(params)
+        .map_err(|err| Error(INVALID_PARAMS, anyhow!("Invalid params: {err}")))
+}
+
+fn listed(session: &Session) -> Result<(&[InputRow], &[PathBuf]), Error> {
+    match &session.listing {
+        Some((listing, roots)) => Ok((listing, roots)),
+        None => Err(anyhow!("List the files first").into()),
+    }
+}
+
+fn list(session: &mut Session, params: ListParams) -> Result<Value, Error> {
+    // Stdin is used for the requests
+    if params.paths.iter().any(|path| path == STDIN_PATH) {
+        return Err(
+            anyhow!("Can't read paths from stdin in server mode").into()
+        );
+    }
+    let roots = crate::get_path_args(&params.paths);
+    let listing = crate::list_files(
+        roots.clone(),
+        &ListOptions {
+            recursive: params.recursive,
+            follow: params.follow,
+            one_file_system: params.one_file_system,
+            separator: '\n',
+            sort: SortOrder::Name,
+            reverse: false,
+            metadata: false,
+        },
+    )?;
+    let files = listing
+        .iter()
+        .map(|file| {
+            Ok(Entry {
+                index: file.index,
+                name: unicode_name(&file.name)?,
+                is_dir: file.is_dir,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let result = json!({ "files": files });
+    session.listing = Some((listing, roots));
+    Ok(result)
+}
+
+fn compute_plan(
+    listing: &[InputRow],
+    roots: &[PathBuf],
+    params: ChangeParams,
+) -> Result<Plan> {
+    let output = params
+        .files
+        .into_iter()
+        .map(|row| OutputRow {
+            index: row.index,
+            name: PathBuf::from(row.name),
+            cells: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    plan::compute_plan(
+        listing,
+        &output,
+        &plan::PlanOptions {
+            columns: &[],
+            copy: false,
+            expand_env: false,
+            normalize: false,
+            auto_suffix: false,
+            backup: None,
+            roots: &crate::canonical_roots(roots)?,
+            restrict: false,
+            allow_parent: false,
+            force: false,
+            recursive_delete: false,
+            parents: false,
+            allow_mount_points: false,
+            portable_names: false,
+            order: None,
+            notes: false,
+        },
+        // There is no one to ask, so conflicts are returned as errors
+        |_, _| Ok(plan::Resolution::Fail),
+    )
+}
+
+/// JSON can only contain valid Unicode
+fn unicode_name(name: &Path) -> Result<&str> {
+    name.to_str()
+        .ok_or_else(|| anyhow!("{name:?} isn't valid Unicode"))
+}
+
+fn describe(plan: &[Operation]) -> Vec<Value> {
+    plan.iter().map(describe_operation).collect()
+}
+
+pub fn describe_operation(operation: &Operation) -> Value {
+    match operation {
+        Operation::Move { from, to, is_dir } => json!({
+            "type": "move",
+            "from": from.to_string_lossy(),
+            "to": to.to_string_lossy(),
+            "is_dir": is_dir,
+        }),
+        Operation::Swap { a, b, .. } => json!({
+            "type": "swap",
+            "a": a.to_string_lossy(),
+            "b": b.to_string_lossy(),
+        }),
+        Operation::Remove { path, is_dir } => json!({
+            "type": "remove",
+            "path": path.to_string_lossy(),
+            "is_dir": is_dir,
+        }),
+        Operation::Copy { from, to, is_dir } => json!({
+            "type": "copy",
+            "from": from.to_string_lossy(),
+            "to": to.to_string_lossy(),
+            "is_dir": is_dir,
+        }),
+        Operation::Chown { path, new, .. } => json!({
+            "type": "chown",
+            "path": path.to_string_lossy(),
+            "owner": new,
+        }),
+        Operation::Touch { path, new, .. } => json!({
+            "type": "touch",
+            "path": path.to_string_lossy(),
+            "mtime": new,
+        }),
+        Operation::CreateDir { path } => json!({
+            "type": "mkdir",
+            "path": path.to_string_lossy(),
+        }),
+    }
+}
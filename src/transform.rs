@@ -0,0 +1,231 @@
+//! Changes to the names written to the temporary file, so that the edited text
+//! starts out with suggested names that only need to be reviewed
+
+use clap::ValueEnum;
+use os_str_bytes::{RawOsStr, RawOsString};
+use regex::Regex;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters that aren't allowed in names on FAT and NTFS, in addition to
+/// control characters
+const ILLEGAL_CHARS: &[u8] = b"<>:\"\\|?*";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SanitizeRule {
+    /// Replace spaces with underscores
+    Spaces,
+    /// Remove characters that aren't allowed on FAT and NTFS, and control
+    /// characters
+    Illegal,
+    /// Remove dots and spaces at the end of names, which Windows ignores
+    Dots,
+    /// All of the above
+    All,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Case {
+    /// all lowercase
+    Lower,
+    /// ALL UPPERCASE
+    Upper,
+    /// The First Letter Of Each Word In Uppercase. The extension is kept as
+    /// is
+    Title,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    /// Composed characters, used by most systems
+    Nfc,
+    /// Decomposed characters, used by HFS+
+    Nfd,
+}
+
+/// Replacement of the matches of a regex in names. `$1` or `${name}` in the
+/// replacement is the text of a capture group
+#[derive(Debug)]
+pub struct Replace {
+    pub regex: Regex,
+    pub replacement: String,
+}
+
+impl Replace {
+    /// Returns the name with the matches highlighted, and the new name with
+    /// the replaced text highlighted, or `None` if nothing matches
+    pub fn preview(&self, name: &str, color: bool) -> Option<(String, String)> {
+        let paint = |out: &mut String, text: &str, color_code: &str| {
+            if color {
+                out.push_str(&format!("{color_code}{text}{RESET}"));
+            } else {
+                out.push_str(text);
+            }
+        };
+
+        let (mut old, mut new) = (String::new(), String::new());
+        let mut end = None;
+        for captures in self.regex.captures_iter(name) {
+            let matched = captures.get(0).unwrap();
+            let unchanged = &name[end.unwrap_or(0)..matched.start()];
+            old.push_str(unchanged);
+            new.push_str(unchanged);
+            let mut replaced = String::new();
+            captures.expand(&self.replacement, &mut replaced);
+            paint(&mut old, matched.as_str(), RED);
+            paint(&mut new, &replaced, GREEN);
+            end = Some(matched.end());
+        }
+        let rest = &name[end?..];
+        old.push_str(rest);
+        new.push_str(rest);
+        Some((old, new))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Transform {
+    /// Applied before the other changes
+    pub replace: Option<Replace>,
+    pub sanitize: Vec<SanitizeRule>,
+    pub case: Option<Case>,
+    pub normalize: Option<Normalization>,
+    /// Added to the start of names
+    pub prefix: Option<String>,
+    /// Added to the end of names, before the extension of files
+    pub suffix: Option<String>,
+}
+
+impl Transform {
+    /// Whether the names are changed at all
+    pub fn is_empty(&self) -> bool {
+        self.replace.is_none()
+            && self.sanitize.is_empty()
+            && self.case.is_none()
+            && self.normalize.is_none()
+            && self.prefix.is_none()
+            && self.suffix.is_none()
+    }
+
+    fn has(&self, rule: SanitizeRule) -> bool {
+        self.sanitize
+            .iter()
+            .any(|r| *r == rule || *r == SanitizeRule::All)
+    }
+
+    /// Returns the suggested name for a file. Only the last component of the
+    /// path is changed
+    pub fn apply(&self, path: &Path, is_dir: bool) -> PathBuf {
+        let path = self.apply_rules(path);
+        if self.prefix.is_none() && self.suffix.is_none() {
+            return path;
+        }
+        let Some(name) = path.file_name() else {
+            return path;
+        };
+        let (stem, extension) = match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(extension)) if !is_dir => (stem, Some(extension)),
+            _ => (name, None),
+        };
+        let mut new_name = OsString::from(self.prefix.as_deref().unwrap_or(""));
+        new_name.push(stem);
+        new_name.push(self.suffix.as_deref().unwrap_or(""));
+        if let Some(extension) = extension {
+            new_name.push(".");
+            new_name.push(extension);
+        }
+        path.with_file_name(new_name)
+    }
+
+    fn apply_rules(&self, path: &Path) -> PathBuf {
+        let Some(mut name) = path.file_name() else {
+            return path.to_owned();
+        };
+        // Names that aren't valid Unicode can't be matched
+        let replaced =
+            self.replace
+                .as_ref()
+                .zip(name.to_str())
+                .map(|(replace, name)| {
+                    replace.regex.replace_all(name, &replace.replacement)
+                });
+        if let Some(replaced) = &replaced {
+            name = OsStr::new(replaced.as_ref());
+        }
+        let mut bytes = RawOsStr::new(name).as_raw_bytes().to_vec();
+
+        if self.has(SanitizeRule::Spaces) {
+            for byte in &mut bytes {
+                if *byte == b' ' {
+                    *byte = b'_';
+                }
+            }
+        }
+        if self.has(SanitizeRule::Illegal) {
+            bytes.retain(|byte| {
+                !ILLEGAL_CHARS.contains(byte) && !byte.is_ascii_control()
+            });
+        }
+        if self.has(SanitizeRule::Dots) {
+            while matches!(bytes.last(), Some(b'.' | b' ')) {
+                bytes.pop();
+            }
+        }
+
+        // A name can't be removed entirely, or refer to another directory
+        if matches!(bytes.as_slice(), b"" | b"." | b"..") {
+            return path.to_owned();
+        }
+        // Only ASCII characters have been replaced or removed, so the encoding
+        // is still valid
+        let name = RawOsString::assert_from_raw_vec(bytes).into_os_string();
+        // Names that aren't valid Unicode have no case or normalization to
+        // change
+        let Some(mut name_str) = name.to_str().map(str::to_owned) else {
+            return path.with_file_name(name);
+        };
+        if let Some(case) = self.case {
+            name_str = change_case(&name_str, case);
+        }
+        match self.normalize {
+            Some(Normalization::Nfc) => name_str = name_str.nfc().collect(),
+            Some(Normalization::Nfd) => name_str = name_str.nfd().collect(),
+            None => {}
+        }
+        path.with_file_name(name_str)
+    }
+}
+
+fn change_case(name: &str, case: Case) -> String {
+    match case {
+        Case::Lower => name.to_lowercase(),
+        Case::Upper => name.to_uppercase(),
+        Case::Title => {
+            let (stem, extension) = match name.rsplit_once('.') {
+                Some((stem, extension)) if !stem.is_empty() => {
+                    (stem, Some(extension))
+                }
+                _ => (name, None),
+            };
+            let mut title = String::new();
+            let mut word_start = true;
+            for c in stem.chars() {
+                if word_start {
+                    title.extend(c.to_uppercase());
+                } else {
+                    title.extend(c.to_lowercase());
+                }
+                word_start = !c.is_alphanumeric();
+            }
+            if let Some(extension) = extension {
+                title.push('.');
+                title.push_str(extension);
+            }
+            title
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Expansion of environment variables in names, written as `$NAME` or
+//! `${NAME}`. A `$` that isn't followed by a variable name is kept as is
+
+use anyhow::{bail, Result};
+use os_str_bytes::{RawOsStr, RawOsString};
+use std::env;
+
+pub fn expand(name: &RawOsStr) -> Result<RawOsString> {
+    let bytes = name.as_raw_bytes();
+    let mut expanded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'$' {
+            expanded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let (var, next) = if bytes.get(i + 1) == Some(&b'{') {
+            let Some(len) = bytes[i + 2..].iter().position(|&b| b == b'}')
+            else {
+                bail!("Unfinished variable name");
+            };
+            let var = &bytes[i + 2..i + 2 + len];
+            if !is_var_name(var) {
+                bail!(
+                    "Invalid variable name {:?}",
+                    String::from_utf8_lossy(var)
+                );
+            }
+            (var, i + 3 + len)
+        } else {
+            let len = bytes[i + 1..]
+                .iter()
+                .position(|&b| !(b.is_ascii_alphanumeric() || b == b'_'))
+                .unwrap_or(bytes.len() - i - 1);
+            let var = &bytes[i + 1..i + 1 + len];
+            if !is_var_name(var) {
+                expanded.push(b'$');
+                i += 1;
+                continue;
+            }
+            (var, i + 1 + len)
+        };
+
+        // Variable names are ASCII
+        let var = std::str::from_utf8(var).unwrap();
+        let Some(value) = env::var_os(var) else {
+            bail!("Environment variable {var} is not set");
+        };
+        expanded.extend_from_slice(RawOsStr::new(&value).as_raw_bytes());
+        i = next;
+    }
+    // Only whole values of variables, which are valid strings, have been
+    // inserted, so the encoding is still valid
+    Ok(RawOsString::assert_from_raw_vec(expanded))
+}
+
+fn is_var_name(var: &[u8]) -> bool {
+    var.first()
+        .is_some_and(|b| b.is_ascii_alphabetic() || *b == b'_')
+        && var.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'_')
+}
@@ -0,0 +1,90 @@
+// Tests of the library, which computes and applies the changes without going
+// through an editor
+
+use rsdir::{InputRow, Operation, OutputRow};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn listed(dir: &Path, names: &[&str]) -> Vec<InputRow> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let name = dir.join(name);
+            fs::write(&name, "").unwrap();
+            InputRow {
+                index: i + 1,
+                section: 0,
+                is_dir: false,
+                metadata: Some(fs::symlink_metadata(&name).unwrap()),
+                ino: None,
+                name,
+            }
+        })
+        .collect()
+}
+
+fn row(index: usize, name: PathBuf) -> OutputRow {
+    OutputRow {
+        index,
+        name,
+        cells: Vec::new(),
+    }
+}
+
+#[test]
+/// Tests that missing rows are removed and changed names are moved
+fn compute_plan() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = listed(dir.path(), &["a", "b"]);
+    let output = [row(2, dir.path().join("c"))];
+
+    let plan = rsdir::compute_plan(&input, &output).unwrap();
+    assert!(matches!(
+        plan.operations(),
+        [
+            Operation::Remove { path, is_dir: false },
+            Operation::Move { from, to, is_dir: false },
+        ] if *path == input[0].name && *from == input[1].name && *to == output[0].name
+    ));
+
+    plan.apply(&Default::default()).unwrap();
+    assert!(!input[0].name.exists());
+    assert!(!input[1].name.exists());
+    assert!(output[0].name.exists());
+}
+
+#[test]
+/// Tests that two rows with the same new name are an error
+fn compute_plan_conflict() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = listed(dir.path(), &["a", "b"]);
+    let output = [row(1, dir.path().join("c")), row(2, dir.path().join("c"))];
+
+    assert!(rsdir::compute_plan(&input, &output).is_err());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that a restricted sandbox renames files beneath its directory, and
+/// refuses names outside of it
+fn sandbox() {
+    let dir = tempfile::tempdir().unwrap();
+    let other = tempfile::tempdir().unwrap();
+    listed(dir.path(), &["a"]);
+
+    let Some(sandbox) =
+        rsdir::Sandbox::open(&[dir.path().to_owned()], true).unwrap()
+    else {
+        return;
+    };
+    sandbox
+        .rename(&dir.path().join("a"), &dir.path().join("b"), false)
+        .unwrap();
+    assert!(dir.path().join("b").exists());
+    let err = sandbox
+        .rename(&dir.path().join("b"), &other.path().join("b"), false)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert!(dir.path().join("b").exists());
+}
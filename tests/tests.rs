@@ -7,12 +7,14 @@
 // files.
 
 #[cfg(target_os = "linux")]
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
 #[cfg(target_os = "linux")]
 use std::os::unix::prelude::OsStringExt;
 use std::path::PathBuf;
 use std::process;
 use std::process::Command;
+use std::time::{Duration, SystemTime};
 
 mod utils;
 
@@ -96,16 +98,11 @@ fn unknown_flag() {
 }
 
 #[test]
-/// Tests the default editor in case the EDITOR environment variables isn't set
-/// by setting the PATH to the `tests` directory, which contains a `vi` shell
-/// script
-fn default_editor() {
+fn completions_subcommand() {
     let bin_path = utils::get_bin_path();
-    let tests_path = utils::get_tests_path();
 
     let output = Command::new(bin_path)
-        .env("PATH", &tests_path)
-        .env_remove("EDITOR")
+        .args(["completions", "bash"])
         .output()
         .unwrap();
 
@@ -118,11 +115,122 @@ fn default_editor() {
         .trim_end()
         .to_owned();
 
+    assert!(stdout.contains("--verbose"));
+    assert!(stdout.contains("--keep-buffer"));
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn manpage_subcommand() {
+    let bin_path = utils::get_bin_path();
+
+    let output = Command::new(bin_path).arg("manpage").output().unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert!(stdout.starts_with(".ie"));
+    assert!(stdout.contains(".TH rsdir 1"));
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests the default editor in case the EDITOR environment variables isn't set
+/// by setting the PATH to the `tests` directory, which contains a `vi` shell
+/// script. vi needs a terminal, so it's run in one
+fn default_editor() {
+    use std::io::Read;
+    use std::os::unix::process::CommandExt;
+
+    let bin_path = utils::get_bin_path();
+    let tests_path = utils::get_tests_path();
+
+    let (mut master, slave) = utils::open_pty();
+    let mut command = Command::new(bin_path);
+    command
+        .env("PATH", &tests_path)
+        .env_remove("EDITOR")
+        .env_remove("VISUAL")
+        .env_remove("RSDIR_EDITOR")
+        .stdin(slave.try_clone().unwrap())
+        .stdout(slave);
+    // SAFETY: setsid and ioctl are async-signal-safe. The terminal on stdin
+    // becomes the controlling terminal, which the editor is connected to
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let output = command.output().unwrap();
+    // The command holds the terminal open until it's dropped
+    drop(command);
+    // Reading fails once the output is read, since the terminal is closed
+    let mut stdout = Vec::new();
+    let _ = master.read_to_end(&mut stdout);
+
+    let stdout = String::from_utf8(stdout).unwrap().trim_end().to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
     assert_eq!(stdout, "fake vi");
     assert_eq!(stderr, "");
     assert!(output.status.success());
 }
 
+#[test]
+#[cfg(unix)]
+/// Tests that the default editor is refused when there is no terminal, which
+/// vi needs
+fn default_editor_no_terminal() {
+    use std::os::unix::process::CommandExt;
+
+    let bin_path = utils::get_bin_path();
+    let tests_path = utils::get_tests_path();
+
+    let mut command = Command::new(bin_path);
+    command
+        .env("PATH", &tests_path)
+        .env_remove("EDITOR")
+        .env_remove("VISUAL")
+        .env_remove("RSDIR_EDITOR");
+    // SAFETY: setsid is async-signal-safe. It leaves the controlling terminal
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let output = command.output().unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert_eq!(stdout, "");
+    assert!(stderr.starts_with("Error: Editor \"vi\" needs a terminal"));
+    assert!(!output.status.success());
+}
+
 #[test]
 fn moves_dir() {
     let test_dir = utils::create_test_dir().unwrap();
@@ -142,22 +250,36 @@ fn moves_dir() {
 }
 
 #[test]
+#[cfg(unix)]
+/// Tests an error when the move is applied, after the plan was checked. The
+/// file is moved into a file, which has the permissions of a directory that
+/// can be written to
 fn move_file_error() {
+    use std::os::unix::fs::PermissionsExt;
+
     let test_dir = utils::create_test_dir().unwrap();
-    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "qux"]).unwrap();
+    std::fs::set_permissions(
+        test_dir.path().join("qux"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
     let output = utils::run_rsdir(
         &test_dir,
-        "s/.\\/baz/\\/non-existent\n\
+        "1s|./baz|./qux/x|\n\
          w\n\
          q",
         true,
     )
     .unwrap();
-    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("qux", Some("qux"))],
+    );
     assert_eq!(output.stdout, "");
     assert!(output.stderr.starts_with(
         "\
-        Error: Error moving file \"./baz\" to \"/non-existent\"
+        Error: Error moving file \"./baz\" to \"./qux/x\"
 
 Caused by:"
     ));
@@ -165,12 +287,13 @@ Caused by:"
 }
 
 #[test]
+/// Tests an error when the move is applied, after the plan was checked
 fn move_dir_error() {
     let test_dir = utils::create_test_dir().unwrap();
     utils::create_test_files(&test_dir, vec!["baz/"]).unwrap();
     let output = utils::run_rsdir(
         &test_dir,
-        "s/.\\/baz/\\/non-existent\n\
+        "1s|./baz|./baz/sub|\n\
          w\n\
          q",
         true,
@@ -180,13 +303,89 @@ fn move_dir_error() {
     assert_eq!(output.stdout, "");
     assert!(output.stderr.starts_with(
         "\
-        Error: Error moving directory \"./baz\" to \"/non-existent/\"
+        Error: Error moving directory \"./baz\" to \"./baz/sub/\"
 
 Caused by:"
     ));
     assert!(!output.status.success());
 }
 
+#[test]
+#[cfg(unix)]
+/// Tests that moving a file into a directory without write permission fails
+/// before it's applied
+fn move_file_not_writable() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "locked/"]).unwrap();
+    let locked = test_dir.path().join("locked");
+    let set_mode = |mode| {
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(mode))
+            .unwrap()
+    };
+
+    set_mode(0o555);
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1s|./baz|./locked/baz|\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    set_mode(0o755);
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("locked/", None)],
+    );
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "\
+        Error: These changes would fail without write permission:
+Move file \"./baz\" to \"./locked/baz\": \"./locked\" isn't writable"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that moving a directory into a directory without write permission
+/// fails before it's applied
+fn move_dir_not_writable() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz/", "locked/"]).unwrap();
+    let locked = test_dir.path().join("locked");
+    let set_mode = |mode| {
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(mode))
+            .unwrap()
+    };
+
+    set_mode(0o555);
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1s|./baz|./locked/baz|\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    set_mode(0o755);
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz/", None), ("locked/", None)],
+    );
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "\
+        Error: These changes would fail without write permission:
+Move directory \"./baz\" to \"./locked/baz/\": \"./locked\" isn't writable"
+    ));
+    assert!(!output.status.success());
+}
+
 #[test]
 fn moves_file() {
     let test_dir = utils::create_test_dir().unwrap();
@@ -258,116 +457,128 @@ fn tmp_file_content() {
     );
     assert_eq!(
         stdout,
-        "    1 ./baz
-    2 ./dir/
-    3 ./lol
-    4 ./xox"
+        "1 ./baz
+2 ./dir/
+3 ./lol
+4 ./xox"
     );
     assert_eq!(stderr, "");
     assert!(output.status.success());
 }
 
 #[test]
-fn deletes_dir() {
+/// Checks that the index column is as wide as the largest index
+fn index_width() {
     let test_dir = utils::create_test_dir().unwrap();
-    utils::create_test_files(&test_dir, vec!["foo/"]).unwrap();
-    let output = utils::run_rsdir(
-        &test_dir,
-        "1d\n\
-         w\n\
-         q",
-        true,
-    )
-    .unwrap();
-    utils::assert_test_files(&test_dir, Vec::<(&str, Option<&str>)>::new());
-    assert_eq!(output.stdout, "Removed directory \"./foo\"");
-    assert_eq!(output.stderr, "");
-    assert!(output.status.success());
+    let names = (0..10).map(|i| format!("f{i}")).collect::<Vec<_>>();
+    utils::create_test_files(&test_dir, names).unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let run = |args: &[&str]| {
+        let output = Command::new(&bin_path)
+            .current_dir(&test_dir)
+            .args(args)
+            .env("EDITOR", "cat")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let stdout = run(&[]);
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], " 1 ./f0");
+    assert_eq!(lines[9], "10 ./f9");
+
+    let stdout = run(&["--pad-index"]);
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "01 ./f0");
+    assert_eq!(lines[9], "10 ./f9");
 }
 
 #[test]
-fn deletes_file() {
+fn no_index() {
     let test_dir = utils::create_test_dir().unwrap();
-    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
-    let output = utils::run_rsdir(
+    utils::create_test_files(&test_dir, vec!["baz", "foo", "lol"]).unwrap();
+    let output = utils::run_rsdir_with_args(
         &test_dir,
-        "1d\n\
+        "1s/baz/boop\n\
+         2s/.*//\n\
          w\n\
          q",
-        true,
+        &["--verbose", "--no-index"],
     )
     .unwrap();
-    utils::assert_test_files(&test_dir, Vec::<(&str, Option<&str>)>::new());
-    assert_eq!(output.stdout, "Removed file \"./baz\"");
+    utils::assert_test_files(
+        &test_dir,
+        vec![("boop", Some("baz")), ("lol", Some("lol"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./baz\" to \"./boop\"
+Removed file \"./foo\""
+    );
     assert_eq!(output.stderr, "");
     assert!(output.status.success());
 }
 
 #[test]
-fn delete_dir_error() {
-    let output = utils::run_rsdir(
-        "/",
-        "/dev\n\
-         d\n\
+fn no_index_row_count() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\n\
          w\n\
          q",
-        true,
+        &["--no-index"],
     )
     .unwrap();
-    assert_eq!(output.stdout, "");
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("foo", Some("foo"))],
+    );
     assert!(output.stderr.starts_with(
-        "\
-        Error: Error deleting directory \"./dev\"
-
-Caused by:"
+        "Error: Expected 2 rows but found 1. Empty a row instead of deleting \
+         it to remove a file\n"
     ));
     assert!(!output.status.success());
 }
 
 #[test]
-fn delete_file_error() {
-    let output = utils::run_rsdir(
-        "/dev",
-        "/null\n\
-         d\n\
+fn tab_separator() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/.\\/baz/ boop \n\
          w\n\
          q",
-        true,
+        &["--verbose", "--separator", "tab", "--size"],
     )
     .unwrap();
-    assert_eq!(output.stdout, "");
-    assert!(output.stderr.starts_with(
-        "\
-        Error: Error deleting file \"./null\"
-
-Caused by:"
-    ));
-    assert!(!output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![(" boop ", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert_eq!(output.stdout, "Moved file \"./baz\" to \" boop \"");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
 }
 
 #[test]
-fn multiple_args() {
-    let test_dir1 = utils::create_test_dir().unwrap();
-    let test_dir2 = utils::create_test_dir().unwrap();
-    utils::create_test_files(&test_dir1, vec!["baz", "bop"]).unwrap();
-    utils::create_test_files(&test_dir2, vec!["foo", "pop"]).unwrap();
+fn size_column() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    std::fs::write(test_dir.path().join("big"), vec![0; 2048]).unwrap();
 
     let bin_path = utils::get_bin_path();
-    let ed_path = utils::get_script_path();
 
     let output = Command::new(bin_path)
-        .args([test_dir1.path(), test_dir2.path()])
-        .env("EDITOR", "/non-existent")
-        .env(
-            "ED_SCRIPT",
-            "/baz\n\
-             d\n\
-             /foo\n\
-             d\n\
-             w\n\
-             q",
-        )
-        .env("EDITOR", ed_path)
+        .current_dir(&test_dir)
+        .arg("--size")
+        .env("EDITOR", "cat")
         .output()
         .unwrap();
 
@@ -380,83 +591,112 @@ fn multiple_args() {
         .trim_end()
         .to_owned();
 
-    utils::assert_test_files(&test_dir1, vec![("bop", Some("bop"))]);
-    utils::assert_test_files(&test_dir2, vec![("pop", Some("pop"))]);
-    assert_eq!(stdout, "");
+    assert_eq!(
+        stdout,
+        "1    3 ./baz
+2 2.0K ./big"
+    );
     assert_eq!(stderr, "");
     assert!(output.status.success());
 }
 
 #[test]
-fn unknown_index() {
+#[cfg(unix)]
+/// Tests that a listing of many files, which is written and parsed row by
+/// row, lines up the columns of every row and applies all of them
+fn many_files() {
     let test_dir = utils::create_test_dir().unwrap();
-    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
-    let output = utils::run_rsdir(
+    let tmp_dir = utils::create_test_dir().unwrap();
+    let names = (0..2_000).map(|i| format!("f{i}")).collect::<Vec<_>>();
+    utils::create_test_files(&test_dir, names.clone()).unwrap();
+    // Listed first, but widens the size column of every row after it
+    std::fs::write(test_dir.path().join("big"), vec![0; 2048]).unwrap();
+    let listing_path = tmp_dir.path().join("listing");
+
+    let output = utils::run_rsdir_with_args(
         &test_dir,
-        "s/1/2\n\
-         w\n\
-         q",
-        true,
+        "",
+        &[
+            "-y",
+            "--size",
+            "--dump-listing",
+            listing_path.to_str().unwrap(),
+        ],
     )
     .unwrap();
-    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
-    assert_eq!(output.stdout, "");
-    assert_eq!(output.stderr, "Error: Unknown index 2 at row 0");
-    assert!(!output.status.success());
+    assert!(output.status.success());
+    let listing = std::fs::read_to_string(&listing_path).unwrap();
+    assert_eq!(listing.lines().count(), 2_001);
+    assert!(listing.lines().all(|row| row.find("./") == Some(10)));
+
+    let editor = utils::create_editor_script(
+        &tmp_dir,
+        "sed -i -e 's|/f|/g|' -e '/big$/d' \"$1\"\n",
+    );
+    let output = Command::new(utils::get_bin_path())
+        .current_dir(&test_dir)
+        .args(["-y", "--size"])
+        .env("EDITOR", &editor)
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+    assert!(output.status.success());
+    let mut files = std::fs::read_dir(&test_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort();
+    let mut expected = (0..2_000).map(|i| format!("g{i}")).collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(files, expected);
 }
 
 #[test]
-fn invalid_index() {
+/// Tests that hard links to the same file have the same inode number
+fn inode_column() {
     let test_dir = utils::create_test_dir().unwrap();
-    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
-    let output = utils::run_rsdir(
-        &test_dir,
-        "s/1/x\n\
-         w\n\
-         q",
-        true,
-    )
-    .unwrap();
-    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
-    assert_eq!(output.stdout, "");
-    assert_eq!(output.stderr, "Error: Invalid index \"x\" at row 0");
-    assert!(!output.status.success());
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    std::fs::hard_link(test_dir.path().join("a"), test_dir.path().join("c"))
+        .unwrap();
+
+    let output = Command::new(utils::get_bin_path())
+        .current_dir(&test_dir)
+        .arg("--inode")
+        .env("EDITOR", "cat")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let inodes = stdout
+        .lines()
+        .map(|line| line.split_whitespace().nth(1).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(inodes.len(), 3);
+    assert_eq!(inodes[0], inodes[2]);
+    assert_ne!(inodes[0], inodes[1]);
+    assert!(output.status.success());
 }
 
 #[test]
-fn editor_failure() {
+fn mtime_column() {
     let test_dir = utils::create_test_dir().unwrap();
     utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
-    let ed_path = utils::get_script_path();
-    let output = utils::run_rsdir(
-        &test_dir,
-        // Searching for a non-existent string will cause ed to return an error
-        "s/missing/never\n\
-         w\n\
-         q",
-        true,
-    )
-    .unwrap();
-    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
-    assert_eq!(output.stdout, "");
-    assert!(output.stderr.starts_with(&format!(
-        "Error: Editor {ed_path:?} returned error code "
-    )));
-    assert!(!output.status.success());
-}
+    // 2001-09-09 01:46:40 UTC
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(test_dir.path().join("baz"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
 
-#[test]
-/// Tests the error handling for when the editor is killed by a signal, in which
-/// case no exit code will be returned
-/// Uses the `kill.sh` script which kills itself
-fn editor_killed() {
-    let test_dir = utils::create_test_dir().unwrap();
     let bin_path = utils::get_bin_path();
-    let editor_path = utils::get_tests_path().join("./kill.sh");
 
     let output = Command::new(bin_path)
         .current_dir(&test_dir)
-        .env("EDITOR", &editor_path)
+        .args(["--size", "--mtime"])
+        .env("EDITOR", "cat")
+        .env("TZ", "UTC")
         .output()
         .unwrap();
 
@@ -469,24 +709,41 @@ fn editor_killed() {
         .trim_end()
         .to_owned();
 
-    assert_eq!(stdout, "");
-    assert_eq!(
-        stderr,
-        format!("Error: Editor {editor_path:?} returned an error")
-    );
-    assert!(!output.status.success());
+    assert_eq!(stdout, "1 3 2001-09-09 01:46 ./baz");
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
 }
 
 #[test]
-fn missing_editor() {
+/// Tests that editing the column shown with `--touch` changes the modification
+/// time
+fn touch_column() {
     let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
     utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    // 2001-09-09 01:46:40 UTC
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(test_dir.path().join("baz"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
 
     let bin_path = utils::get_bin_path();
 
     let output = Command::new(bin_path)
         .current_dir(&test_dir)
-        .env("EDITOR", "/non-existent")
+        .args(["--verbose", "--touch", "--tmpdir"])
+        .arg(tmp_dir.path())
+        .env("EDITOR", utils::get_script_path())
+        .env(
+            "ED_SCRIPT",
+            "s/2001-09-09 01:46:40/2002-01-02 03:04:05/\n\
+             w\n\
+             q",
+        )
+        .env("TZ", "UTC")
         .output()
         .unwrap();
 
@@ -498,30 +755,34 @@ fn missing_editor() {
         .unwrap()
         .trim_end()
         .to_owned();
-    println!("status: {}", output.status);
-    println!("stdout: {stdout}");
-    println!("stderr: {stderr}");
-
-    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
-    assert_eq!(stdout, "");
-    assert!(stderr.starts_with(
-        "\
-Error: Failed to open editor \"/non-existent\"
 
-Caused by:"
-    ));
-    assert!(!output.status.success());
+    assert_eq!(
+        stdout,
+        "Changed modification time of file \"./baz\" to 2002-01-02 03:04:05"
+    );
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::metadata(test_dir.path().join("baz"))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_009_940_645)
+    );
 }
 
 #[test]
-fn missing_path() {
+#[cfg(unix)]
+fn owner_column() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+
     let bin_path = utils::get_bin_path();
-    let ed_path = utils::get_script_path();
 
     let output = Command::new(bin_path)
-        .arg("/non-existent")
-        .env("ED_SCRIPT", "q")
-        .env("EDITOR", ed_path)
+        .current_dir(&test_dir)
+        .arg("--owner")
+        .env("EDITOR", "cat")
         .output()
         .unwrap();
 
@@ -534,29 +795,45 @@ fn missing_path() {
         .trim_end()
         .to_owned();
 
-    assert_eq!(stdout, "");
-    assert!(stderr.starts_with(
-        "\
-Error: Couldn't list files in \"/non-existent\"
+    // Compare with the output of ls, which resolves the names the same way
+    let ls_output = Command::new("ls")
+        .current_dir(&test_dir)
+        .args(["-l", "baz"])
+        .output()
+        .unwrap();
+    let ls_stdout = String::from_utf8(ls_output.stdout).unwrap();
+    let ls_columns = ls_stdout.split_whitespace().collect::<Vec<_>>();
 
-Caused by:"
-    ));
-    assert!(!output.status.success());
+    assert_eq!(
+        stdout,
+        format!("1 {} {} ./baz", ls_columns[2], ls_columns[3])
+    );
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
 }
 
 #[test]
-/// Tests the error handling when failing to create a temporary file by setting
-/// `TMPDIR` to a non-existent path
-fn create_tmpfile_error() {
+#[cfg(unix)]
+/// Tests that `-l` shows the same columns as `ls -l`
+fn long_listing() {
     let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    // 2001-09-09 01:46:40 UTC
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(test_dir.path().join("baz"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+
     let bin_path = utils::get_bin_path();
-    let ed_path = utils::get_script_path();
 
     let output = Command::new(bin_path)
         .current_dir(&test_dir)
-        .env("TMPDIR", "/non-existent")
-        .env("ED_SCRIPT", "q")
-        .env("EDITOR", ed_path)
+        .arg("-l")
+        .env("EDITOR", "cat")
+        .env("TZ", "UTC")
         .output()
         .unwrap();
 
@@ -564,88 +841,4550 @@ fn create_tmpfile_error() {
         .unwrap()
         .trim_end()
         .to_owned();
-    let stderr = String::from_utf8(output.stderr)
-        .unwrap()
-        .trim_end()
-        .to_owned();
 
-    assert_eq!(stdout, "");
-    assert!(stderr.starts_with(
-        "\
-Error: Failed to create temporary file
+    let ls_output = Command::new("ls")
+        .current_dir(&test_dir)
+        .args(["-l", "baz"])
+        .output()
+        .unwrap();
+    let ls_stdout = String::from_utf8(ls_output.stdout).unwrap();
+    let ls_columns = ls_stdout.split_whitespace().collect::<Vec<_>>();
 
-Caused by:
-    No such file or directory (os error 2) at path \"/non-existent/"
+    assert_eq!(
+        stdout,
+        format!(
+            "1 {} 1 {} {} 3 2001-09-09 01:46 ./baz",
+            // Leave out the marker for extended attributes and ACLs
+            &ls_columns[0][..10],
+            ls_columns[2],
+            ls_columns[3]
+        )
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that editing the column shown with `--chown` changes the owner and
+/// group, and that changing them to root fails without privileges
+fn chown_column() {
+    use std::os::unix::fs::MetadataExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    let metadata = std::fs::metadata(test_dir.path().join("baz")).unwrap();
+    let ownership = format!("{}:{}", metadata.uid(), metadata.gid());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        &format!(
+            "s/[^ ]*:[^ ]*/{ownership}/\n\
+             w\n\
+             q"
+        ),
+        &["--verbose", "--chown"],
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(
+        output.stdout,
+        format!("Changed owner of file \"./baz\" to {ownership}")
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "s/[^ ]*:[^ ]*/0:0/\n\
+         s/baz/boop/\n\
+         w\n\
+         q",
+        &["--chown"],
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert!(output.stderr.starts_with(
+        "Error: Error changing owner of file \"./baz\" to 0:0\n\
+         \n\
+         Caused by:\n    \
+         0: Changing the owner usually requires root\n    \
+         1: Operation not permitted (os error 1)"
+    ));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "s/[^ ]*:[^ ]*/nosuchuser:0/\n\
+         w\n\
+         q",
+        &["--chown"],
+    )
+    .unwrap();
+    assert!(output.stderr.starts_with(
+        "Error: Invalid owner for \"./baz\"\n\
+         \n\
+         Caused by:\n    \
+         Unknown user \"nosuchuser\""
     ));
     assert!(!output.status.success());
 }
 
 #[test]
-fn non_verbose() {
+/// Checks that columns are ignored when parsing the edited text
+fn moves_file_with_columns() {
     let test_dir = utils::create_test_dir().unwrap();
     utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
-    let output = utils::run_rsdir(
+    let output = utils::run_rsdir_with_args(
         &test_dir,
-        "s/baz/boop\n\
+        "s/ 3 / 123 /\n\
+         s/baz/boop\n\
          w\n\
          q",
-        false,
+        &["--verbose", "--size", "--mtime"],
     )
     .unwrap();
     utils::assert_test_files(&test_dir, vec![("boop", Some("baz"))]);
-    assert_eq!(output.stdout, "");
+    assert_eq!(output.stdout, "Moved file \"./baz\" to \"./boop\"");
     assert_eq!(output.stderr, "");
     assert!(output.status.success());
 }
 
 #[test]
-fn removes_temp_file() {
-    let bin_path = utils::get_bin_path();
-
-    let file_path = String::from_utf8(
-        process::Command::new(bin_path)
-            .env("EDITOR", "echo")
-            .output()
-            .unwrap()
-            .stdout,
+fn deletes_dir() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["foo/"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1d\n\
+         w\n\
+         q",
+        true,
     )
     .unwrap();
-    assert!(!PathBuf::from(file_path).exists());
+    utils::assert_test_files(&test_dir, Vec::<(&str, Option<&str>)>::new());
+    assert_eq!(output.stdout, "Removed directory \"./foo\"");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
 }
 
 #[test]
-#[cfg(target_os = "linux")]
-/// Tests that paths that are not valid Unicode are handled correctly
-/// Does not apply to macOS(HFS+ or APFS) where paths are always valid Unicode
-fn byte_path_linux() {
-    let file_path_bytes = vec![
-        97, 0o017, 0o254, 0o001, 0o103, 0o326, 0o144, 0o203, 0o261, 0o154,
-        0o065, 0o053, 0o167,
-    ];
-    // Ensure that the test path is invalid UTF-8
-    String::from_utf8(file_path_bytes.clone()).unwrap_err();
-    let file_path_os: OsString = OsStringExt::from_vec(file_path_bytes);
-
+fn deletes_file() {
     let test_dir = utils::create_test_dir().unwrap();
-    let path_buf = test_dir.path().join(PathBuf::from(file_path_os));
-    std::fs::create_dir_all(path_buf).unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
     let output = utils::run_rsdir(
         &test_dir,
-        "d\n\
-         i\n\
-         1 ./baz\n\
-         .\n\
+        "1d\n\
          w\n\
          q",
         true,
     )
     .unwrap();
-    utils::assert_test_files(&test_dir, vec![("baz", None)]);
-    assert_eq!(
-        output.stdout,
-        "Moved directory \
-        \"./a\\u{f}\\xAC\\u{1}C\\xD6d\\x83\\xB1l5+w\" to \"./baz\""
-    );
+    utils::assert_test_files(&test_dir, Vec::<(&str, Option<&str>)>::new());
+    assert_eq!(output.stdout, "Removed file \"./baz\"");
     assert_eq!(output.stderr, "");
     assert!(output.status.success());
 }
+
+#[test]
+#[cfg(unix)]
+/// Tests an error when the removal is applied, after the plan was checked. The
+/// directory has a subdirectory without write permission
+fn delete_dir_error() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz/", "baz/sub/", "baz/sub/a"])
+        .unwrap();
+    let sub = test_dir.path().join("baz/sub");
+    let set_mode = |mode| {
+        std::fs::set_permissions(&sub, std::fs::Permissions::from_mode(mode))
+            .unwrap()
+    };
+
+    set_mode(0o555);
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\n\
+         w\n\
+         q",
+        &["--recursive-delete"],
+    )
+    .unwrap();
+    set_mode(0o755);
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "\
+        Error: Error deleting directory \"./baz\"
+
+Caused by:"
+    ));
+    assert!(!output.status.success());
+    assert!(test_dir.path().join("baz/sub/a").exists());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that removing a directory from a directory without write permission
+/// fails before it's applied
+fn delete_dir_not_writable() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["locked/", "locked/baz/"])
+        .unwrap();
+    let locked = test_dir.path().join("locked");
+    let set_mode = |mode| {
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(mode))
+            .unwrap()
+    };
+
+    set_mode(0o555);
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\n\
+         w\n\
+         q",
+        &["locked"],
+    )
+    .unwrap();
+    set_mode(0o755);
+    utils::assert_test_files(
+        &test_dir,
+        vec![("locked/", None), ("locked/baz/", None)],
+    );
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "\
+        Error: These changes would fail without write permission:
+Remove directory \"locked/baz\": \"locked\" isn't writable"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that removing a mount point fails before anything is removed
+fn delete_mount_point() {
+    let output = utils::run_rsdir_with_args(
+        "/dev",
+        "/pts\n\
+         d\n\
+         w\n\
+         q",
+        &["--recursive-delete"],
+    )
+    .unwrap();
+    assert!(output.stderr.starts_with(
+        "Error: Directory \"./pts\" is a mount point. Use \
+         --allow-mountpoint to remove it with the files of the file system \
+         mounted there"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that a mount point, whose inode in the listing is the one of the
+/// directory below it, isn't taken for a replaced directory. Skipped where
+/// user namespaces aren't allowed
+fn move_mount_point() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["mnt/"]).unwrap();
+
+    let unshare = |script: &str| {
+        let mut command = Command::new("unshare");
+        command.args(["-Urm", "sh", "-c", script]);
+        command
+    };
+    if !unshare("true")
+        .status()
+        .is_ok_and(|status| status.success())
+    {
+        return;
+    }
+    let output = unshare("mount -t tmpfs none mnt && exec \"$1\"")
+        .args([OsStr::new("sh"), utils::get_bin_path().as_os_str()])
+        .current_dir(&test_dir)
+        .env("EDITOR", utils::get_script_path())
+        .env(
+            "ED_SCRIPT",
+            "1s|./mnt|./other|\n\
+             w\n\
+             q",
+        )
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with(
+        "Error: Error moving directory \"./mnt\" to \"./other/\""
+    ));
+    assert!(!output.status.success());
+    utils::assert_test_files(&test_dir, vec![("mnt/", None)]);
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that removing a device is refused without `--force`, before anything
+/// is changed
+fn delete_file_error() {
+    let output = utils::run_rsdir_with_args(
+        "/dev",
+        "/null\n\
+         d\n\
+         w\n\
+         q",
+        &["--verbose"],
+    )
+    .unwrap();
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "Error: Can't remove character device \"./null\" without --force"
+    ));
+    assert!(!output.status.success());
+    assert!(std::path::Path::new("/dev/null").exists());
+}
+
+#[test]
+fn multiple_args() {
+    let test_dir1 = utils::create_test_dir().unwrap();
+    let test_dir2 = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir1, vec!["baz", "bop"]).unwrap();
+    utils::create_test_files(&test_dir2, vec!["foo", "pop"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .args([test_dir1.path(), test_dir2.path()])
+        .env("EDITOR", "/non-existent")
+        .env(
+            "ED_SCRIPT",
+            "/baz\n\
+             d\n\
+             /foo\n\
+             d\n\
+             w\n\
+             q",
+        )
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    utils::assert_test_files(&test_dir1, vec![("bop", Some("bop"))]);
+    utils::assert_test_files(&test_dir2, vec![("pop", Some("pop"))]);
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Checks that the files from each directory are grouped together and
+/// separated by a blank line
+fn multiple_args_content() {
+    let test_dir1 = utils::create_test_dir().unwrap();
+    let test_dir2 = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir1, vec!["bop", "baz"]).unwrap();
+    utils::create_test_files(&test_dir2, vec!["foo"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let output = Command::new(bin_path)
+        .args([test_dir2.path(), test_dir1.path()])
+        .env("EDITOR", "cat")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert_eq!(
+        stdout,
+        format!(
+            "1 {}\n\n2 {}\n3 {}",
+            test_dir2.path().join("foo").display(),
+            test_dir1.path().join("baz").display(),
+            test_dir1.path().join("bop").display(),
+        )
+    );
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn blank_lines() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1a\n\
+         \x20\x20\n\
+         \n\
+         .\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert_eq!(output.stdout, "");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn recursive() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(
+        &test_dir,
+        vec!["foo/", "foo/bar", "foo/baz/", "foo/baz/boop", "foo-bar"],
+    )
+    .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/bar\n\
+         s/bar/qux\n\
+         /baz\n\
+         d\n\
+         d\n\
+         w\n\
+         q",
+        &["--verbose", "--recursive"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("foo/", None),
+            ("foo/qux", Some("foo/bar")),
+            ("foo-bar", Some("foo-bar")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./foo/bar\" to \"./foo/qux\"
+Removed directory \"./foo/baz\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Checks that symlinked directories are only descended into with `--follow`,
+/// and that symlinks that loop back to a listed directory are skipped
+fn recursive_follow() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["foo/", "foo/bar"]).unwrap();
+    std::os::unix::fs::symlink("foo", test_dir.path().join("link")).unwrap();
+    std::os::unix::fs::symlink("..", test_dir.path().join("foo/up")).unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let run = |args: &[&str]| {
+        let output = Command::new(&bin_path)
+            .current_dir(&test_dir)
+            .args(args)
+            .env("EDITOR", "cat")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout)
+            .unwrap()
+            .trim_end()
+            .to_owned();
+        let stderr = String::from_utf8(output.stderr)
+            .unwrap()
+            .trim_end()
+            .to_owned();
+        assert!(output.status.success());
+        (stdout, stderr)
+    };
+
+    let (stdout, stderr) = run(&["--recursive"]);
+    assert_eq!(
+        stdout,
+        "1 ./foo/
+2 ./foo/bar
+3 ./foo/up
+4 ./link"
+    );
+    assert_eq!(stderr, "");
+
+    let real_path = test_dir.path().canonicalize().unwrap();
+    let (stdout, stderr) = run(&["--recursive", "--follow"]);
+    assert_eq!(
+        stdout,
+        "1 ./foo/
+2 ./foo/bar
+3 ./foo/up
+4 ./link
+5 ./link/bar
+6 ./link/up"
+    );
+    assert_eq!(
+        stderr,
+        format!(
+            "Not following \"./foo/up\", which loops back to {real_path:?}
+Not following \"./link/up\", which loops back to {real_path:?}"
+        )
+    );
+}
+
+#[test]
+/// Tests the confirmation before editing large listings, answering on stdin
+fn listing_threshold() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo", "bar"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let run = |args: &[&str], answer: &str| {
+        let mut child = Command::new(&bin_path)
+            .current_dir(&test_dir)
+            .args(args)
+            .env("EDITOR", "true")
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(answer.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        let stderr = String::from_utf8(output.stderr)
+            .unwrap()
+            .trim_end()
+            .to_owned();
+        (output.status.success(), stderr)
+    };
+
+    assert_eq!(
+        run(&["--listing-threshold", "2"], "n\n"),
+        (false, "Edit 3 files? [y/N] Error: Aborted".to_owned())
+    );
+    assert_eq!(
+        run(&["--listing-threshold", "2"], "y\n"),
+        (true, "Edit 3 files? [y/N]".to_owned())
+    );
+    assert_eq!(
+        run(&["--listing-threshold", "2", "--yes"], ""),
+        (true, "".to_owned())
+    );
+    assert_eq!(
+        run(&["--listing-threshold", "3"], ""),
+        (true, "".to_owned())
+    );
+}
+
+#[test]
+fn max_files() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo", "bar"]).unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\nw\nq",
+        &["--max-files", "2"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("bar", Some("bar")),
+            ("baz", Some("baz")),
+            ("foo", Some("foo")),
+        ],
+    );
+    assert_eq!(output.stdout, "");
+    assert_eq!(
+        output.stderr,
+        "Error: Found 3 files, which is more than the limit of 2"
+    );
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\nw\nq",
+        &["--max-files", "3"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--confirm` shows the changes and only applies them if confirmed
+fn confirm_changes() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo", "bar"]).unwrap();
+    let script = "3d\n\
+                  /bar/s/bar/boop/\n\
+                  w\n\
+                  q";
+
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &["--confirm"], "n\n")
+            .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("baz", Some("baz")),
+            ("foo", Some("foo")),
+            ("bar", Some("bar")),
+        ],
+    );
+    assert_eq!(output.stdout, "- ./bar\n+ ./boop\n- ./foo");
+    assert!(output
+        .stderr
+        .starts_with("Apply these changes? [y/N] Error: Aborted"));
+    assert!(!output.status.success());
+
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &["--confirm"], "y\n")
+            .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("boop", Some("bar"))],
+    );
+    assert_eq!(output.stdout, "- ./bar\n+ ./boop\n- ./foo");
+    assert_eq!(output.stderr, "Apply these changes? [y/N]");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--interactive each` asks before every change
+fn interactive_each() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo", "bar"]).unwrap();
+    let script = "3d\n\
+                  /bar/s/bar/boop/\n\
+                  /baz/s/baz/qux/\n\
+                  w\n\
+                  q";
+    let args = ["--interactive", "each"];
+
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &args, "n\nq\n")
+            .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("baz", Some("baz")),
+            ("foo", Some("foo")),
+            ("bar", Some("bar")),
+        ],
+    );
+    assert!(output.stderr.starts_with(
+        "Move file \"./bar\" to \"./boop\"? [y/n/a/q] \
+         Move file \"./baz\" to \"./qux\"? [y/n/a/q] Error: Aborted"
+    ));
+    assert!(!output.status.success());
+
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &args, "n\na\n")
+            .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("bar", Some("bar")), ("qux", Some("baz"))],
+    );
+    assert_eq!(
+        output.stderr,
+        "Move file \"./bar\" to \"./boop\"? [y/n/a/q] \
+         Move file \"./baz\" to \"./qux\"? [y/n/a/q]"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--copy` copies files whose index is on several rows, and
+/// expands braces into several copies
+fn copy_rows() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo", "dir/", "dir/bar"])
+        .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1a\n\
+         1 ./qux\n\
+         .\n\
+         /dir/s/dir/{dir,dir2}/\n\
+         $s/foo/photo_{01..03}/\n\
+         w\n\
+         q",
+        &["--verbose", "--copy"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("baz", Some("baz")),
+            ("qux", Some("baz")),
+            ("dir", None),
+            ("dir/bar", Some("dir/bar")),
+            ("dir2", None),
+            ("dir2/bar", Some("dir/bar")),
+            ("photo_01", Some("foo")),
+            ("photo_02", Some("foo")),
+            ("photo_03", Some("foo")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Copied file \"./baz\" to \"./qux\"\n\
+         Copied directory \"./dir\" to \"./dir2/\"\n\
+         Copied file \"./foo\" to \"./photo_02\"\n\
+         Copied file \"./foo\" to \"./photo_03\"\n\
+         Moved file \"./foo\" to \"./photo_01\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that copies are checked for conflicts before anything is changed
+fn copy_conflicts() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/baz/qux/\n\
+         1a\n\
+         1 ./foo\n\
+         .\n\
+         w\n\
+         q",
+        &["--copy"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert!(output.stderr.starts_with(
+        "Error: Can't copy \"./baz\" to \"./foo\", which already exists"
+    ));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/baz/{a,b}/\n\
+         2s/foo/b/\n\
+         w\n\
+         q",
+        &["--copy"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert!(output
+        .stderr
+        .starts_with("Error: Several files would be named \"./b\""));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests that `--expand-env` expands environment variables in changed names
+/// only
+fn expand_env() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["$keep", "baz", "foo", "dest/"])
+        .unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .args(["--verbose", "--expand-env", "--tmpdir"])
+        .arg(tmp_dir.path())
+        .env("EDITOR", utils::get_script_path())
+        .env(
+            "ED_SCRIPT",
+            "/baz/s/baz/${DEST}_$NAME/\n\
+             /foo/s|foo|$DEST/foo|\n\
+             w\n\
+             q",
+        )
+        .env("DEST", "dest")
+        .env("NAME", "qux")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("$keep", Some("$keep")),
+            ("dest_qux", Some("baz")),
+            ("dest", None),
+            ("dest/foo", Some("foo")),
+        ],
+    );
+    assert_eq!(
+        stdout,
+        "Moved file \"./baz\" to \"./dest_qux\"\n\
+         Moved file \"./foo\" to \"./dest/foo\""
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that moving a file to a name that exists fails, unless the existing
+/// file is moved away too
+fn move_conflict() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "2s/b/c/\n\
+         w\n\
+         q",
+        &[],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b")), ("c", Some("c"))],
+    );
+    assert!(output.stderr.starts_with(
+        "Error: Can't move \"./b\" to \"./c\", which already exists"
+    ));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/a/d/\n\
+         2s/b/a/\n\
+         3d\n\
+         w\n\
+         q",
+        &[],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("b")), ("d", Some("a"))],
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that files renamed to each other's names are swapped, and that
+/// longer cycles and chains of renames are ordered so nothing is replaced
+fn swap_and_cycle() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c", "d", "e", "f"])
+        .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/a/b/\n\
+         2s/b/a/\n\
+         3s/c/d/\n\
+         4s/d/e/\n\
+         5s/e/c/\n\
+         6s/f/g/\n\
+         w\n\
+         q",
+        &["--verbose"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("a", Some("b")),
+            ("b", Some("a")),
+            ("c", Some("e")),
+            ("d", Some("c")),
+            ("e", Some("d")),
+            ("g", Some("f")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "\
+Moved file \"./f\" to \"./g\"
+Swapped file \"./a\" with file \"./b\"
+Moved file \"./c\" to \"./c.rsdir-1\"
+Moved file \"./e\" to \"./c\"
+Moved file \"./d\" to \"./e\"
+Moved file \"./c.rsdir-1\" to \"./d\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--auto-suffix` adds a suffix to names that already exist
+fn auto_suffix() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(
+        &test_dir,
+        vec!["a.txt", "a (1).txt", "b.txt", "c.txt", "dir/", "dir2/"],
+    )
+    .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/b.txt/s/b/a/\n\
+         /c.txt/s/c/a/\n\
+         /dir2/s/dir2/dir/\n\
+         w\n\
+         q",
+        &["--verbose", "--auto-suffix"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("a.txt", Some("a.txt")),
+            ("a (1).txt", Some("a (1).txt")),
+            ("a (2).txt", Some("b.txt")),
+            ("a (3).txt", Some("c.txt")),
+            ("dir", None),
+            ("dir (1)", None),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./b.txt\" to \"./a (2).txt\"\n\
+         Moved file \"./c.txt\" to \"./a (3).txt\"\n\
+         Moved directory \"./dir2\" to \"./dir (1)\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn backup() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c", "d", "d.bak"])
+        .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/b/s/b/a/\n\
+         w\n\
+         q",
+        &["--verbose", "--backup"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("a", Some("b")),
+            ("a~", Some("a")),
+            ("c", Some("c")),
+            ("d", Some("d")),
+            ("d.bak", Some("d.bak")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./a\" to \"./a~\"\n\
+         Moved file \"./b\" to \"./a\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+
+    // Existing backups aren't replaced
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/c/s/c/d/\n\
+         w\n\
+         q",
+        &["--backup=.bak"],
+    )
+    .unwrap();
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "Error: Can't back up \"./d\" to \"./d.bak\", which already exists"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn numbered_backup() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "a.~1~", "b", "c"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/b/s/b/a/\n\
+         w\n\
+         q",
+        &["--verbose", "--backup=numbered"],
+    )
+    .unwrap();
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./a\" to \"./a.~2~\"\n\
+         Moved file \"./b\" to \"./a\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/c/s/c/a/\n\
+         w\n\
+         q",
+        &["--backup=numbered"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("a", Some("c")),
+            ("a.~1~", Some("a.~1~")),
+            ("a.~2~", Some("a")),
+            ("a.~3~", Some("b")),
+        ],
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn restrict() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["dir/", "dir/a", "dir/b", "c"])
+        .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/a/s|dir/a|a|\n\
+         w\n\
+         q",
+        &["--restrict", "dir"],
+    )
+    .unwrap();
+    assert_eq!(output.stdout, "");
+    assert!(output
+        .stderr
+        .starts_with("Error: \"a\" is outside of the listed directories"));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/b/s|dir/b|dir/../dir/d|\n\
+         w\n\
+         q",
+        &["--restrict", "dir"],
+    )
+    .unwrap();
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "Error: \"dir/../dir/d\" contains .., which isn't allowed with \
+         --restrict"
+    ));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/b/s|dir/b|dir/d|\n\
+         w\n\
+         q",
+        &["--verbose", "--restrict", "dir"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("c", Some("c")),
+            ("dir", None),
+            ("dir/a", Some("dir/a")),
+            ("dir/d", Some("dir/b")),
+        ],
+    );
+    assert_eq!(output.stdout, "Moved file \"dir/b\" to \"dir/d\"");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn restrict_remove() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(
+        &test_dir,
+        vec!["dir/", "dir/a", "dir/sub/", "dir/sub/b", "c"],
+    )
+    .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\n\
+         1d\n\
+         w\n\
+         q",
+        &["--verbose", "--restrict", "--recursive-delete", "dir"],
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("c", Some("c")), ("dir", None)]);
+    assert_eq!(
+        output.stdout,
+        "Removed file \"dir/a\"\nRemoved directory \"dir/sub\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that files that are changed or replaced while the editor is open are
+/// skipped. The modification time is only known when the metadata is read for
+/// a column or sort
+fn changed_while_editing() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c"]).unwrap();
+    let editor = utils::create_editor_script(
+        &tmp_dir,
+        "sed -i -e 's|/a$|/x|' -e 's|/b$|/y|' -e '/c$/d' \"$1\"\n\
+         touch -d @0 a\n\
+         echo new > b.new && mv b.new b\n",
+    );
+    let run = |args: &[&str]| {
+        Command::new(utils::get_bin_path())
+            .current_dir(&test_dir)
+            .args(["--verbose", "--tmpdir"])
+            .arg(tmp_dir.path())
+            .args(args)
+            .env("EDITOR", &editor)
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&["--sort", "mtime"]);
+
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Skipped: Move file \"./a\" to \"./x\". \"./a\" changed after it \
+         was listed\n\
+         Skipped: Move file \"./b\" to \"./y\". \"./b\" changed after it \
+         was listed\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Removed file \"./c\"\n"
+    );
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("new\n"))],
+    );
+
+    // Without the metadata, only the replaced file is noticed, by its inode
+    std::fs::remove_file(test_dir.path().join("b")).unwrap();
+    utils::create_test_files(&test_dir, vec!["b", "c"]).unwrap();
+    let output = run(&[]);
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Skipped: Move file \"./b\" to \"./y\". \"./b\" changed after it \
+         was listed\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Moved file \"./a\" to \"./x\"\nRemoved file \"./c\"\n"
+    );
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("b", Some("new\n")), ("x", Some("a"))],
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that files that are renamed while the editor is open are found by
+/// their inode, unless `--no-track-renames` is used
+fn renamed_while_editing() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    let editor = utils::create_editor_script(
+        &tmp_dir,
+        "sed -i 's|/a$|/b|' \"$1\"\n\
+         mv a a2\n",
+    );
+    let run = |args: &[&str]| {
+        Command::new(utils::get_bin_path())
+            .current_dir(&test_dir)
+            .arg("--tmpdir")
+            .arg(tmp_dir.path())
+            .args(args)
+            .env("EDITOR", &editor)
+            .output()
+            .unwrap()
+    };
+
+    utils::create_test_files(&test_dir, vec!["a"]).unwrap();
+    let output = run(&["--verbose"]);
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "\"./a\" was renamed to \"./a2\" after it was listed\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Moved file \"./a2\" to \"./b\"\n"
+    );
+    assert!(output.status.success());
+    utils::assert_test_files(&test_dir, vec![("b", Some("a"))]);
+
+    std::fs::rename(test_dir.path().join("b"), test_dir.path().join("a"))
+        .unwrap();
+    let output = run(&["--no-track-renames"]);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("Error: Error moving file \"./a\" to \"./b\""));
+    assert!(!output.status.success());
+    utils::assert_test_files(&test_dir, vec![("a2", Some("a"))]);
+}
+
+#[test]
+/// Tests that `--relative-to` shortens the names in the temporary file, and
+/// that edited names are relative to it
+fn relative_to() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(
+        &test_dir,
+        vec!["deep/", "deep/sub/", "deep/sub/a", "deep/x/"],
+    )
+    .unwrap();
+    let base = test_dir.path().join("deep");
+    let sub = base.join("sub");
+
+    let output = Command::new(utils::get_bin_path())
+        .arg("--relative-to")
+        .arg(&base)
+        .arg(&sub)
+        .env("EDITOR", "cat")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1 sub/a");
+    assert!(output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s|sub/a|x/b|\n\
+         w\n\
+         q",
+        &[
+            "--relative-to",
+            base.to_str().unwrap(),
+            sub.to_str().unwrap(),
+        ],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("deep", None),
+            ("deep/sub", None),
+            ("deep/x", None),
+            ("deep/x/b", Some("deep/sub/a")),
+        ],
+    );
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "q",
+        &["--relative-to", sub.to_str().unwrap(), "deep"],
+    )
+    .unwrap();
+    assert_eq!(
+        output.stderr,
+        format!("Error: \"deep/sub\" isn't inside {sub:?}, see --relative-to")
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests that `--number-by-order` numbers the names by the new order of the
+/// rows, replacing earlier numbers
+fn number_by_order() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["01-b", "02-a", "c"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "2d\n\
+         1i\n\
+         2 ./02-a\n\
+         .\n\
+         w\n\
+         q",
+        &["--number-by-order", "##-"],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("01-a", Some("02-a")),
+            ("02-b", Some("01-b")),
+            ("03-c", Some("c")),
+        ],
+    );
+
+    let output =
+        utils::run_rsdir_with_args(&test_dir, "q", &["--number-by-order", "-"])
+            .unwrap();
+    assert!(output.stderr.contains("Use # for the digits of the number"));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests that `--prefix` and `--suffix` suggest names with the text added,
+/// with the suffix before the extension of files
+fn prefix_suffix() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a.txt", "dir.d/", "b"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "q",
+        &["--yes", "--prefix", "x-", "--suffix", "_v2"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("x-a_v2.txt", Some("a.txt")),
+            ("x-b_v2", Some("b")),
+            ("x-dir.d_v2", None),
+        ],
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--rename-preview` shows the replaced names and applies them,
+/// opens the editor with them or aborts depending on the answer
+fn rename_preview() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["img_1.jpg", "img_2.jpg", "x"])
+        .unwrap();
+    let args = [
+        "--rename-preview",
+        r"img_(\d+)",
+        "photo-$1",
+        "--color",
+        "always",
+    ];
+
+    let output =
+        utils::run_rsdir_with_input(&test_dir, "q", &args, "n\n").unwrap();
+    assert_eq!(
+        output.stdout,
+        "./\x1b[31mimg_1\x1b[0m.jpg -> ./\x1b[32mphoto-1\x1b[0m.jpg\n\
+         ./\x1b[31mimg_2\x1b[0m.jpg -> ./\x1b[32mphoto-2\x1b[0m.jpg"
+    );
+    assert_eq!(output.stderr, "Apply these names? [y/e/N] Error: Aborted");
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "2s/photo-2/second/\n\
+         w\n\
+         q",
+        &args,
+        "e\n",
+    )
+    .unwrap();
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("photo-1.jpg", Some("img_1.jpg")),
+            ("second.jpg", Some("img_2.jpg")),
+            ("x", Some("x")),
+        ],
+    );
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "q",
+        &[
+            "--rename-preview",
+            "^(.*)[.]jpg$",
+            "$1.jpeg",
+            "--color",
+            "never",
+        ],
+        "y\n",
+    )
+    .unwrap();
+    assert_eq!(
+        output.stdout,
+        "./photo-1.jpg -> ./photo-1.jpeg\n./second.jpg -> ./second.jpeg"
+    );
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("photo-1.jpeg", Some("img_1.jpg")),
+            ("second.jpeg", Some("img_2.jpg")),
+            ("x", Some("x")),
+        ],
+    );
+}
+
+#[test]
+/// Tests that conflicts can be skipped, overwritten or renamed when asked
+fn resolve_conflict() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let script = "1s|/a$|/b|\n\
+                  w\n\
+                  q";
+    let args = ["--on-conflict", "ask"];
+
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &args, "s\n").unwrap();
+    assert_eq!(
+        output.stderr,
+        "Can't move \"./a\" to \"./b\", which already exists. Skip, \
+         overwrite, rename or edit? [s/o/r/e/Q]"
+    );
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b"))],
+    );
+
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &args, "r\n").unwrap();
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("b (1)", Some("a")), ("b", Some("b"))],
+    );
+
+    std::fs::rename(test_dir.path().join("b (1)"), test_dir.path().join("a"))
+        .unwrap();
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &args, "o\n").unwrap();
+    assert!(output.status.success());
+    utils::assert_test_files(&test_dir, vec![("b", Some("a"))]);
+
+    // Without a terminal, the default is to fail
+    utils::create_test_files(&test_dir, vec!["x"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1s|/b$|/c|\n\
+         2s|/x$|/c|\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    assert!(output
+        .stderr
+        .contains("Several files would be named \"./c\""));
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that the names can be edited again after a conflict, with the
+/// conflict as a comment in the temporary file
+fn edit_conflict() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let editor = utils::create_editor_script(
+        &tmp_dir,
+        "if grep -q '^#' \"$1\"; then\n\
+           cp \"$1\" \"$(dirname \"$0\")/annotated\"\n\
+           sed -i 's|^1 ./b$|1 ./c|' \"$1\"\n\
+         else\n\
+           sed -i 's|/a$|/b|' \"$1\"\n\
+         fi\n",
+    );
+
+    let mut child = Command::new(utils::get_bin_path())
+        .current_dir(&test_dir)
+        .args(["--on-conflict", "ask", "--tmpdir"])
+        .arg(tmp_dir.path())
+        .env("EDITOR", &editor)
+        .stdin(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"e\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(tmp_dir.path().join("annotated")).unwrap(),
+        "# Can't move \"./a\" to \"./b\", which already exists\n\
+         1 ./b\n\
+         2 ./b"
+    );
+    utils::assert_test_files(
+        &test_dir,
+        vec![("c", Some("a")), ("b", Some("b"))],
+    );
+}
+
+#[test]
+/// Tests that new names can't go above the listed directory with .. unless
+/// `--allow-parent` is used
+fn allow_parent() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(
+        &test_dir,
+        vec!["sub/", "sub/a", "sub/b", "sub/x/"],
+    )
+    .unwrap();
+    let sub_dir = test_dir.path().join("sub");
+    let script = "1s|./a|../a|\n\
+                  w\n\
+                  q";
+
+    let output = utils::run_rsdir(&sub_dir, script, false).unwrap();
+    assert_eq!(
+        output.stderr.lines().next().unwrap(),
+        "Error: \"../a\" goes above the listed directories with .., which \
+         requires --allow-parent"
+    );
+    assert!(!output.status.success());
+
+    // Names with .. that stay inside are allowed
+    let output = utils::run_rsdir(
+        &sub_dir,
+        "2s|./b|./x/../c|\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    assert!(output.status.success());
+
+    let output =
+        utils::run_rsdir_with_args(&sub_dir, script, &["--allow-parent"])
+            .unwrap();
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("a", Some("sub/a")),
+            ("sub", None),
+            ("sub/c", Some("sub/b")),
+            ("sub/x", None),
+        ],
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that special files are marked in the diff, aren't copied, and that
+/// removing a device requires `--force`
+fn special_files() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let status = Command::new("mkfifo")
+        .arg(test_dir.path().join("fifo"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/fifo/pipe/\n\
+         w\n\
+         q",
+        &["--confirm", "--yes"],
+    )
+    .unwrap();
+    assert_eq!(output.stdout, "- ./fifo (FIFO)\n+ ./pipe (FIFO)");
+    assert!(output.status.success());
+    assert!(test_dir.path().join("pipe").exists());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/pipe/{pipe,copy}/\n\
+         w\n\
+         q",
+        &["--copy"],
+    )
+    .unwrap();
+    assert!(output.stderr.contains("Can't copy FIFO \"./pipe\""));
+    assert!(!output.status.success());
+    assert!(!test_dir.path().join("copy").exists());
+
+    // Creating a device requires root
+    let status = Command::new("mknod")
+        .arg(test_dir.path().join("null"))
+        .args(["c", "1", "3"])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    if !status.success() {
+        return;
+    }
+    let output = utils::run_rsdir(
+        &test_dir,
+        "/null/d\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    assert!(output
+        .stderr
+        .contains("Can't remove character device \"./null\" without --force"));
+    assert!(!output.status.success());
+    assert!(test_dir.path().join("null").exists());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/null/d\n\
+         w\n\
+         q",
+        &["--force"],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    assert!(!test_dir.path().join("null").exists());
+}
+
+#[test]
+/// Tests that `--sanitize` suggests cleaned up names, using only the given
+/// rules
+fn sanitize() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["my file?.txt", "dots..", "ok"])
+        .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "w\n\
+         q",
+        &["--verbose", "--sanitize=spaces,dots"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("my_file?.txt", Some("my file?.txt")),
+            ("dots", Some("dots..")),
+            ("ok", Some("ok")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./dots..\" to \"./dots\"\n\
+         Moved file \"./my file?.txt\" to \"./my_file?.txt\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "w\n\
+         q",
+        &["--verbose", "--sanitize"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("my_file.txt", Some("my file?.txt")),
+            ("dots", Some("dots..")),
+            ("ok", Some("ok")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./my_file?.txt\" to \"./my_file.txt\""
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--transform` changes the case of the names, and is applied
+/// without the editor with `--yes`
+fn transform_case() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["my FILE.TXT", "Other"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "w\n\
+         q",
+        &["--verbose", "--transform", "title"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("My File.TXT", Some("my FILE.TXT")),
+            ("Other", Some("Other")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./my FILE.TXT\" to \"./My File.TXT\""
+    );
+    assert!(output.status.success());
+
+    let bin_path = utils::get_bin_path();
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .args(["--verbose", "--transform", "lower", "--yes"])
+        .env("EDITOR", "false")
+        .output()
+        .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("my file.txt", Some("my FILE.TXT")),
+            ("other", Some("Other")),
+        ],
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Moved file \"./My File.TXT\" to \"./my file.txt\"\n\
+         Moved file \"./Other\" to \"./other\"\n"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that a hard link to the moved file counts as an existing file, in the
+/// same directory and in another one
+fn hard_link_conflict() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "s/"]).unwrap();
+    std::fs::hard_link(test_dir.path().join("a"), test_dir.path().join("b"))
+        .unwrap();
+    std::fs::hard_link(test_dir.path().join("a"), test_dir.path().join("s/b"))
+        .unwrap();
+
+    for to in ["./b", "./s/b"] {
+        let output = utils::run_rsdir_with_args(
+            &test_dir,
+            &format!(
+                "1s|./a|{to}|\n\
+                 w\n\
+                 q"
+            ),
+            &[],
+        )
+        .unwrap();
+        assert!(output.stderr.starts_with(&format!(
+            "Error: Can't move \"./a\" to \"{to}\", which already exists"
+        )));
+        assert!(!output.status.success());
+        utils::assert_test_files(
+            &test_dir,
+            vec![
+                ("a", Some("a")),
+                ("b", Some("a")),
+                ("s/", None),
+                ("s/b", Some("a")),
+            ],
+        );
+    }
+}
+
+#[test]
+/// Tests that changing the case of two names to the same name fails
+fn transform_conflict() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["Foo", "foo"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "w\n\
+         q",
+        &["--transform", "lower"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("Foo", Some("Foo")), ("foo", Some("foo"))],
+    );
+    assert!(output.stderr.starts_with(
+        "Error: Can't move \"./Foo\" to \"./foo\", which already exists"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests that a name can be changed to one that only differs in case, which
+/// is the same name on case-insensitive file systems
+fn case_only_rename() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["foo"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1s|./foo|./Foo|\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    utils::assert_test_files(&test_dir, vec![("Foo", Some("foo"))]);
+}
+
+#[test]
+/// Tests that two new names that only differ in case conflict on
+/// case-insensitive file systems, and are separate names elsewhere
+fn case_collision() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1s|./a|./x|\n\
+         2s|./b|./X|\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    if utils::is_case_insensitive(&test_dir) {
+        assert!(output.stderr.starts_with(
+            "Error: Several files would be named \"./x\" and \"./X\", which \
+             only differ in case and are the same name there"
+        ));
+        assert!(!output.status.success());
+        utils::assert_test_files(
+            &test_dir,
+            vec![("a", Some("a")), ("b", Some("b"))],
+        );
+    } else {
+        assert_eq!(output.stderr, "");
+        assert!(output.status.success());
+        utils::assert_test_files(
+            &test_dir,
+            vec![("x", Some("a")), ("X", Some("b"))],
+        );
+    }
+}
+
+#[test]
+/// Tests that two files can swap names, with a change of case that makes the
+/// new names the same as the old ones on case-insensitive file systems
+fn case_swap() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["bar", "foo"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1s|./bar|./FOO|\n\
+         2s|./foo|./BAR|\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("BAR", Some("foo")), ("FOO", Some("bar"))],
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that changes in Unicode normalization are ignored, unless asked for
+/// with `--normalize`
+/// Does not apply to macOS, where the file system may normalize names
+fn normalization() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["cafe\u{301}"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "s/e\u{301}/\u{e9}/\n\
+         w\n\
+         q",
+        &["-vv"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("cafe\u{301}", Some("cafe\u{301}"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "Checked 1 rows against 1 files\n\
+         Ignoring change in Unicode normalization of \"./cafe\\u{301}\"\n\
+         Unchanged file \"./cafe\\u{301}\""
+    );
+    assert!(output.status.success());
+
+    let bin_path = utils::get_bin_path();
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .args(["--normalize", "nfc", "--yes"])
+        .env("EDITOR", "false")
+        .output()
+        .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("caf\u{e9}", Some("cafe\u{301}"))],
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that names in the diff and the verbose output are colored using
+/// `LS_COLORS`
+fn ls_colors() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a.txt", "dir/"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .args(["-v", "--confirm", "--yes", "--color", "always", "--tmpdir"])
+        .arg(tmp_dir.path())
+        .env("EDITOR", utils::get_script_path())
+        .env(
+            "ED_SCRIPT",
+            "1s/a/b/\n\
+             2s/dir/dir2/\n\
+             w\n\
+             q",
+        )
+        .env("LS_COLORS", "di=01;34:*.txt=33")
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "\x1b[31m-\x1b[0m \x1b[33m./\x1b[7ma\x1b[27m.txt\x1b[0m\n\
+         \x1b[32m+\x1b[0m \x1b[33m./\x1b[7mb\x1b[27m.txt\x1b[0m\n\
+         \x1b[31m-\x1b[0m \x1b[01;34m./\x1b[7mdir\x1b[27m/\x1b[0m\n\
+         \x1b[32m+\x1b[0m \x1b[01;34m./\x1b[7mdir2\x1b[27m/\x1b[0m\n\
+         Moved file \x1b[33m\"./a.txt\"\x1b[0m to \x1b[33m\"./b.txt\"\x1b[0m\n\
+         Moved directory \x1b[01;34m\"./dir\"\x1b[0m to \
+         \x1b[01;34m\"./dir2/\"\x1b[0m\n"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--emit-script` prints a shell script instead of making the
+/// changes, and that running it makes them unless a new name was taken in the
+/// meantime
+fn emit_script() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["dir/", "foo", "it's"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\n\
+         1d\n\
+         1s/it's/b c/\n\
+         w\n\
+         q",
+        &["--emit-script"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("dir", None), ("foo", Some("foo")), ("it's", Some("it's"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "#!/bin/sh\n\
+         set -e\n\
+         check_free() {\n    \
+             if [ -e \"$1\" ] || [ -L \"$1\" ]; then\n        \
+                 echo \"$1 already exists\" >&2\n        \
+                 exit 1\n    \
+             fi\n\
+         }\n\
+         rm -r -- './dir'\n\
+         rm -- './foo'\n\
+         check_free './b c' && mv -- './it'\\''s' './b c'"
+    );
+    assert!(output.status.success());
+    let run_script = || {
+        Command::new("sh")
+            .current_dir(&test_dir)
+            .args(["-c", &output.stdout])
+            .output()
+            .unwrap()
+    };
+
+    // mv would move the file into the directory
+    std::fs::create_dir(test_dir.path().join("b c")).unwrap();
+    let script_output = run_script();
+    assert_eq!(
+        String::from_utf8(script_output.stderr).unwrap(),
+        "./b c already exists\n"
+    );
+    assert!(!script_output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("b c", None), ("it's", Some("it's"))],
+    );
+
+    std::fs::remove_dir(test_dir.path().join("b c")).unwrap();
+    utils::create_test_files(&test_dir, vec!["dir/", "foo"]).unwrap();
+    assert!(run_script().status.success());
+    utils::assert_test_files(&test_dir, vec![("b c", Some("it's"))]);
+}
+
+#[test]
+fn files_from() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["foo/", "foo/bar", "baz", "boop"])
+        .unwrap();
+    std::fs::write(test_dir.path().join("list"), "foo/bar\nboop\nfoo\n")
+        .unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .args(["--files-from", "list"])
+        .env("EDITOR", "cat")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert_eq!(
+        stdout,
+        "1 foo/bar
+2 boop
+3 foo/"
+    );
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn files_from_null() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "boop"]).unwrap();
+    let list_dir = utils::create_test_dir().unwrap();
+    let list_path = list_dir.path().join("list");
+    std::fs::write(&list_path, "boop\0baz").unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .arg("-0")
+        .arg("--files-from")
+        .arg(&list_path)
+        .arg("--verbose")
+        .env(
+            "ED_SCRIPT",
+            "1d\n\
+             w\n\
+             q",
+        )
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(stdout, "Removed file \"boop\"");
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn files_from_stdin() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "boop", "foo"]).unwrap();
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "1d\n\
+         w\n\
+         q",
+        &["--verbose", "-0", "-"],
+        "./boop\0./baz\0",
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert_eq!(output.stdout, "Removed file \"./boop\"");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "1s/baz/bar/\n\
+         w\n\
+         q",
+        &["--verbose", "--null-stdin"],
+        "./baz\0",
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("bar", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert_eq!(output.stdout, "Moved file \"./baz\" to \"./bar\"");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `-` can be combined with directories, editing the paths read
+/// from stdin after the listed files
+fn stdin_with_paths() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["dir/", "dir/a", "b", "c"])
+        .unwrap();
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "$s/b/d/\n\
+         w\n\
+         q",
+        &["--verbose", "dir", "-"],
+        "./c\n./b\n",
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("dir/", None),
+            ("dir/a", Some("dir/a")),
+            ("c", Some("c")),
+            ("d", Some("b")),
+        ],
+    );
+    assert_eq!(output.stdout, "Moved file \"./b\" to \"./d\"");
+    assert!(output.status.success());
+
+    let output =
+        utils::run_rsdir_with_input(&test_dir, "q", &["-", "-"], "").unwrap();
+    assert!(output.stderr.contains("- can only be given once"));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn unknown_index() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "s/1/2\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "\
+Error: Unknown index 2 at row 0
+Saved edited buffer to "
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn invalid_index() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "s/1/x\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(
+        "\
+Error: Invalid index \"x\" at row 0
+Saved edited buffer to "
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Checks that the edited buffer is kept when the changes can't be applied,
+/// so that the edits aren't lost
+fn keeps_buffer_on_failure() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .arg("--tmpdir")
+        .arg(tmp_dir.path())
+        .env(
+            "ED_SCRIPT",
+            "s/1/2\n\
+             w\n\
+             q",
+        )
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let buffer_path = utils::get_single_file(&tmp_dir);
+
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(std::fs::read_to_string(&buffer_path).unwrap(), "2 ./baz\n");
+    assert_eq!(
+        stderr,
+        format!(
+            "Error: Unknown index 2 at row 0\n\
+             Saved edited buffer to {buffer_path:?}"
+        )
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn keep_buffer_flag() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .arg("--keep-buffer")
+        .arg("--tmpdir")
+        .arg(tmp_dir.path())
+        .env("ED_SCRIPT", "q")
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let buffer_path = utils::get_single_file(&tmp_dir);
+
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(std::fs::read_to_string(&buffer_path).unwrap(), "1 ./baz");
+    assert_eq!(stderr, format!("Saved edited buffer to {buffer_path:?}"));
+    assert!(output.status.success());
+}
+
+#[test]
+fn loop_until_unchanged() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    // The second listing already has the new name, so nothing changes
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "$s|[.]/[a-z]*$|./x|\n\
+         w\n\
+         q",
+        &["-vv", "--loop"],
+    )
+    .unwrap();
+
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("x", Some("b"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "\
+Checked 2 rows against 2 files
+Unchanged file \"./a\"
+Moved file \"./b\" to \"./x\"
+Checked 2 rows against 2 files
+Unchanged file \"./a\"
+Unchanged file \"./x\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn resume_buffer() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let buffer_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let buffer_path = buffer_dir.path().join("buffer");
+    std::fs::write(&buffer_path, "    1 ./boop\n    2 ./foo\n").unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .arg("--resume")
+        .arg(&buffer_path)
+        .env("ED_SCRIPT", "q")
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    utils::assert_test_files(
+        &test_dir,
+        vec![("boop", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn from_listing() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let listing_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let listing_path = listing_dir.path().join("listing");
+    let edited_path = listing_dir.path().join("edited");
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "q",
+        &["--dump-listing", listing_path.to_str().unwrap()],
+    )
+    .unwrap();
+    assert_eq!(output.stdout, "");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&listing_path).unwrap(),
+        "1 ./baz\n2 ./foo"
+    );
+
+    std::fs::write(&edited_path, "1 ./boop\n").unwrap();
+    // Nothing is listed, so the files are found from the baseline
+    std::fs::write(test_dir.path().join("new"), "new").unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "q",
+        &[
+            "--verbose",
+            "--from-listing",
+            edited_path.to_str().unwrap(),
+            "--baseline",
+            listing_path.to_str().unwrap(),
+        ],
+    )
+    .unwrap();
+
+    utils::assert_test_files(
+        &test_dir,
+        vec![("boop", Some("baz")), ("new", Some("new"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./baz\" to \"./boop\"\nRemoved file \"./foo\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn resume_unknown_index() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let buffer_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    let buffer_path = buffer_dir.path().join("buffer");
+    std::fs::write(&buffer_path, "    1 ./baz\n    2 ./foo\n").unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .arg("--resume")
+        .arg(&buffer_path)
+        .env("ED_SCRIPT", "q")
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        format!(
+            "Error: Couldn't resume from {buffer_path:?}
+
+Caused by:
+    Unknown index 2 at row 1"
+        )
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn editor_failure() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    let ed_path = utils::get_script_path();
+    let output = utils::run_rsdir(
+        &test_dir,
+        // Searching for a non-existent string will cause ed to return an error
+        "s/missing/never\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(output.stdout, "");
+    assert!(output.stderr.starts_with(&format!(
+        "Error: Editor {ed_path:?} returned error code "
+    )));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests the error handling for when the editor is killed by a signal, in which
+/// case no exit code will be returned
+/// Uses the `kill.sh` script which kills itself
+fn editor_killed() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let bin_path = utils::get_bin_path();
+    let editor_path = utils::get_tests_path().join("./kill.sh");
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .env("EDITOR", &editor_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        format!("Error: Editor {editor_path:?} returned an error")
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn missing_editor() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .env("EDITOR", "/non-existent")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    println!("status: {}", output.status);
+    println!("stdout: {stdout}");
+    println!("stderr: {stderr}");
+
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(stdout, "");
+    assert!(stderr.starts_with(
+        "\
+Error: Failed to open editor \"/non-existent\"
+
+Caused by:"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn missing_path() {
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .arg("/non-existent")
+        .env("ED_SCRIPT", "q")
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert_eq!(stdout, "");
+    assert!(stderr.starts_with(
+        "\
+Error: Couldn't list files in \"/non-existent\"
+
+Caused by:"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests the error handling when failing to create a temporary file by setting
+/// `TMPDIR` to a non-existent path
+fn create_tmpfile_error() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .env("TMPDIR", "/non-existent")
+        .env("ED_SCRIPT", "q")
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert_eq!(stdout, "");
+    assert!(stderr.starts_with(
+        "\
+Error: Failed to create temporary file
+
+Caused by:
+    No such file or directory (os error 2) at path \"/non-existent/"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests that the temporary file is created in the directory passed with
+/// `--tmpdir`, using `echo` as the editor to print the path
+fn tmpdir_flag() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let tmp_dir = utils::create_test_dir().unwrap();
+    let bin_path = utils::get_bin_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .arg("--tmpdir")
+        .arg(tmp_dir.path())
+        .env("EDITOR", "echo")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert!(PathBuf::from(stdout).starts_with(tmp_dir.path()));
+    assert_eq!(stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn tmpdir_flag_error() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let bin_path = utils::get_bin_path();
+    let ed_path = utils::get_script_path();
+
+    let output = Command::new(bin_path)
+        .current_dir(&test_dir)
+        .args(["--tmpdir", "/non-existent"])
+        .env("ED_SCRIPT", "q")
+        .env("EDITOR", ed_path)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+    let stderr = String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned();
+
+    assert_eq!(stdout, "");
+    assert!(stderr.starts_with(
+        "\
+Error: Failed to create temporary file
+
+Caused by:
+    No such file or directory (os error 2) at path \"/non-existent/"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn non_verbose() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "s/baz/boop\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("boop", Some("baz"))]);
+    assert_eq!(output.stdout, "");
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that repeating `-v` also logs unchanged files, checks and timing
+fn verbosity_levels() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/baz/boop/\n\
+         w\n\
+         q",
+        &["-vv"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("boop", Some("baz")), ("foo", Some("foo"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "Checked 2 rows against 2 files\n\
+         Unchanged file \"./foo\"\n\
+         Moved file \"./baz\" to \"./boop\""
+    );
+    assert!(output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/boop/baz/\n\
+         w\n\
+         q",
+        &["-vvv"],
+    )
+    .unwrap();
+    let lines = output.stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("Listed 2 files in "));
+    assert!(lines[4].starts_with("Applied changes in "));
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--log-file` appends every applied change with a timestamp
+fn log_file() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let log_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz", "foo"]).unwrap();
+    let log_path = log_dir.path().join("rsdir.log");
+    std::fs::write(&log_path, "previous\n").unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "2d\n\
+         1s/baz/boop/\n\
+         w\n\
+         q",
+        &["--log-file", log_path.to_str().unwrap()],
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("boop", Some("baz"))]);
+    assert_eq!(output.stdout, "");
+    assert!(output.status.success());
+
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "previous");
+    assert!(lines[1].ends_with(&format!(
+        " Applying changes in {:?}",
+        test_dir.path().canonicalize().unwrap()
+    )));
+    assert!(lines[2].ends_with(" Move file \"./baz\" to \"./boop\""));
+    assert!(lines[3].ends_with(" Remove file \"./foo\""));
+}
+
+#[test]
+fn removes_temp_file() {
+    let bin_path = utils::get_bin_path();
+
+    let file_path = String::from_utf8(
+        process::Command::new(bin_path)
+            .env("EDITOR", "echo")
+            .output()
+            .unwrap()
+            .stdout,
+    )
+    .unwrap();
+    assert!(!PathBuf::from(file_path).exists());
+}
+
+#[test]
+#[cfg(unix)]
+/// Checks that newlines, tabs and backslashes in names are escaped
+/// The edited text is passed with `--resume`, since `echo` in `ed.sh` may
+/// interpret backslashes
+fn escaped_names() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let buffer_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a\nb", "back\\slash", "t\tab"])
+        .unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let output = Command::new(&bin_path)
+        .current_dir(&test_dir)
+        .env("EDITOR", "cat")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 ./a\\nb\n2 ./back\\\\slash\n3 ./t\\tab"
+    );
+
+    let buffer_path = buffer_dir.path().join("buffer");
+    std::fs::write(&buffer_path, "1 ./x\\ty\\\\\n2 ./back\\\\slash\n").unwrap();
+    let buffer_arg = buffer_path.to_str().unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "q",
+        &["--verbose", "--resume", buffer_arg],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("x\ty\\", Some("a\nb")),
+            ("back\\slash", Some("back\\slash")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./a\\nb\" to \"./x\\ty\\\\\"
+Removed file \"./t\\tab\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+fn null_buffer() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let buffer_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a\nb", "back\\slash"]).unwrap();
+
+    let bin_path = utils::get_bin_path();
+    let output = Command::new(&bin_path)
+        .current_dir(&test_dir)
+        .arg("--null-buffer")
+        .env("EDITOR", "cat")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 ./a\nb\x002 ./back\\slash\0"
+    );
+
+    let buffer_path = buffer_dir.path().join("buffer");
+    std::fs::write(&buffer_path, "1 ./x\ty\\\0\n").unwrap();
+    let buffer_arg = buffer_path.to_str().unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "q",
+        &["--verbose", "--null-buffer", "--resume", buffer_arg],
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("x\ty\\", Some("a\nb"))]);
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./a\\nb\" to \"./x\\ty\\\\\"
+Removed file \"./back\\\\slash\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn invalid_escape() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let buffer_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["baz"]).unwrap();
+    let buffer_path = buffer_dir.path().join("buffer");
+    std::fs::write(&buffer_path, "1 ./b\\az\n").unwrap();
+    let buffer_arg = buffer_path.to_str().unwrap();
+    let output =
+        utils::run_rsdir_with_args(&test_dir, "q", &["--resume", buffer_arg])
+            .unwrap();
+    utils::assert_test_files(&test_dir, vec![("baz", Some("baz"))]);
+    assert_eq!(output.stdout, "");
+    assert_eq!(
+        output.stderr,
+        format!(
+            "\
+Error: Couldn't resume from {buffer_path:?}
+
+Caused by:
+    0: Invalid name at row 0
+    1: Invalid escape sequence \\a"
+        )
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that paths that are not valid Unicode are handled correctly
+/// Does not apply to macOS(HFS+ or APFS) where paths are always valid Unicode
+fn byte_path_linux() {
+    let file_path_bytes = vec![
+        97, 0o017, 0o254, 0o001, 0o103, 0o326, 0o144, 0o203, 0o261, 0o154,
+        0o065, 0o053, 0o167,
+    ];
+    // Ensure that the test path is invalid UTF-8
+    String::from_utf8(file_path_bytes.clone()).unwrap_err();
+    let file_path_os: OsString = OsStringExt::from_vec(file_path_bytes);
+
+    let test_dir = utils::create_test_dir().unwrap();
+    let path_buf = test_dir.path().join(PathBuf::from(file_path_os));
+    std::fs::create_dir_all(path_buf).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "d\n\
+         i\n\
+         1 ./baz\n\
+         .\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, vec![("baz", None)]);
+    assert_eq!(
+        output.stdout,
+        "Moved directory \
+        \"./a\\u{f}\\xAC\\u{1}C\\xD6d\\x83\\xB1l5+w\" to \"./baz\""
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `serve --stdio` lists files and plans and applies changes as
+/// JSON-RPC requests, one per line
+fn serve_stdio() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let requests = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"list"}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"plan","params":{"files":[{"index":1,"name":"./c"}]}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"apply","params":{"files":[{"index":2,"name":"./d"},{"index":1,"name":"./a"}]}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"method":"apply","params":{"files":[]}}"#,
+        r#"{"jsonrpc":"2.0","id":5,"method":"rename"}"#,
+        "{",
+    ];
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "q",
+        &["serve", "--stdio"],
+        &requests.join("\n"),
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("d", Some("b"))],
+    );
+    let responses = output.stdout.lines().collect::<Vec<_>>();
+    assert_eq!(
+        responses,
+        [
+            r#"{"id":1,"jsonrpc":"2.0","result":{"files":[{"index":1,"is_dir":false,"name":"./a"},{"index":2,"is_dir":false,"name":"./b"}]}}"#,
+            r#"{"id":2,"jsonrpc":"2.0","result":{"operations":[{"from":"./a","is_dir":false,"to":"./c","type":"move"},{"is_dir":false,"path":"./b","type":"remove"}]}}"#,
+            r#"{"id":3,"jsonrpc":"2.0","result":{"operations":[{"from":"./b","is_dir":false,"to":"./d","type":"move"}]}}"#,
+            r#"{"error":{"code":-32000,"message":"List the files first"},"id":4,"jsonrpc":"2.0"}"#,
+            r#"{"error":{"code":-32601,"message":"Unknown method \"rename\""},"id":5,"jsonrpc":"2.0"}"#,
+            r#"{"error":{"code":-32700,"message":"Invalid JSON: EOF while parsing an object at line 1 column 1"},"id":null,"jsonrpc":"2.0"}"#,
+        ]
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--strict` rejects reordered rows, directories without a
+/// trailing slash and trailing whitespace, but applies other changes
+fn strict() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "dir/"]).unwrap();
+    let files = vec![("a", Some("a")), ("b", Some("b")), ("dir/", None)];
+
+    for (script, error) in [
+        (
+            "1d\n$a\n1 ./a\n.\nw\nq",
+            "Index 1 comes after index 3, but rows can't be reordered",
+        ),
+        (
+            "2a\n2 ./c\n.\nw\nq",
+            "Index 2 is on several rows, which isn't allowed",
+        ),
+        (
+            "3s|dir/|other|\nw\nq",
+            "\"./other\" is a directory, but its row doesn't end with /",
+        ),
+        ("1s|./a|./a |\nw\nq", "Trailing whitespace at row 0"),
+    ] {
+        let output =
+            utils::run_rsdir_with_args(&test_dir, script, &["--strict"])
+                .unwrap();
+        utils::assert_test_files(&test_dir, files.clone());
+        assert!(output.stderr.contains(error));
+        assert!(!output.status.success());
+    }
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "2s/b/c/\n\
+         3s|dir/|other/|\n\
+         w\n\
+         q",
+        &["--strict"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("c", Some("b")), ("other/", None)],
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--lenient` matches rows without an index to the file at the
+/// same position, instead of failing
+fn lenient() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c"]).unwrap();
+    let script = "2s|2 ./b|./d|\n\
+                  w\n\
+                  q";
+
+    let output = utils::run_rsdir(&test_dir, script, false).unwrap();
+    assert!(output.stderr.contains("Couldn't find index at row 1"));
+    assert!(!output.status.success());
+
+    let output =
+        utils::run_rsdir_with_args(&test_dir, script, &["--lenient"]).unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("c", Some("c")), ("d", Some("b"))],
+    );
+    assert_eq!(
+        output.stderr,
+        "Row 1 has no index, so it was matched to index 2 by its position"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that changes in directories without write permission are all
+/// reported before anything is changed
+fn check_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(
+        &test_dir,
+        vec!["locked/", "locked/b", "locked/c"],
+    )
+    .unwrap();
+    let locked = test_dir.path().join("locked");
+    let set_mode = |mode| {
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(mode))
+            .unwrap()
+    };
+
+    set_mode(0o555);
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s/b/e/\n\
+         2d\n\
+         w\n\
+         q",
+        &["locked"],
+    )
+    .unwrap();
+    set_mode(0o755);
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("locked/", None),
+            ("locked/b", Some("locked/b")),
+            ("locked/c", Some("locked/c")),
+        ],
+    );
+    assert!(output.stderr.starts_with(
+        "Error: These changes would fail without write permission:
+Move file \"locked/b\" to \"locked/e\": \"locked\" isn't writable
+Remove file \"locked/c\": \"locked\" isn't writable"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests that copies are checked against the free space of the file system
+/// before any of them are made
+fn check_space() {
+    let test_dir = utils::create_test_dir().unwrap();
+    // A sparse file takes no space, but its copy is as large as its length
+    std::fs::File::create(test_dir.path().join("big"))
+        .unwrap()
+        .set_len(1 << 43)
+        .unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1a\n\
+         1 ./copy\n\
+         .\n\
+         w\n\
+         q",
+        &["--copy"],
+    )
+    .unwrap();
+    assert!(!test_dir.path().join("copy").exists());
+    assert!(output.stderr.starts_with(
+        "Error: Copying needs 8.0T on the file system of \".\", but only"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Tests that RSDIR_EDITOR takes precedence over VISUAL, which takes
+/// precedence over EDITOR
+fn rsdir_editor() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a"]).unwrap();
+    let run = |script: &str, vars: &[(&str, PathBuf)]| {
+        let mut command = Command::new(utils::get_bin_path());
+        command
+            .current_dir(&test_dir)
+            .env("ED_SCRIPT", script)
+            .env("EDITOR", "/non-existent")
+            .env_remove("VISUAL")
+            .env_remove("RSDIR_EDITOR");
+        for (var, value) in vars {
+            command.env(var, value);
+        }
+        command.output().unwrap()
+    };
+
+    let output = run(
+        "1s/a/b/\nw\nq",
+        &[
+            ("RSDIR_EDITOR", utils::get_script_path()),
+            ("VISUAL", PathBuf::from("/non-existent")),
+        ],
+    );
+    assert!(output.status.success());
+    utils::assert_test_files(&test_dir, vec![("b", Some("a"))]);
+
+    let output = run("1s/b/c/\nw\nq", &[("VISUAL", utils::get_script_path())]);
+    assert!(output.status.success());
+    utils::assert_test_files(&test_dir, vec![("c", Some("a"))]);
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that GUI editors are passed a flag to wait until the file is closed
+fn gui_editor_wait() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a"]).unwrap();
+    let editor_dir = utils::create_test_dir().unwrap();
+    let editor = editor_dir.path().join("code");
+    std::fs::write(
+        &editor,
+        "#!/bin/sh\necho \"$1\" > \"$(dirname \"$0\")/args\"",
+    )
+    .unwrap();
+    std::fs::set_permissions(&editor, std::fs::Permissions::from_mode(0o755))
+        .unwrap();
+
+    let output = Command::new(utils::get_bin_path())
+        .current_dir(&test_dir)
+        .env("EDITOR", &editor)
+        .env_remove("VISUAL")
+        .env_remove("RSDIR_EDITOR")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let args = std::fs::read_to_string(editor_dir.path().join("args")).unwrap();
+    assert_eq!(args, "--wait\n");
+}
+
+#[test]
+/// Tests that directories that still contain files are only removed with
+/// `--recursive-delete` or `--force`, unless their contents are removed too
+fn recursive_delete() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let files = vec!["a/", "a/b", "a/c", "d/", "d/e"];
+    utils::create_test_files(&test_dir, files.clone()).unwrap();
+    let expected = vec![
+        ("a/", None),
+        ("a/b", Some("a/b")),
+        ("a/c", Some("a/c")),
+        ("d/", None),
+        ("d/e", Some("d/e")),
+    ];
+    let script = "1d\n\
+                  w\n\
+                  q";
+
+    let output = utils::run_rsdir(&test_dir, script, false).unwrap();
+    utils::assert_test_files(&test_dir, expected.clone());
+    assert!(output.stderr.starts_with(
+        "Error: Directory \"./a\" still contains 2 files. Use \
+         --recursive-delete to remove it with everything in it"
+    ));
+    assert!(!output.status.success());
+
+    // Every file in the directory is removed too
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "5d\n\
+         4d\n\
+         w\n\
+         q",
+        &["--recursive"],
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, expected[..3].to_vec());
+    assert!(output.status.success());
+
+    utils::create_test_files(&test_dir, vec!["d/", "d/e"]).unwrap();
+    let output =
+        utils::run_rsdir_with_args(&test_dir, script, &["--recursive-delete"])
+            .unwrap();
+    utils::assert_test_files(&test_dir, expected[3..].to_vec());
+    assert!(output.status.success());
+
+    utils::create_test_files(&test_dir, files[..3].to_vec()).unwrap();
+    let output =
+        utils::run_rsdir_with_args(&test_dir, script, &["--force"]).unwrap();
+    utils::assert_test_files(&test_dir, expected[3..].to_vec());
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that large changes and, with `--confirm-removals`, removals are only
+/// applied if confirmed, unless `--yes` is passed
+fn change_threshold() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let files = vec![
+        ("bar", Some("bar")),
+        ("baz", Some("baz")),
+        ("foo", Some("foo")),
+    ];
+    utils::create_test_files(&test_dir, vec!["bar", "baz", "foo"]).unwrap();
+    let rename = "1s/ba/bo/\n\
+                  2s/ba/bo/\n\
+                  w\n\
+                  q";
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        rename,
+        &["--change-threshold", "1"],
+        "n\n",
+    )
+    .unwrap();
+    utils::assert_test_files(&test_dir, files.clone());
+    assert_eq!(output.stdout, "- ./bar\n+ ./bor\n- ./baz\n+ ./boz");
+    assert!(output
+        .stderr
+        .starts_with("Apply these changes? [y/N] Error: Aborted"));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        rename,
+        &["--change-threshold", "2"],
+        "",
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("bor", Some("bar")),
+            ("boz", Some("baz")),
+            ("foo", Some("foo")),
+        ],
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+
+    let remove = "$d\n\
+                  w\n\
+                  q";
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        remove,
+        &["--confirm-removals"],
+        "n\n",
+    )
+    .unwrap();
+    assert_eq!(output.stdout, "- ./foo");
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        remove,
+        &["--confirm-removals", "--yes"],
+        "",
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("bor", Some("bar")), ("boz", Some("baz"))],
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `-y` answers every confirmation, so that nothing is read from
+/// stdin
+fn yes_to_all() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["bar", "baz", "foo"]).unwrap();
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "1s/bar/boop/\n\
+         $d\n\
+         w\n\
+         q",
+        &[
+            "-y",
+            "--listing-threshold",
+            "1",
+            "--change-threshold",
+            "1",
+            "--confirm-removals",
+            "--interactive",
+            "each",
+        ],
+        "",
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("baz", Some("baz")), ("boop", Some("bar"))],
+    );
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that a file moved to the name of a removed file waits until it is
+/// removed, and that the name is still taken with `--order renames-first`
+fn operation_order() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let script = "2d\n\
+                  1s/a/b/\n\
+                  w\n\
+                  q";
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        script,
+        &["--order", "renames-first"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b"))],
+    );
+    assert!(output.stderr.starts_with(
+        "Error: Can't move \"./a\" to \"./b\", which already exists"
+    ));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir(&test_dir, script, true).unwrap();
+    utils::assert_test_files(&test_dir, vec![("b", Some("a"))]);
+    assert_eq!(
+        output.stdout,
+        "Removed file \"./b\"\nMoved file \"./a\" to \"./b\""
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that files are moved out of a directory before it is removed, and
+/// that they don't count as remaining in it
+fn move_out_of_removed_dir() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["dir/", "dir/a", "dir/b"])
+        .unwrap();
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\n\
+         1s|dir/a|a|\n\
+         2s|dir/b|c|\n\
+         w\n\
+         q",
+        &["--verbose", "--recursive", "--order", "deletes-first"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("dir/a")), ("c", Some("dir/b"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "Moved file \"./dir/a\" to \"./a\"\n\
+         Moved file \"./dir/b\" to \"./c\"\n\
+         Removed directory \"./dir\""
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that `--parents` creates the missing directories of new names,
+/// parents first, and that files are moved into a directory after it is moved
+/// to its name
+fn create_parents() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "z/"]).unwrap();
+    let script = "1s|a|x/y/a|\n\
+                  2s|b|z2/b|\n\
+                  3s|z|z2|\n\
+                  w\n\
+                  q";
+
+    let output = utils::run_rsdir(&test_dir, script, true).unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b")), ("z/", None)],
+    );
+    assert!(output
+        .stderr
+        .starts_with("Error: Error moving file \"./a\" to \"./x/y/a\""));
+    assert!(!output.status.success());
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        script,
+        &["--verbose", "--parents"],
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("x/", None),
+            ("x/y/", None),
+            ("x/y/a", Some("a")),
+            ("z2/", None),
+            ("z2/b", Some("b")),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        "Created directory \"./x\"\n\
+         Created directory \"./x/y\"\n\
+         Moved file \"./a\" to \"./x/y/a\"\n\
+         Moved directory \"./z\" to \"./z2/\"\n\
+         Moved file \"./b\" to \"./z2/b\""
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+/// Tests that an index on several rows with different names is an error
+/// without `--copy`
+fn duplicate_index() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let output = utils::run_rsdir(
+        &test_dir,
+        "1a\n\
+         1 ./c\n\
+         .\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b"))],
+    );
+    assert!(output.stderr.starts_with(
+        "Error: Index 1 is on rows 0 and 1 with different names. Use --copy \
+         to copy the file"
+    ));
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that `--one-file-system` lists mount points without their contents,
+/// using the file systems mounted in /dev
+fn one_file_system() {
+    let list = |args: &[&str]| {
+        let output = Command::new(utils::get_bin_path())
+            .args(args)
+            .arg("/dev")
+            .env("EDITOR", "cat")
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let all = list(&["--recursive"]);
+    assert!(all.lines().any(|line| line.ends_with("/dev/pts/")));
+    assert!(all.lines().any(|line| line.contains("/dev/pts/ptmx")));
+
+    let same = list(&["--recursive", "--one-file-system"]);
+    assert!(same.lines().any(|line| line.ends_with("/dev/pts/")));
+    assert!(!same.lines().any(|line| line.contains("/dev/pts/ptmx")));
+}
+
+#[test]
+#[cfg(any(unix, windows))]
+/// Tests that a symlink to a directory is copied and removed as a link, and
+/// that the files of the directory are left as they are
+fn dir_symlink() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["foo/", "foo/bar"]).unwrap();
+    utils::symlink_dir("foo", test_dir.path().join("link")).unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "/link/a\n\
+         2 ./link2\n\
+         .\n\
+         w\n\
+         q",
+        &["--copy"],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    let link2 = test_dir.path().join("link2");
+    assert!(std::fs::symlink_metadata(&link2).unwrap().is_symlink());
+    assert_eq!(std::fs::read_link(&link2).unwrap(), PathBuf::from("foo"));
+
+    let output = utils::run_rsdir(
+        &test_dir,
+        "/link2/d\n\
+         /link/d\n\
+         w\n\
+         q",
+        true,
+    )
+    .unwrap();
+    utils::assert_test_files(
+        &test_dir,
+        vec![("foo/", None), ("foo/bar", Some("foo/bar"))],
+    );
+    assert_eq!(
+        output.stdout,
+        "Removed file \"./link\"\nRemoved file \"./link2\""
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that files can be moved into a symlinked directory, and renamed in a
+/// symlinked directory that is followed with `--recursive --follow`
+fn rename_through_symlink() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let other_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "dir/"]).unwrap();
+    utils::symlink_dir("dir", test_dir.path().join("link")).unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s|./a|./link/a|\n\
+         w\n\
+         q",
+        &[],
+    )
+    .unwrap();
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    assert!(test_dir.path().join("dir/a").exists());
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&other_dir, vec!["bar"]).unwrap();
+    utils::symlink_dir(other_dir.path(), test_dir.path().join("link")).unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "2s|bar|baz|\n\
+         w\n\
+         q",
+        &["--recursive", "--follow"],
+    )
+    .unwrap();
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    utils::assert_test_files(&other_dir, vec![("baz", Some("bar"))]);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that a file moved to another file system is copied with its
+/// permissions and ACL, and that `--no-acl` leaves the ACL behind
+fn move_across_file_systems() {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let acl_name = c"system.posix_acl_access";
+    let acl_len = |path: &std::path::Path| {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // SAFETY: The strings are NUL-terminated, and a length of 0 reads
+        // nothing
+        unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                acl_name.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+            )
+        }
+    };
+
+    for (args, keeps_acl) in [(&[][..], true), (&["--no-acl"][..], false)] {
+        let test_dir = utils::create_test_dir().unwrap();
+        utils::create_test_files(&test_dir, vec!["a"]).unwrap();
+        let from = test_dir.path().join("a");
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o640)).unwrap();
+        // Owner rw, user 0 r, group r, mask r, others nothing
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, 0),
+            (0x04, 4, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        let path = CString::new(from.as_os_str().as_bytes()).unwrap();
+        // SAFETY: The strings are NUL-terminated and the value has the length
+        // that is passed
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                acl_name.as_ptr(),
+                acl.as_ptr().cast(),
+                acl.len(),
+                0,
+            )
+        };
+        assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+
+        let Some(other_dir) = utils::create_other_fs_dir(&test_dir) else {
+            return;
+        };
+        let to = other_dir.path().join("a");
+        let mut all_args = vec!["--allow-parent"];
+        all_args.extend(args);
+        let output = utils::run_rsdir_with_args(
+            &test_dir,
+            &format!(
+                "1s|./a|{}|\n\
+                 w\n\
+                 q",
+                to.display()
+            ),
+            &all_args,
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert!(!from.exists());
+        let moved = fs::symlink_metadata(&to).unwrap();
+        assert_eq!(moved.permissions().mode() & 0o777, 0o640);
+        assert_eq!(acl_len(&to) > 0, keeps_acl);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that a file is still moved to another file system when its SELinux
+/// context can't be set there, here since it is larger than ext4 allows
+fn move_without_selinux_context() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    let Some(other_dir) = utils::create_other_fs_dir(&test_dir) else {
+        return;
+    };
+    utils::create_test_files(&other_dir, vec!["a"]).unwrap();
+    let from = other_dir.path().join("a");
+    let context = vec![b'x'; 8000];
+    let path = CString::new(from.as_os_str().as_bytes()).unwrap();
+    // SAFETY: The strings are NUL-terminated and the value has the length
+    // that is passed
+    let result = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            c"security.selinux".as_ptr(),
+            context.as_ptr().cast(),
+            context.len(),
+            0,
+        )
+    };
+    // Only systems without SELinux take any context
+    if result != 0 {
+        return;
+    }
+
+    let to = test_dir.path().join("a");
+    let output = utils::run_rsdir_with_args(
+        &other_dir,
+        &format!(
+            "1s|./a|{}|\n\
+             w\n\
+             q",
+            to.display()
+        ),
+        &["--allow-parent"],
+    )
+    .unwrap();
+    assert_eq!(
+        output.stderr,
+        format!(
+            "Couldn't set attribute \"security.selinux\" on {to:?}: No space \
+             left on device (os error 28)"
+        )
+    );
+    assert!(output.status.success());
+    assert!(!from.exists());
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "a");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that names and paths that are too long are reported with their row
+/// before anything is changed
+fn name_too_long() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+
+    let long_name = "x".repeat(256);
+    let output = utils::run_rsdir(
+        &test_dir,
+        &format!(
+            "1s|./a|./c|\n\
+             2s|./b|./{long_name}|\n\
+             w\n\
+             q"
+        ),
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        output.stderr.lines().next().unwrap(),
+        format!(
+            "Error: The name \"{long_name}\" at row 1 is longer than the 255 \
+             bytes that names can have"
+        )
+    );
+    assert!(!output.status.success());
+
+    let long_path = format!("./{}", "d/".repeat(2100));
+    let output = utils::run_rsdir(
+        &test_dir,
+        &format!(
+            "1s|./a|{long_path}a|\n\
+             w\n\
+             q"
+        ),
+        false,
+    )
+    .unwrap();
+    assert!(output.stderr.contains(
+        "at row 0 is longer than the 4095 bytes that paths can have"
+    ));
+    assert!(!output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b"))],
+    );
+}
+
+#[test]
+#[cfg(not(windows))]
+/// Tests that `--portable-names` rejects names reserved for devices on Windows
+/// and names ending with a dot or space
+fn portable_names() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+
+    for (name, error) in [
+        (
+            "./Aux.txt",
+            "Error: The name \"Aux.txt\" at row 0 is reserved for a device on \
+             Windows",
+        ),
+        (
+            "./com1/c",
+            "Error: The name \"com1\" at row 0 is reserved for a device on \
+             Windows",
+        ),
+        (
+            "./c.",
+            "Error: The name \"c.\" at row 0 ends with a dot or space, which \
+             Windows removes",
+        ),
+    ] {
+        let output = utils::run_rsdir_with_args(
+            &test_dir,
+            &format!(
+                "1s|./a|{name}|\n\
+                 w\n\
+                 q"
+            ),
+            &["--portable-names"],
+        )
+        .unwrap();
+        assert_eq!(output.stderr.lines().next().unwrap(), error);
+        assert!(!output.status.success());
+    }
+
+    // Names that only start like a reserved name are fine, and the names
+    // are only checked with the flag
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s|./a|./console|\n\
+         w\n\
+         q",
+        &["--portable-names"],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    let output = utils::run_rsdir(
+        &test_dir,
+        "/b$/s|./b|./nul|\n\
+         w\n\
+         q",
+        false,
+    )
+    .unwrap();
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("console", Some("a")), ("nul", Some("b"))],
+    );
+}
+
+#[test]
+/// Tests that `--report` prints the result of every change at the end
+fn report() {
+    let script = "1s|./a|./x,1|\n\
+                  2d\n\
+                  w\n\
+                  q";
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let args = ["--interactive", "each", "--report", "csv"];
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &args, "y\nn\n")
+            .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        "result,type,path,new,detail\n\
+         applied,move,./a,\"./x,1\",\n\
+         skipped,remove,./b,,Declined"
+    );
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let args = ["--interactive", "each", "--report", "table"];
+    let output =
+        utils::run_rsdir_with_input(&test_dir, script, &args, "n\nq\n")
+            .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        output.stdout,
+        "RESULT      CHANGE\n\
+         skipped     Move file \"./a\" to \"./x,1\": Declined\n\
+         failed      Remove file \"./b\": Aborted"
+    );
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let output =
+        utils::run_rsdir_with_args(&test_dir, script, &["--report", "json"])
+            .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        "[{\"from\":\"./a\",\"is_dir\":false,\"result\":\"applied\",\
+         \"to\":\"./x,1\",\"type\":\"move\"},{\"is_dir\":false,\"path\":\
+         \"./b\",\"result\":\"applied\",\"type\":\"remove\"}]"
+    );
+}
+
+#[test]
+/// Tests that `--print0` prints the names after the changes, with copies and
+/// without removed files
+fn print0() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c"]).unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s|./a|./x|\n\
+         1a\n\
+         1 ./y\n\
+         .\n\
+         /b$/d\n\
+         w\n\
+         q",
+        &["--copy", "--print0"],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, "./x\0./y\0./c\0");
+    utils::assert_test_files(
+        &test_dir,
+        vec![("c", Some("c")), ("x", Some("a")), ("y", Some("a"))],
+    );
+}
+
+#[test]
+/// Tests that `--print0` prints the new names of the files in a renamed
+/// directory, and not of files with names that only start like it
+fn print0_directory() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["dir/", "dir/a", "dir b"])
+        .unwrap();
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1s|./dir/|./new/|\n\
+         w\n\
+         q",
+        &["--recursive", "--print0"],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, "./new/\0./new/a\0./dir b\0");
+}
+
+#[test]
+/// Tests that `--write-map` writes the old and new names of renamed files
+fn write_map() {
+    let map_dir = utils::create_test_dir().unwrap();
+    let script = "1s|./a|./x|\n\
+                  /b$/d\n\
+                  w\n\
+                  q";
+
+    for (name, expected) in [
+        ("map.tsv", "./a\t./x\n"),
+        ("map.json", "[{\"new\":\"./x\",\"old\":\"./a\"}]\n"),
+    ] {
+        let test_dir = utils::create_test_dir().unwrap();
+        utils::create_test_files(&test_dir, vec!["a", "b", "c"]).unwrap();
+        let map = map_dir.path().join(name);
+        let output = utils::run_rsdir_with_args(
+            &test_dir,
+            script,
+            &["--write-map", map.to_str().unwrap()],
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(std::fs::read_to_string(&map).unwrap(), expected);
+    }
+}
+
+#[test]
+/// Tests that `--map` renames files by a map without opening the editor, and
+/// that conflicts are found like when editing
+fn apply_map() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c", "d"]).unwrap();
+    let map_dir = utils::create_test_dir().unwrap();
+    let run = |name: &str, map: &str| {
+        let path = map_dir.path().join(name);
+        std::fs::write(&path, map).unwrap();
+        Command::new(utils::get_bin_path())
+            .current_dir(&test_dir)
+            .arg("--map")
+            .arg(&path)
+            .env("EDITOR", "false")
+            .output()
+            .unwrap()
+    };
+
+    let output = run("map.tsv", "./a\t./b\n./b\t./a\n./c\t./x\\ty\n");
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("a", Some("b")),
+            ("b", Some("a")),
+            ("d", Some("d")),
+            ("x\ty", Some("c")),
+        ],
+    );
+
+    let output = run("map.json", r#"[{"old": "./a", "new": "./d"}]"#);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap().lines().next(),
+        Some("Error: Can't move \"./a\" to \"./d\", which already exists")
+    );
+
+    let output = run("missing.tsv", "./a\t./e\n./z\t./f\n");
+    assert!(!output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![
+            ("a", Some("b")),
+            ("b", Some("a")),
+            ("d", Some("d")),
+            ("x\ty", Some("c")),
+        ],
+    );
+}
+
+#[test]
+/// Tests that `--error-format json` prints errors as JSON with the row and
+/// names they are about
+fn error_format_json() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let args = ["--error-format", "json", "--on-conflict", "fail"];
+    let error = |script: &str| {
+        let output =
+            utils::run_rsdir_with_args(&test_dir, script, &args).unwrap();
+        assert!(!output.status.success());
+        let line = output.stderr.lines().next().unwrap().to_owned();
+        serde_json::from_str::<serde_json::Value>(&line).unwrap()
+    };
+
+    assert_eq!(
+        error(
+            "1s|./a|./b|\n\
+             w\n\
+             q"
+        ),
+        serde_json::json!({
+            "message": "Can't move \"./a\" to \"./b\", which already exists",
+            "causes": [],
+            "kind": "conflict",
+            "row": null,
+            "path": "./a",
+            "target": "./b",
+            "io_error": null,
+        })
+    );
+    assert_eq!(
+        error(
+            "2s|2|9|\n\
+             w\n\
+             q"
+        ),
+        serde_json::json!({
+            "message": "Unknown index 9 at row 1",
+            "causes": [],
+            "kind": "invalid_row",
+            "row": 1,
+            "path": null,
+            "target": "./b",
+            "io_error": null,
+        })
+    );
+}
+
+#[test]
+/// Tests that the diff highlights the words that a move changes
+fn diff_highlight() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["photo_001.jpg", "x"]).unwrap();
+
+    let output = Command::new(utils::get_bin_path())
+        .current_dir(&test_dir)
+        .args(["--confirm", "--yes", "--color", "always"])
+        .env("EDITOR", utils::get_script_path())
+        .env(
+            "ED_SCRIPT",
+            "1s/001/002/\n\
+             2d\n\
+             w\n\
+             q",
+        )
+        .env_remove("LS_COLORS")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "\x1b[31m-\x1b[0m ./photo_\x1b[7m001\x1b[27m.jpg\n\
+         \x1b[32m+\x1b[0m ./photo_\x1b[7m002\x1b[27m.jpg\n\
+         \x1b[31m-\x1b[0m ./x\n"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that a diff longer than the terminal is shown through the pager,
+/// unless `--no-pager` is passed
+fn pager() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let pager_dir = utils::create_test_dir().unwrap();
+    let paged = pager_dir.path().join("paged");
+    let pager = utils::create_editor_script(
+        pager_dir.path(),
+        &format!("cat > {}", paged.display()),
+    );
+
+    for (args, uses_pager) in [(&[][..], true), (&["--no-pager"][..], false)] {
+        utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+        let (master, slave) = utils::open_pty();
+
+        let status = Command::new(utils::get_bin_path())
+            .current_dir(&test_dir)
+            .args(["--confirm", "--yes", "--color", "never"])
+            .args(args)
+            .env("EDITOR", utils::get_script_path())
+            .env(
+                "ED_SCRIPT",
+                "1d\n\
+                 1d\n\
+                 w\n\
+                 q",
+            )
+            .env("PAGER", &pager)
+            .env("LINES", "2")
+            .stdout(slave)
+            .status()
+            .unwrap();
+        drop(master);
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read_to_string(&paged).ok().as_deref(),
+            uses_pager.then_some("- ./a\n- ./b\n")
+        );
+        let _ = std::fs::remove_file(&paged);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that a file created under a new name after the changes were checked
+/// isn't replaced, both with `RENAME_NOREPLACE` and when checking for it right
+/// before renaming, on kernels without `renameat2`. A file moved to another
+/// file system by copying it must neither replace nor remove it either
+fn created_after_planning() {
+    use std::os::unix::process::CommandExt;
+
+    for (disable_renameat2, other_fs) in
+        [(false, false), (true, false), (false, true)]
+    {
+        let test_dir = utils::create_test_dir().unwrap();
+        let pager_dir = utils::create_test_dir().unwrap();
+        utils::create_test_files(&test_dir, vec!["a"]).unwrap();
+        let other_dir = match other_fs {
+            true => match utils::create_other_fs_dir(&test_dir) {
+                Some(other_dir) => Some(other_dir),
+                None => continue,
+            },
+            false => None,
+        };
+        let (to, name) = match &other_dir {
+            Some(other_dir) => {
+                let to = other_dir.path().join("b");
+                (to.clone(), to)
+            }
+            None => (test_dir.path().join("b"), PathBuf::from("./b")),
+        };
+        // The diff is paged after the changes are checked and before they are
+        // applied, which is when the new name appears
+        let pager = utils::create_editor_script(
+            pager_dir.path(),
+            &format!("cat > /dev/null; echo new > {}", to.display()),
+        );
+        let (master, slave) = utils::open_pty();
+
+        let mut command = Command::new(utils::get_bin_path());
+        command
+            .current_dir(&test_dir)
+            .args(["--confirm", "--yes", "--allow-parent", "--color", "never"])
+            .env("EDITOR", utils::get_script_path())
+            .env(
+                "ED_SCRIPT",
+                format!(
+                    "1s|./a|{}|\n\
+                     w\n\
+                     q",
+                    name.display()
+                ),
+            )
+            .env("PAGER", &pager)
+            .env("LINES", "1")
+            .stdout(slave)
+            .stderr(process::Stdio::piped());
+        if disable_renameat2 {
+            // SAFETY: The filter is installed with system calls only
+            unsafe {
+                command.pre_exec(utils::disable_renameat2);
+            }
+        }
+        let output = command.output().unwrap();
+        drop(master);
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains(&format!("Error moving file \"./a\" to {name:?}")));
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "new\n");
+        assert!(test_dir.path().join("a").exists());
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// Tests that owner names with wide characters are padded by the columns they
+/// take up in a terminal. The names come from a passwd file that is mounted
+/// in a user namespace, where another file mounted in the listing is owned
+/// by an unmapped user. Skipped where user namespaces aren't allowed
+fn wide_owner_names() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let users_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+    let passwd = users_dir.path().join("passwd");
+    let group = users_dir.path().join("group");
+    let listing_path = users_dir.path().join("listing");
+    std::fs::write(
+        &passwd,
+        "日本:x:0:0::/:/bin/sh\nabcd:x:65534:65534::/:/bin/sh\n",
+    )
+    .unwrap();
+    std::fs::write(&group, "g:x:0:\nother:x:65534:\n").unwrap();
+
+    let unshare = |script: &str| {
+        let mut command = Command::new("unshare");
+        command.args(["-Urm", "sh", "-c", script]);
+        command
+    };
+    if !unshare("true")
+        .status()
+        .is_ok_and(|status| status.success())
+    {
+        return;
+    }
+    // The current user is root in the namespace, and root outside of it is
+    // the overflow user
+    let output = unshare(
+        "mount --bind \"$1\" /etc/passwd && \
+         mount --bind \"$2\" /etc/group && \
+         mount --bind /etc/hostname b && \
+         exec \"$3\" --owner --dump-listing \"$4\"",
+    )
+    .args([
+        OsStr::new("sh"),
+        passwd.as_os_str(),
+        group.as_os_str(),
+        utils::get_bin_path().as_os_str(),
+        listing_path.as_os_str(),
+    ])
+    .current_dir(&test_dir)
+    .env("EDITOR", utils::get_script_path())
+    .env("ED_SCRIPT", "q")
+    .output()
+    .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&listing_path).unwrap(),
+        "1 日本 g     ./a\n2 abcd other ./b"
+    );
+}
+
+#[test]
+/// Tests that `--sort locale` sorts accented letters next to their base
+/// letters, ignoring case
+fn sort_locale() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let listing_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["b", "é", "A", "a", "Á"]).unwrap();
+    let listing_path = listing_dir.path().join("listing");
+
+    for (args, listing) in [
+        (&[][..], "1 ./A\n2 ./a\n3 ./b\n4 ./Á\n5 ./é"),
+        (
+            &["--sort", "locale"][..],
+            "1 ./A\n2 ./a\n3 ./Á\n4 ./b\n5 ./é",
+        ),
+    ] {
+        let output = utils::run_rsdir_with_args(
+            &test_dir,
+            "q",
+            &[args, &["--dump-listing", listing_path.to_str().unwrap()]]
+                .concat(),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(std::fs::read_to_string(&listing_path).unwrap(), listing);
+    }
+}
+
+#[test]
+/// Tests that `--sort ext` groups the files by extension
+fn sort_ext() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let listing_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["b.raw", "a.raw", "c.jpeg", "d"])
+        .unwrap();
+    let listing_path = listing_dir.path().join("listing");
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "q",
+        &[
+            "--sort",
+            "ext",
+            "--dump-listing",
+            listing_path.to_str().unwrap(),
+        ],
+    )
+    .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&listing_path).unwrap(),
+        "1 ./d\n2 ./c.jpeg\n3 ./a.raw\n4 ./b.raw"
+    );
+}
+
+#[test]
+/// Tests that `--reverse` reverses the selected sort order
+fn sort_reverse() {
+    let test_dir = utils::create_test_dir().unwrap();
+    let listing_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c"]).unwrap();
+    let listing_path = listing_dir.path().join("listing");
+    for (name, mtime) in [("a", 300), ("b", 100), ("c", 200)] {
+        std::fs::File::options()
+            .write(true)
+            .open(test_dir.path().join(name))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+    }
+
+    for (args, listing) in [
+        (&["-R"][..], "1 ./c\n2 ./b\n3 ./a"),
+        (&["--sort", "mtime"][..], "1 ./b\n2 ./c\n3 ./a"),
+        (&["--sort", "mtime", "--reverse"][..], "1 ./a\n2 ./c\n3 ./b"),
+    ] {
+        let output = utils::run_rsdir_with_args(
+            &test_dir,
+            "q",
+            &[args, &["--dump-listing", listing_path.to_str().unwrap()]]
+                .concat(),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(std::fs::read_to_string(&listing_path).unwrap(), listing);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that `--sort-cmd` reorders the rows of the listing, and fails if the
+/// command changes them
+fn sort_cmd() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b", "c"]).unwrap();
+
+    // The first row is now c
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "1d\n\
+         w\n\
+         q",
+        &["--sort-cmd", "sort -r"],
+    )
+    .unwrap();
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b"))],
+    );
+
+    let output = utils::run_rsdir_with_args(
+        &test_dir,
+        "w\n\
+         q",
+        &["--sort-cmd", "head -n 1"],
+    )
+    .unwrap();
+    assert_eq!(
+        output.stderr,
+        "Error: Sort command \"head -n 1\" changed the rows of the listing \
+         instead of only reordering them"
+    );
+    assert!(!output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("a", Some("a")), ("b", Some("b"))],
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that an editor that needs a terminal fails instead of waiting when
+/// there is none
+fn no_terminal() {
+    use std::os::unix::process::CommandExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a"]).unwrap();
+
+    let mut command = Command::new(utils::get_bin_path());
+    command
+        .current_dir(&test_dir)
+        .args(["--emit-script"])
+        .env("EDITOR", "vim")
+        .env_remove("VISUAL")
+        .env_remove("RSDIR_EDITOR");
+    // SAFETY: setsid is async-signal-safe. It leaves the controlling terminal
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let output = command.output().unwrap();
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap().trim_end(),
+        "Error: Editor \"vim\" needs a terminal, but there is none. Set \
+         EDITOR to an editor that doesn't, or edit the listing in a pipeline \
+         with --list and --apply-stdin"
+    );
+    assert!(!output.status.success());
+    utils::assert_test_files(&test_dir, vec![("a", Some("a"))]);
+}
+
+#[test]
+/// Tests that a listing written by `--list` and edited by another program is
+/// applied by `--apply-stdin`, unless the files changed in between
+fn list_apply_stdin() {
+    let test_dir = utils::create_test_dir().unwrap();
+    utils::create_test_files(&test_dir, vec!["a", "b"]).unwrap();
+
+    let output =
+        utils::run_rsdir_with_args(&test_dir, "", &["--list"]).unwrap();
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    let (header, listing) = output.stdout.split_once('\n').unwrap();
+    assert!(header.starts_with("# rsdir listing "));
+    assert_eq!(listing, "1 ./a\n2 ./b");
+
+    let edited = output.stdout.replace("./a", "./c");
+    utils::create_test_files(&test_dir, vec!["d"]).unwrap();
+    let output =
+        utils::run_rsdir_with_input(&test_dir, "", &["--apply-stdin"], &edited)
+            .unwrap();
+    assert_eq!(
+        output.stderr,
+        "Error: The files changed since they were listed with --list. List \
+         them again"
+    );
+    assert!(!output.status.success());
+
+    std::fs::remove_file(test_dir.path().join("d")).unwrap();
+    let output =
+        utils::run_rsdir_with_input(&test_dir, "", &["--apply-stdin"], &edited)
+            .unwrap();
+    assert_eq!(output.stderr, "");
+    assert!(output.status.success());
+    utils::assert_test_files(
+        &test_dir,
+        vec![("b", Some("b")), ("c", Some("a"))],
+    );
+
+    let output = utils::run_rsdir_with_input(
+        &test_dir,
+        "",
+        &["--apply-stdin"],
+        "1 ./e\n",
+    )
+    .unwrap();
+    assert_eq!(
+        output.stderr,
+        "Error: The listing on stdin has no \"# rsdir listing\" row. Pipe a \
+         listing written by --list"
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+/// Tests that questions are answered on the terminal with `--apply-stdin`,
+/// since stdin is the listing, and that it fails clearly without one
+fn apply_stdin_question() {
+    use std::os::unix::process::CommandExt;
+
+    let test_dir = utils::create_test_dir().unwrap();
+    let names = (0..60).map(|i| format!("f{i:02}")).collect::<Vec<_>>();
+    utils::create_test_files(&test_dir, names.clone()).unwrap();
+
+    let output =
+        utils::run_rsdir_with_args(&test_dir, "", &["--list"]).unwrap();
+    assert!(output.status.success());
+    let edited = output.stdout.replace("./f", "./g");
+
+    for (args, success) in [(&[][..], false), (&["-y"][..], true)] {
+        let mut command = Command::new(utils::get_bin_path());
+        command
+            .current_dir(&test_dir)
+            .arg("--apply-stdin")
+            .args(args)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped());
+        // SAFETY: setsid is async-signal-safe. It leaves the controlling
+        // terminal, so that there is none to answer on
+        unsafe {
+            command.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(edited.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(output.status.success(), success, "{stderr}");
+        if success {
+            let renamed = names.iter().map(|name| name.replacen('f', "g", 1));
+            utils::assert_test_files(
+                &test_dir,
+                renamed
+                    .zip(&names)
+                    .map(|(new, old)| (new, Some(old.as_str())))
+                    .collect(),
+            );
+        } else {
+            assert!(stderr.trim_end().ends_with(
+                "Error: Can't ask \"Apply these changes?\", since stdin was \
+                 the listing and there is no terminal to answer on. Pass -y \
+                 to apply the changes without asking"
+            ));
+            assert!(test_dir.path().join("f00").exists());
+        }
+    }
+}
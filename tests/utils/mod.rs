@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::{env, fs, io, process};
@@ -83,6 +84,29 @@ pub fn assert_test_files(
     assert!(result.iter().eq(expected.iter()));
 }
 
+/// Returns the path of the only file in a directory
+/// Will panic if the directory doesn't contain exactly one entry
+pub fn get_single_file(dir: impl AsRef<Path>) -> PathBuf {
+    let mut entries = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(entries.len(), 1);
+    entries.pop().unwrap()
+}
+
+/// Writes an executable shell script to use as the editor, e.g. to change the
+/// files while the editor is open
+#[cfg(unix)]
+pub fn create_editor_script(dir: impl AsRef<Path>, script: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = dir.as_ref().join("editor.sh");
+    fs::write(&path, format!("#!/bin/sh\n{script}")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
 pub fn get_bin_path() -> PathBuf {
     env::current_exe()
         .unwrap()
@@ -109,19 +133,51 @@ pub fn run_rsdir(
     dir: impl AsRef<Path>,
     ed_script: &str,
     verbose: bool,
+) -> Result<Output, Box<dyn Error>> {
+    run_rsdir_with_args(
+        dir,
+        ed_script,
+        if verbose { &["--verbose"] } else { &[] },
+    )
+}
+
+/// Runs rsdir in a directory with `ed` as the editor, passing extra arguments
+pub fn run_rsdir_with_args(
+    dir: impl AsRef<Path>,
+    ed_script: &str,
+    args: &[&str],
+) -> Result<Output, Box<dyn Error>> {
+    run_rsdir_with_input(dir, ed_script, args, "")
+}
+
+/// Runs rsdir like `run_rsdir_with_args`, writing `input` to its stdin, e.g. to
+/// answer prompts
+pub fn run_rsdir_with_input(
+    dir: impl AsRef<Path>,
+    ed_script: &str,
+    args: &[&str],
+    input: &str,
 ) -> Result<Output, Box<dyn Error>> {
     let bin_path = get_bin_path();
     let ed_path = get_script_path();
+    // Kept temporary files are removed together with the directory
+    let tmp_dir = tempdir()?;
 
     let mut cmd = Command::new(bin_path);
     cmd.current_dir(dir);
+    cmd.arg("--tmpdir").arg(tmp_dir.path());
     cmd.env("ED_SCRIPT", ed_script);
     cmd.env("EDITOR", ed_path);
-    if verbose {
-        cmd.args(["--verbose"]);
-    }
+    cmd.env_remove("VISUAL");
+    cmd.env_remove("RSDIR_EDITOR");
+    cmd.args(args);
+    cmd.stdin(process::Stdio::piped());
+    cmd.stdout(process::Stdio::piped());
+    cmd.stderr(process::Stdio::piped());
 
-    let output = cmd.output()?;
+    let mut child = cmd.spawn()?;
+    child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
     let stdout = String::from_utf8(output.stdout)?.trim_end().to_owned();
     let stderr = String::from_utf8(output.stderr)?.trim_end().to_owned();
 
@@ -135,3 +191,114 @@ pub fn run_rsdir(
         stderr,
     })
 }
+
+/// Creates a symlink to a directory, which is a separate kind of symlink on
+/// Windows
+#[cfg(unix)]
+pub fn symlink_dir(
+    target: impl AsRef<Path>,
+    link: impl AsRef<Path>,
+) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub fn symlink_dir(
+    target: impl AsRef<Path>,
+    link: impl AsRef<Path>,
+) -> io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+/// Whether names that only differ in case are the same file in a directory,
+/// as on macOS and Windows by default
+pub fn is_case_insensitive(dir: impl AsRef<Path>) -> bool {
+    let probe = dir.as_ref().join("case-probe");
+    fs::write(&probe, "").unwrap();
+    let insensitive = dir.as_ref().join("CASE-PROBE").exists();
+    fs::remove_file(probe).unwrap();
+    insensitive
+}
+
+/// Opens a pseudo terminal and returns its master and slave, for running
+/// rsdir as if in a terminal
+#[cfg(unix)]
+pub fn open_pty() -> (fs::File, fs::File) {
+    use std::os::fd::FromRawFd;
+
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: The file descriptors are written, and the name, settings and
+    // size are optional
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(result, 0);
+    // SAFETY: openpty opened both, and they are only closed by the files
+    unsafe { (fs::File::from_raw_fd(master), fs::File::from_raw_fd(slave)) }
+}
+
+/// Makes `renameat2` fail with `ENOSYS` in the current process and its
+/// children, like on a kernel without it. Meant to be called in `pre_exec`
+#[cfg(target_os = "linux")]
+pub fn disable_renameat2() -> io::Result<()> {
+    use libc::{sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K};
+    use libc::{BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO};
+
+    let statement = |code, k| sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let filter = [
+        // The system call number is the first field of `seccomp_data`
+        statement(BPF_LD | BPF_W | BPF_ABS, 0),
+        sock_filter {
+            code: (BPF_JMP | BPF_JEQ | BPF_K) as u16,
+            jt: 0,
+            jf: 1,
+            k: libc::SYS_renameat2 as u32,
+        },
+        statement(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+    ];
+    let program = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut sock_filter,
+    };
+    // SAFETY: The program points to the filter, which outlives the calls
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+            || libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const sock_fprog,
+            ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Creates a temporary directory on another file system than `dir`, for
+/// moving files across file systems. There is none when /dev/shm is missing
+/// or is on the same file system, and the test that needs it is skipped
+#[cfg(target_os = "linux")]
+pub fn create_other_fs_dir(dir: impl AsRef<Path>) -> Option<TempDir> {
+    use std::os::unix::fs::MetadataExt;
+
+    let other_dir = tempfile::tempdir_in("/dev/shm").ok()?;
+    let device = |path: &Path| fs::metadata(path).map(|m| m.dev()).ok();
+    if device(dir.as_ref()) == device(other_dir.path()) {
+        println!("No other file system than {:?}", dir.as_ref());
+        return None;
+    }
+    Some(other_dir)
+}